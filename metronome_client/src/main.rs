@@ -1,17 +1,24 @@
 extern crate clap;
 extern crate metronome_lib;
-extern crate serde;
-extern crate serde_json;
 extern crate single_value_channel;
-#[macro_use] extern crate serde_derive;
 use clap::{Arg, App};
-use client_lib::datatypes::{ClientConfig, ClientSessionTracker, RTTMeasurement, ClientSessionStatistics};
-use metronome_lib::datatypes::{MetronomeMessage, TimestampedMessage, MessageWithSize};
+use client_lib::datatypes::ClientConfig;
+use metronome_lib::client::{ClientSession, RTTMeasurement, SentPings};
+use metronome_lib::stats::{StatsDestination, StatsSink};
+use metronome_lib::clock::SystemClock;
+use metronome_lib::pacing::Pacer;
+use metronome_lib::datatypes::{ip_overhead, MetronomeMessage, TimestampedMessage, MessageWithSize, ReceiveTimeWindows};
+use metronome_lib::timestamping::{PendingTxTimestamps, TimestampedReceiver, Timestamps, TimestampingMode};
 use std::net::ToSocketAddrs;
 mod client_lib;
 
 const SLEEP_TIME: u64 = 100;
 const TIMEOUT_SECONDS: f64 = 5.0;
+const TX_TIMESTAMP_WAIT: f64 = 0.05;
+const TX_TIMESTAMP_POLL_INTERVAL: f64 = 0.001;
+const MAX_WAIT: f64 = 0.1;
+// Receive timeout of the rx thread, bounds how late reports and expiry scans run when no pongs arrive
+const RX_POLL_TIME: u64 = 10;
 
 fn prepare_connect_socket(addr: std::net::SocketAddr) -> std::net::UdpSocket {
     let socket: std::net::UdpSocket;
@@ -36,42 +43,71 @@ fn prepare_connect_socket(addr: std::net::SocketAddr) -> std::net::UdpSocket {
     return socket;
 }
 
-fn tx_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ClientConfig, tx_socket: std::net::UdpSocket, tx_stats_tx: std::sync::mpsc::Sender<RTTMeasurement>, mut target_pps: single_value_channel::Receiver<u64>) {
+fn tx_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ClientConfig, tx_socket: std::net::UdpSocket, sent_pings: std::sync::Arc<SentPings>, mut target_pps: single_value_channel::Receiver<u64>) {
+    let clock = config.clock.clone();
     let mut msg_seq: u64 = 0;
-    let payload = std::iter::repeat("X").take(config.payload_size).collect::<String>();
-    let mut next_tx_at = metronome_lib::util::get_timestamp();
+    let payload_seed = metronome_lib::util::random_seed();
+    let mut next_tx_at = clock.monotonic();
     let mut msg: MetronomeMessage = MetronomeMessage {
         mode: "ping".to_string(),
-        payload: Some(payload),
+        payload: None,
         mul: config.balance,
         seq: msg_seq,
         key: config.key,
         sid: config.sid,
+        hub_stats_requested: config.request_hub_stats,
+        hub_stats: None,
+        payload_checksum: None,
     };
+    msg.set_payload(config.payload_pattern.generate(config.payload_size, msg_seq, payload_seed), config.payload_checksum);
     let mut pps_sleeptime: f64;
-    let mut last_send_error_printed: f64 = 0.0;
+    let mut last_send_error_printed: f64 = f64::NEG_INFINITY;
+    let mut pacer = Pacer::new(config.pacing);
+    let mut pending_tx_timestamps: Option<PendingTxTimestamps<u64>> = if config.timestamping.transmit() { Some(PendingTxTimestamps::new(TX_TIMESTAMP_WAIT)) } else { None };
+    let mut released_tx_timestamps: Vec<(u64, Timestamps)> = Vec::new();
     while running.load(std::sync::atomic::Ordering::Relaxed) {
         pps_sleeptime = 1.0/(*target_pps.latest() as f64);
-        let current_time = metronome_lib::util::get_timestamp();
+        // Wake up for the shutdown flag, rate changes and transmit timestamps even between slow pings
+        let mut wake_at = next_tx_at.min(clock.monotonic() + MAX_WAIT);
+        if pending_tx_timestamps.as_ref().is_some_and(|pending| !pending.is_empty()) {
+            wake_at = wake_at.min(clock.monotonic() + TX_TIMESTAMP_POLL_INTERVAL);
+        }
+        let current_time = pacer.wait_until(clock.as_ref(), wake_at).monotonic;
         if current_time >= next_tx_at {
-            // Advance next_tx_at to previous second if fallen further behind
-            next_tx_at = (current_time-1.0).max(next_tx_at);
+            // Advance next_tx_at to previous second if fallen further behind, the sends passed over are skipped
+            let resume_at = (current_time-1.0).max(next_tx_at);
+            let mut skipped = ((resume_at - next_tx_at) / pps_sleeptime).ceil() as u64;
+            next_tx_at = resume_at;
+            let mut sent_this_wakeup: u64 = 0;
             while current_time >= next_tx_at {
                 msg.seq = msg_seq;
+                if config.payload_pattern.per_message() {
+                    msg.set_payload(config.payload_pattern.generate(config.payload_size, msg_seq, payload_seed), config.payload_checksum);
+                }
                 match msg.as_vec() {
                     Ok(serialized) => {
+                        let rttmeas = RTTMeasurement {
+                            seq: msg_seq,
+                            timestamps: clock.now(),
+                            scheduled: next_tx_at,
+                            catch_up: sent_this_wakeup > 0,
+                            skipped: skipped,
+                            message_raw_size: serialized.len(),
+                        };
+                        // In flight before it hits the wire, the pong may be back before send() returns
+                        sent_pings.sending(&rttmeas);
                         if let Err(e) = tx_socket.send(&serialized) {
+                            sent_pings.send_failed(msg_seq);
                             if (current_time - last_send_error_printed) > 10.0 {
                                 eprintln!("failed to send message to hub: {}", e);
                                 last_send_error_printed = current_time;
                             }
                         } else {
-                            let rttmeas = RTTMeasurement {
-                                seq: msg_seq,
-                                timestamp: current_time,
-                            };
-                            if let Err(e) = tx_stats_tx.send(rttmeas) {
-                                eprintln!("failed to send RTT measurement to stats thread: {}", e);
+                            sent_pings.sent(&rttmeas);
+                            skipped = 0;
+                            sent_this_wakeup += 1;
+                            if let Some(pending) = &mut pending_tx_timestamps {
+                                pending.sent(rttmeas.timestamps, msg_seq);
                             }
                             msg_seq += 1;
                         }
@@ -83,98 +119,57 @@ fn tx_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: Cli
                 next_tx_at += pps_sleeptime;
             }
         }
-        if config.use_sleep {
-            // Fixme
-            let sleeptime = std::time::Duration::from_micros(100);
-            std::thread::sleep(sleeptime);
-        }
-    }
-}
-
-fn rx_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ClientConfig, rx_socket: std::net::UdpSocket, rx_stats_tx: std::sync::mpsc::Sender<TimestampedMessage>) {
-    let mut rxbuf = [0;65536];
-    while running.load(std::sync::atomic::Ordering::Relaxed) {
-        if let Ok(size) = rx_socket.recv(&mut rxbuf) {
-            let timestamp = metronome_lib::util::get_timestamp();
-            if let Some(metronome_message) = MetronomeMessage::parse_from_buffer(&rxbuf) {
-                if metronome_message.key != config.key {
-                    continue;
-                }
-                if metronome_message.sid != config.sid {
+        if let Some(pending) = &mut pending_tx_timestamps {
+            // Transmit timestamps replace the userspace send time of measurements still in flight
+            pending.poll(&tx_socket, clock.monotonic(), &mut released_tx_timestamps);
+            for (seq, timestamps) in released_tx_timestamps.drain(..) {
+                if timestamps.software.is_none() && timestamps.hardware.is_none() {
                     continue;
                 }
-                let timestamped_message = TimestampedMessage {
-                    timestamp: timestamp,
-                    message_with_size: MessageWithSize {
-                        message_raw_size: size,
-                        message: metronome_message,
-                    },
-                };
-                if let Err(e) = rx_stats_tx.send(timestamped_message) {
-                    eprintln!("failed to send MetronomeMessage to stats thread from rx thread: {}", e);
-                }
+                sent_pings.tx_timestamp(seq, &timestamps);
             }
         }
     }
 }
 
-fn send_stats(stats: ClientSessionStatistics, clocktower_socket: &std::net::UdpSocket) {
-    if let Ok(stats_json) = stats.to_json() {
-        let message_bytes = stats_json.into_bytes();
-        if let Err(e) = clocktower_socket.send(&message_bytes) {
-            eprintln!("failed to send statistics to clocktower: {}", e);
-        }
+fn rx_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ClientConfig, rx_socket: std::net::UdpSocket, mut stats_sinks: Vec<StatsSink>, sent_pings: std::sync::Arc<SentPings>) {
+    let clock = config.clock.clone();
+    let mut session: ClientSession = ClientSession::new(sent_pings, clock.wall());
+    if config.stats_deltas {
+        session.report_deltas();
     }
-}
-
-fn stats_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ClientConfig, clocktowers: Vec<std::net::UdpSocket>, tx_stats_rx: std::sync::mpsc::Receiver<RTTMeasurement>, rx_stats_rx: std::sync::mpsc::Receiver<TimestampedMessage>, _pps_updater: single_value_channel::Updater<u64>) {
-    let mut tracker: std::collections::HashMap<u64, RTTMeasurement> = std::collections::HashMap::new();
-    let mut stats: ClientSessionTracker = ClientSessionTracker::new();
+    session.tracker.receive_time_windows = config.receive_time_windows.clone();
+    session.tracker.ip_overhead = ip_overhead(&config.remote);
     let mut last_scan: f64 = 0.0;
-    let mut something_done: bool;
+    let mut rxbuf = [0;65536];
+    let mut timestamped_receiver = TimestampedReceiver::new();
+    if let Err(_) = rx_socket.set_read_timeout(Some(std::time::Duration::from_millis(RX_POLL_TIME))) {
+        panic!("failed to set socket read timeout!");
+    }
     while running.load(std::sync::atomic::Ordering::Relaxed) {
-        something_done = false;
-
-        if let Ok(rtt_measurement) = tx_stats_rx.try_recv() {
-            stats.outgoing(rtt_measurement.timestamp);
-            tracker.insert(rtt_measurement.seq, rtt_measurement);
-            something_done = true;
-        }
-
-        if let Ok(timestamped_message) = rx_stats_rx.try_recv() {
-            let message = &timestamped_message.message_with_size.message;
-            stats.incoming(timestamped_message.timestamp, message.seq, timestamped_message.message_with_size.message_raw_size);
-            if let Some(rtt_measurement) = tracker.get(&message.seq) {
-                stats.rtt_success(rtt_measurement.timestamp, timestamped_message.timestamp);
-                tracker.remove(&message.seq);
+        if let Ok((size, _, timestamps)) = timestamped_receiver.recv_from(&rx_socket, &mut rxbuf) {
+            if let Some(metronome_message) = MetronomeMessage::parse_from_buffer(&rxbuf) {
+                if metronome_message.key == config.key && metronome_message.sid == config.sid {
+                    session.received(&TimestampedMessage {
+                        timestamps: timestamps,
+                        message_with_size: MessageWithSize {
+                            message_raw_size: size,
+                            message: metronome_message,
+                        },
+                    });
+                }
             }
-            something_done = true;
         }
 
-        let current_timestamp = metronome_lib::util::get_timestamp();
+        let current_timestamp = clock.monotonic();
         if last_scan < (current_timestamp - config.stats_interval) {
-            let deadline = current_timestamp - TIMEOUT_SECONDS;
-            let mut delete_list: Vec<u64> = Vec::new();
-            for (seq, rtt_measurement) in tracker.iter() {
-                if rtt_measurement.timestamp < deadline {
-                    delete_list.push(*seq);
-                }
-            }
-            for seq in delete_list.iter() {
-                tracker.remove(seq);
-                stats.rtt_timeout();
-            }
+            session.expire(current_timestamp - TIMEOUT_SECONDS);
             last_scan = current_timestamp;
-            something_done = true;
-            for clocktower_socket in clocktowers.iter() {
-                send_stats(ClientSessionStatistics::from_session_tracker(current_timestamp, &config.sid, &stats), &clocktower_socket);
+            let stats = session.statistics(clock.wall(), &config.sid, current_timestamp);
+            for stats_sink in stats_sinks.iter_mut() {
+                stats_sink.send(&stats);
             }
         }
-
-        if !something_done {
-            let sleeptime = std::time::Duration::from_micros(1000);
-            std::thread::sleep(sleeptime);
-        }
     }
 }
 
@@ -192,6 +187,15 @@ fn main() {
             Arg::with_name("use-sleep")
                 .short("S")
                 .long("use-sleep")
+                .help("Kept for compatibility, sleeping is the default pacing mode")
+        )
+        .arg(
+            Arg::with_name("pacing")
+                .long("pacing")
+                .takes_value(true)
+                .possible_values(&["sleep", "spin"])
+                .default_value("sleep")
+                .help("Sleep until shortly before each send and spin the rest, or spin a core for the lowest jitter")
         )
         .arg(
             Arg::with_name("payload-size")
@@ -200,6 +204,13 @@ fn main() {
                 .takes_value(true)
                 .default_value("1")
         )
+        .arg(
+            Arg::with_name("payload-pattern")
+                .long("payload-pattern")
+                .takes_value(true)
+                .default_value("fill")
+                .help("Payload content: fill[:CHAR], zeros, random, prbs or custom:TEXT. Compare random against fill to spot compressing WAN optimisers")
+        )
         .arg(
             Arg::with_name("balance")
                 .short("b")
@@ -221,6 +232,7 @@ fn main() {
                 .multiple(true)
                 .takes_value(true)
                 .required(true)
+                .help("Statistics destination: host:port for the clocktower, influx-udp://host:port or influx-http://host:port/write?db=metronome for InfluxDB line protocol, statsd://host:port, dogstatsd://host:port, otlp-http://host:port for OpenTelemetry, or tcp://host:port and http://host:port/statistics for acknowledged delivery to the clocktower with ?spool=DIR&spool_records=N&spool_bytes=N")
         )
         .arg(
             Arg::with_name("key")
@@ -242,16 +254,50 @@ fn main() {
                 .takes_value(true)
                 .default_value("1.0")
         )
+        .arg(
+            Arg::with_name("stats_deltas")
+                .long("stats-deltas")
+                .help("Add what each counter gained since the previous report to session statistics")
+        )
+        .arg(
+            Arg::with_name("receive_windows")
+                .long("receive-windows")
+                .takes_value(true)
+                .default_value("10")
+                .help("Number of windows the receive time period is divided into, counted per stats interval")
+        )
+        .arg(
+            Arg::with_name("receive_window_period")
+                .long("receive-window-period")
+                .takes_value(true)
+                .default_value("1.0")
+                .help("Seconds the receive time windows repeat over, e.g. 0.02 to line up with 20 ms voice frames")
+        )
+        .arg(
+            Arg::with_name("payload_checksum")
+                .long("payload-checksum")
+                .help("Send a CRC-32 of the payload so the hub and client count corrupted messages. Hubs predating it drop these pings")
+        )
+        .arg(
+            Arg::with_name("hub_stats")
+                .long("hub-stats")
+        )
+        .arg(
+            Arg::with_name("timestamping")
+                .long("timestamping")
+                .takes_value(true)
+                .possible_values(&["off", "software", "hardware"])
+                .default_value("off")
+                .help("Kernel receive and transmit timestamps, Linux only. Hardware timestamps need the interface configured for them")
+        )
         .get_matches();
 
-    let mut clocktowers: Vec<std::net::UdpSocket> = Vec::new();
+    let mut stats_sinks: Vec<StatsSink> = Vec::new();
     if let Some(clocktower_strings) = matches.values_of("clocktower") {
         for clocktower_string in clocktower_strings {
-            let clocktower_address = clocktower_string
-                .to_socket_addrs().expect(&format!("Failed to convert '{}' to socket address", clocktower_string))
-                .next().expect(&format!("'{}' doesn't resolve to any addresses", clocktower_string));
-            let clocktower_socket = prepare_connect_socket(clocktower_address);
-            clocktowers.push(clocktower_socket);
+            let destination: StatsDestination = clocktower_string.parse().unwrap();
+            let stats_sink = StatsSink::new(&destination, "metronome-client").expect(&format!("Failed to set up statistics destination '{}'", clocktower_string));
+            stats_sinks.push(stats_sink);
         }
     }
 
@@ -260,48 +306,62 @@ fn main() {
         .to_socket_addrs().expect(&format!("Failed to convert '{}' to socket address", remote_string))
         .next().expect(&format!("'{}' doesn't resolve to any addresses", remote_string));
 
-    let config = ClientConfig {
+    let mut config = ClientConfig {
         pps_limit: matches.value_of("pps-max").unwrap().parse().unwrap(),
         payload_size: matches.value_of("payload-size").unwrap().parse().unwrap(),
-        use_sleep: matches.is_present("use-sleep"),
+        payload_pattern: matches.value_of("payload-pattern").unwrap().parse().unwrap_or_else(|e| panic!("{}", e)),
+        payload_checksum: matches.is_present("payload_checksum"),
+        pacing: matches.value_of("pacing").unwrap().parse().unwrap(),
         balance: matches.value_of("balance").unwrap().parse().unwrap(),
         remote: remote_address,
         key: matches.value_of("key").unwrap().to_string(),
         sid: matches.value_of("session_id").unwrap().to_string(),
         stats_interval: matches.value_of("stats_interval").unwrap().parse().unwrap(),
+        stats_deltas: matches.is_present("stats_deltas"),
+        receive_time_windows: ReceiveTimeWindows::new(
+            matches.value_of("receive_windows").unwrap().parse().unwrap(),
+            matches.value_of("receive_window_period").unwrap().parse().unwrap(),
+        ).unwrap_or_else(|e| panic!("{}", e)),
+        request_hub_stats: matches.is_present("hub_stats"),
+        timestamping: matches.value_of("timestamping").unwrap().parse().unwrap(),
+        clock: std::sync::Arc::new(SystemClock),
     };
 
     let running = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
     
     let hub_socket = prepare_connect_socket(config.remote);
+    match metronome_lib::timestamping::enable(&hub_socket, config.timestamping) {
+        Ok(enabled_mode) => {
+            config.timestamping = enabled_mode;
+        },
+        Err(e) => {
+            eprintln!("failed to enable kernel timestamps, using userspace timestamps: {}", e);
+            config.timestamping = TimestampingMode::Off;
+        }
+    }
 
     let hub_rx_socket = hub_socket.try_clone().unwrap();
     let hub_tx_socket = hub_socket.try_clone().unwrap();
 
-    let (rx_stats_tx, rx_stats_rx) = std::sync::mpsc::channel();
-    let (tx_stats_tx, tx_stats_rx) = std::sync::mpsc::channel();
-    let (pps_receiver, pps_updater) = single_value_channel::channel_starting_with(config.pps_limit);
+    // Room for every ping that can be in flight before it times out
+    let sent_pings = std::sync::Arc::new(SentPings::new(((config.pps_limit as f64 * TIMEOUT_SECONDS * 2.0) as usize).max(1024)));
+    let (pps_receiver, _pps_updater) = single_value_channel::channel_starting_with(config.pps_limit);
 
     let running_rx = running.clone();
     let running_tx = running.clone();
-    let running_stats = running.clone();
     let config_rx = config.clone();
     let config_tx = config.clone();
-    let config_stats = config.clone();
+    let sent_pings_rx = sent_pings.clone();
+    let sent_pings_tx = sent_pings.clone();
 
     let tx_thd = std::thread::spawn(move || {
-        tx_thread(running_tx, config_tx, hub_tx_socket, tx_stats_tx, pps_receiver);
+        tx_thread(running_tx, config_tx, hub_tx_socket, sent_pings_tx, pps_receiver);
     });
 
     let rx_thd = std::thread::spawn(move || {
-        rx_thread(running_rx, config_rx, hub_rx_socket, rx_stats_tx);
-    });
-
-    let stats_thd = std::thread::spawn(move || {
-        stats_thread(running_stats, config_stats, clocktowers, tx_stats_rx, rx_stats_rx, pps_updater);
+        rx_thread(running_rx, config_rx, hub_rx_socket, stats_sinks, sent_pings_rx);
     });
 
     tx_thd.join().unwrap();
     rx_thd.join().unwrap();
-    stats_thd.join().unwrap();
 }
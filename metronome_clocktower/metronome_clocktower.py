@@ -3,6 +3,10 @@ import socket
 import json
 import threading
 import argparse
+import socketserver
+import struct
+import http.server
+import sys
 from prometheus_client import start_http_server
 from prometheus_client.core import GaugeMetricFamily, CounterMetricFamily, REGISTRY
 
@@ -11,6 +15,9 @@ parser = argparse.ArgumentParser(description='metronome clocktower')
 parser.add_argument('-b', '--bind-address', required=False, help='bind address for clocktower messages', default='0.0.0.0')
 parser.add_argument('-p', '--bind-port', required=False, help='bind port for clocktower messages', default='4444', type=int)
 parser.add_argument('-e', '--exporter-port', required=False, help='bind port for prometheus exporter', default='8415', type=int)
+parser.add_argument('--tcp-port', required=False, help='bind port for length-delimited statistics over TCP (tcp:// destinations)', default=None, type=int)
+parser.add_argument('--http-port', required=False, help='bind port for statistics posted over HTTP (http:// destinations)', default=None, type=int)
+parser.add_argument('--schema', required=False, help='JSON Schema to validate statistics records against (schema/statistics.schema.json), needs the jsonschema package', default=None)
 args = parser.parse_args()
 
 
@@ -20,6 +27,31 @@ hub_sessions = {}
 hub_sessions_lock = threading.Lock()
 client_sessions = {}
 client_sessions_lock = threading.Lock()
+hub_acl = {}
+hub_acl_lock = threading.Lock()
+# Records arrive on several listener threads, keep their lines whole
+output_lock = threading.Lock()
+# Last (epoch, stats_seq, arrival time) of each session, for spotting lost records and counter resets
+record_sequences = {}
+lost_records = {}
+counter_resets = {}
+record_sequences_lock = threading.Lock()
+# Records failing the schema are counted and dropped instead of exported
+invalid_records = {}
+invalid_records_lock = threading.Lock()
+last_invalid_record_printed = 0.0
+# One validator per clocktower_type so errors point at the record type that was meant
+record_validators = None
+if args.schema is not None:
+    import jsonschema
+    with open(args.schema) as schema_file:
+        schema = json.load(schema_file)
+    record_validators = {}
+    for reference in schema['oneOf']:
+        definition = schema['$defs'][reference['$ref'].split('/')[-1]]
+        record_validators[definition['properties']['clocktower_type']['const']] = jsonschema.Draft202012Validator(
+            {'$ref': reference['$ref'], '$defs': schema['$defs']}
+        )
 msglistener = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
 msglistener.bind((args.bind_address, args.bind_port))
 
@@ -30,46 +62,103 @@ class CustomCollector(object):
         global hub_sessions_lock
         global client_sessions
         global client_sessions_lock
+        global hub_acl
+        global hub_acl_lock
 
         hub_received_messages = CounterMetricFamily(
             'metronome2_hub_received_messages',
             'Messages received by the metronome hub',
-            labels=['sid']
+            labels=['tenant', 'sid']
         )
         hub_holes_created = CounterMetricFamily(
             'metronome2_hub_holes_created',
             'Holes created within session',
-            labels=['sid']
+            labels=['tenant', 'sid']
         )
         hub_holes_closed = CounterMetricFamily(
             'metronome2_hub_holes_closed',
             'Holes closed within session',
-            labels=['sid']
+            labels=['tenant', 'sid']
         )
         hub_holes_timed_out = CounterMetricFamily(
             'metronome2_hub_holes_timed_out',
             'Holes timed out within session',
-            labels=['sid']
+            labels=['tenant', 'sid']
         )
         hub_holes_current = GaugeMetricFamily(
             'metronome2_hub_holes_current',
             'Current holes within session',
-            labels=['sid']
+            labels=['tenant', 'sid']
         )
         hub_payload_bytes = CounterMetricFamily(
             'metronome2_hub_received_bytes',
             'Payload bytes received by the hub',
-            labels=['sid']
+            labels=['tenant', 'sid']
         )
         hub_intermessage_gap_mavg_seconds = GaugeMetricFamily(
             'metronome2_hub_intermessage_gap_mavg',
             'Moving average of intermessage gap',
-            labels=['sid']
+            labels=['tenant', 'sid']
+        )
+        hub_residence_time_mavg_seconds = GaugeMetricFamily(
+            'metronome2_hub_residence_time_mavg',
+            'Moving average of time between receiving a ping and sending its pong',
+            labels=['tenant', 'sid']
+        )
+        hub_residence_time_worst_seconds = GaugeMetricFamily(
+            'metronome2_hub_residence_time_worst',
+            'Worst time between receiving a ping and sending its pong',
+            labels=['tenant', 'sid']
         )
         hub_receive_time_window_messages = GaugeMetricFamily(
             'metronome2_hub_receive_time_window_messages',
-            'Messages received by time window',
-            labels=['sid', 'window']
+            'Messages received by time window over the last stats interval',
+            labels=['tenant', 'sid', 'window']
+        )
+        hub_receive_burstiness = GaugeMetricFamily(
+            'metronome2_hub_receive_burstiness',
+            'Unevenness of messages over the receive time windows, 0 when spread evenly and 1 when all in one window',
+            labels=['tenant', 'sid']
+        )
+        hub_rejected_messages = CounterMetricFamily(
+            'metronome2_hub_rejected_messages',
+            'Messages not replied to by hub policy',
+            labels=['tenant', 'sid', 'reason']
+        )
+        hub_impaired_messages = CounterMetricFamily(
+            'metronome2_hub_impaired_messages',
+            'Pongs affected by the hub impairment emulator',
+            labels=['tenant', 'sid', 'impairment']
+        )
+        hub_interval_loss_percent = GaugeMetricFamily(
+            'metronome2_hub_interval_loss_percent',
+            'Share of messages lost on the way to the hub over the last stats interval',
+            labels=['tenant', 'sid']
+        )
+        hub_interval_received_pps = GaugeMetricFamily(
+            'metronome2_hub_interval_received_pps',
+            'Messages per second received by the hub over the last stats interval',
+            labels=['tenant', 'sid']
+        )
+        hub_corrupted_messages = CounterMetricFamily(
+            'metronome2_hub_corrupted_messages',
+            'Pings whose payload did not match their checksum',
+            labels=['tenant', 'sid']
+        )
+        hub_sent_payload_bytes = CounterMetricFamily(
+            'metronome2_hub_sent_bytes',
+            'Payload bytes sent by the hub',
+            labels=['tenant', 'sid']
+        )
+        hub_interval_bps = GaugeMetricFamily(
+            'metronome2_hub_interval_bps',
+            'Bits per second on the wire or as payload (goodput) at the hub over the last stats interval',
+            labels=['tenant', 'sid', 'direction', 'measure']
+        )
+        hub_acl_hits = CounterMetricFamily(
+            'metronome2_hub_acl_hits',
+            'Messages matching a hub source ACL rule',
+            labels=['action', 'prefix']
         )
 
         client_unexpected_increments = CounterMetricFamily(
@@ -132,44 +221,218 @@ class CustomCollector(object):
             'Moving average of intermessage gap',
             labels=['sid']
         )
+        client_send_error_mavg_seconds = GaugeMetricFamily(
+            'metronome2_client_send_error_mavg',
+            'Moving average of time between scheduled and actual send',
+            labels=['sid']
+        )
+        client_send_error_worst_seconds = GaugeMetricFamily(
+            'metronome2_client_send_error_worst',
+            'Worst time between scheduled and actual send',
+            labels=['sid']
+        )
+        client_send_lateness_seconds = GaugeMetricFamily(
+            'metronome2_client_send_lateness',
+            'Time between scheduled and actual send over the last stats interval',
+            labels=['sid', 'quantile']
+        )
+        client_skipped_sends = CounterMetricFamily(
+            'metronome2_client_skipped_sends',
+            'Sends given up after the client fell more than a second behind',
+            labels=['sid']
+        )
+        client_catch_up_sends = CounterMetricFamily(
+            'metronome2_client_catch_up_sends',
+            'Sends made back to back after the client fell behind',
+            labels=['sid']
+        )
         client_receive_time_window_messages = GaugeMetricFamily(
             'metronome2_client_receive_time_window_messages',
-            'Messages received by time window',
+            'Messages received by time window over the last stats interval',
             labels=['sid', 'window']
         )
+        client_receive_burstiness = GaugeMetricFamily(
+            'metronome2_client_receive_burstiness',
+            'Unevenness of messages over the receive time windows, 0 when spread evenly and 1 when all in one window',
+            labels=['sid']
+        )
+        client_interval_loss_percent = GaugeMetricFamily(
+            'metronome2_client_interval_loss_percent',
+            'Share of pings lost over the last stats interval',
+            labels=['sid']
+        )
+        client_interval_rtt_seconds = GaugeMetricFamily(
+            'metronome2_client_interval_rtt',
+            'RTT over the last stats interval',
+            labels=['sid', 'stat']
+        )
+        client_interval_received_pps = GaugeMetricFamily(
+            'metronome2_client_interval_received_pps',
+            'Pongs per second received over the last stats interval',
+            labels=['sid']
+        )
+        client_corrupted_messages = CounterMetricFamily(
+            'metronome2_client_corrupted_messages',
+            'Messages whose payload did not match their checksum, forward as counted by the hub',
+            labels=['sid', 'direction']
+        )
+        client_sent_payload_bytes = CounterMetricFamily(
+            'metronome2_client_sent_bytes',
+            'Payload bytes sent by the client',
+            labels=['sid']
+        )
+        client_interval_bps = GaugeMetricFamily(
+            'metronome2_client_interval_bps',
+            'Bits per second on the wire or as payload (goodput) at the client over the last stats interval',
+            labels=['sid', 'direction', 'measure']
+        )
+        client_hub_received_messages = CounterMetricFamily(
+            'metronome2_client_hub_received_messages',
+            'Messages received by the hub as reported in pongs',
+            labels=['sid']
+        )
+        hub_timestamp_source = GaugeMetricFamily(
+            'metronome2_hub_timestamp_source',
+            'Timestamp source used by the hub, 1 for the active source',
+            labels=['tenant', 'sid', 'measurement', 'source']
+        )
+        client_timestamp_source = GaugeMetricFamily(
+            'metronome2_client_timestamp_source',
+            'Timestamp source used by the client, 1 for the active source',
+            labels=['sid', 'measurement', 'source']
+        )
+        clocktower_lost_records = CounterMetricFamily(
+            'metronome2_clocktower_lost_records',
+            'Statistics records that never arrived, from gaps in their sequence numbers',
+            labels=['clocktower_type']
+        )
+        clocktower_counter_resets = CounterMetricFamily(
+            'metronome2_clocktower_counter_resets',
+            'Sessions whose counters started over, seen as a new epoch',
+            labels=['clocktower_type']
+        )
+        clocktower_invalid_records = CounterMetricFamily(
+            'metronome2_clocktower_invalid_records',
+            'Statistics records dropped for not matching the schema',
+            labels=['clocktower_type']
+        )
+        client_hub_policed_messages = CounterMetricFamily(
+            'metronome2_client_hub_policed_messages',
+            'Messages dropped by hub policy as reported in pongs',
+            labels=['sid']
+        )
+        client_forward_lost_messages = CounterMetricFamily(
+            'metronome2_client_forward_lost_messages',
+            'Messages lost on the way to the hub',
+            labels=['sid']
+        )
+        client_reverse_lost_messages = CounterMetricFamily(
+            'metronome2_client_reverse_lost_messages',
+            'Messages lost on the way back from the hub',
+            labels=['sid']
+        )
+        client_forward_reordered_messages = CounterMetricFamily(
+            'metronome2_client_forward_reordered_messages',
+            'Messages reordered on the way to the hub',
+            labels=['sid']
+        )
+        client_reverse_reordered_messages = CounterMetricFamily(
+            'metronome2_client_reverse_reordered_messages',
+            'Messages reordered on the way back from the hub',
+            labels=['sid']
+        )
 
         with hub_sessions_lock:
-            for sid, session_info in hub_sessions.items():
+            for (tenant, sid), session_info in hub_sessions.items():
                 hub_received_messages.add_metric(
-                    [sid], session_info.get('received_messages'), timestamp=session_info.get('timestamp')
+                    [tenant, sid], session_info.get('received_messages'), timestamp=session_info.get('timestamp')
                 )
                 hub_holes_created.add_metric(
-                    [sid], session_info.get('holes_created'), timestamp=session_info.get('timestamp')
+                    [tenant, sid], session_info.get('holes_created'), timestamp=session_info.get('timestamp')
                 )
                 hub_holes_closed.add_metric(
-                    [sid], session_info.get('holes_closed'), timestamp=session_info.get('timestamp')
+                    [tenant, sid], session_info.get('holes_closed'), timestamp=session_info.get('timestamp')
                 )
                 hub_holes_timed_out.add_metric(
-                    [sid], session_info.get('holes_timed_out'), timestamp=session_info.get('timestamp')
+                    [tenant, sid], session_info.get('holes_timed_out'), timestamp=session_info.get('timestamp')
                 )
                 hub_holes_current.add_metric(
-                    [sid], session_info.get('holes_current'), timestamp=session_info.get('timestamp')
+                    [tenant, sid], session_info.get('holes_current'), timestamp=session_info.get('timestamp')
                 )
                 hub_payload_bytes.add_metric(
-                    [sid], session_info.get('received_bytes'), timestamp=session_info.get('timestamp')
+                    [tenant, sid], session_info.get('received_bytes'), timestamp=session_info.get('timestamp')
                 )
                 if session_info.get('intermessage_gap_mavg') is not None:
                     hub_intermessage_gap_mavg_seconds.add_metric(
-                        [sid], session_info.get('intermessage_gap_mavg'), timestamp=session_info.get('timestamp')
+                        [tenant, sid], session_info.get('intermessage_gap_mavg'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('residence_time_mavg') is not None:
+                    hub_residence_time_mavg_seconds.add_metric(
+                        [tenant, sid], session_info.get('residence_time_mavg'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('residence_time_worst') is not None:
+                    hub_residence_time_worst_seconds.add_metric(
+                        [tenant, sid], session_info.get('residence_time_worst'), timestamp=session_info.get('timestamp')
                     )
                 if session_info.get('receive_time_windows') is not None:
                     i = 0
                     for window in session_info.get('receive_time_windows'):
                         hub_receive_time_window_messages.add_metric(
-                            [sid, str(i)],
+                            [tenant, sid, str(i)],
                             window, timestamp=session_info.get('timestamp')
                         )
                         i += 1
+                if session_info.get('receive_burstiness') is not None:
+                    hub_receive_burstiness.add_metric(
+                        [tenant, sid], session_info.get('receive_burstiness'), timestamp=session_info.get('timestamp')
+                    )
+                for reason in ['rejected_reply_size', 'rejected_amplification', 'rejected_bandwidth', 'rate_limited']:
+                    if session_info.get(reason) is not None:
+                        hub_rejected_messages.add_metric(
+                            [tenant, sid, reason], session_info.get(reason), timestamp=session_info.get('timestamp')
+                        )
+                for impairment in ['dropped', 'rate_limited', 'duplicated', 'reordered']:
+                    if session_info.get('impairment_' + impairment) is not None:
+                        hub_impaired_messages.add_metric(
+                            [tenant, sid, impairment], session_info.get('impairment_' + impairment), timestamp=session_info.get('timestamp')
+                        )
+                if session_info.get('interval_loss_percent') is not None:
+                    hub_interval_loss_percent.add_metric(
+                        [tenant, sid], session_info.get('interval_loss_percent'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('interval_received_pps') is not None:
+                    hub_interval_received_pps.add_metric(
+                        [tenant, sid], session_info.get('interval_received_pps'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('corrupted_messages') is not None:
+                    hub_corrupted_messages.add_metric(
+                        [tenant, sid], session_info.get('corrupted_messages'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('sent_bytes') is not None:
+                    hub_sent_payload_bytes.add_metric(
+                        [tenant, sid], session_info.get('sent_bytes'), timestamp=session_info.get('timestamp')
+                    )
+                for direction in ['sent', 'received']:
+                    for measure, suffix in [('wire', '_bps'), ('goodput', '_goodput_bps')]:
+                        if session_info.get('interval_' + direction + suffix) is not None:
+                            hub_interval_bps.add_metric(
+                                [tenant, sid, direction, measure], session_info.get('interval_' + direction + suffix), timestamp=session_info.get('timestamp')
+                            )
+                for measurement in ['rx', 'residence']:
+                    if session_info.get(measurement + '_timestamp_source') is not None:
+                        hub_timestamp_source.add_metric(
+                            [tenant, sid, measurement, session_info.get(measurement + '_timestamp_source')], 1, timestamp=session_info.get('timestamp')
+                        )
+
+        with hub_acl_lock:
+            for rule in hub_acl.get('rules', []):
+                hub_acl_hits.add_metric(
+                    [rule.get('action'), rule.get('prefix')], rule.get('hits'), timestamp=hub_acl.get('timestamp')
+                )
+            if hub_acl.get('default_hits') is not None:
+                hub_acl_hits.add_metric(
+                    [hub_acl.get('default_action'), 'default'], hub_acl.get('default_hits'), timestamp=hub_acl.get('timestamp')
+                )
 
         with client_sessions_lock:
             for sid, session_info in client_sessions.items():
@@ -214,6 +477,27 @@ class CustomCollector(object):
                     client_intermessage_gap_mavg_seconds.add_metric(
                         [sid], session_info.get('intermessage_gap_mavg'), timestamp=session_info.get('timestamp')
                     )
+                if session_info.get('send_error_mavg') is not None:
+                    client_send_error_mavg_seconds.add_metric(
+                        [sid], session_info.get('send_error_mavg'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('send_error_worst') is not None:
+                    client_send_error_worst_seconds.add_metric(
+                        [sid], session_info.get('send_error_worst'), timestamp=session_info.get('timestamp')
+                    )
+                for quantile in ['p50', 'p90', 'p99', 'p999', 'max']:
+                    if session_info.get('send_lateness_' + quantile) is not None:
+                        client_send_lateness_seconds.add_metric(
+                            [sid, quantile], session_info.get('send_lateness_' + quantile), timestamp=session_info.get('timestamp')
+                        )
+                if session_info.get('send_skipped') is not None:
+                    client_skipped_sends.add_metric(
+                        [sid], session_info.get('send_skipped'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('send_catch_up') is not None:
+                    client_catch_up_sends.add_metric(
+                        [sid], session_info.get('send_catch_up'), timestamp=session_info.get('timestamp')
+                    )
                 if session_info.get('receive_time_windows') is not None:
                     i = 0
                     for window in session_info.get('receive_time_windows'):
@@ -222,6 +506,67 @@ class CustomCollector(object):
                             window, timestamp=session_info.get('timestamp')
                         )
                         i += 1
+                if session_info.get('receive_burstiness') is not None:
+                    client_receive_burstiness.add_metric(
+                        [sid], session_info.get('receive_burstiness'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('interval_loss_percent') is not None:
+                    client_interval_loss_percent.add_metric(
+                        [sid], session_info.get('interval_loss_percent'), timestamp=session_info.get('timestamp')
+                    )
+                for stat in ['min', 'avg', 'max']:
+                    if session_info.get('interval_rtt_' + stat) is not None:
+                        client_interval_rtt_seconds.add_metric(
+                            [sid, stat], session_info.get('interval_rtt_' + stat), timestamp=session_info.get('timestamp')
+                        )
+                if session_info.get('interval_received_pps') is not None:
+                    client_interval_received_pps.add_metric(
+                        [sid], session_info.get('interval_received_pps'), timestamp=session_info.get('timestamp')
+                    )
+                for direction, field in [('forward', 'hub_corrupted_messages'), ('reverse', 'corrupted_messages')]:
+                    if session_info.get(field) is not None:
+                        client_corrupted_messages.add_metric(
+                            [sid, direction], session_info.get(field), timestamp=session_info.get('timestamp')
+                        )
+                if session_info.get('sent_bytes') is not None:
+                    client_sent_payload_bytes.add_metric(
+                        [sid], session_info.get('sent_bytes'), timestamp=session_info.get('timestamp')
+                    )
+                for direction in ['sent', 'received']:
+                    for measure, suffix in [('wire', '_bps'), ('goodput', '_goodput_bps')]:
+                        if session_info.get('interval_' + direction + suffix) is not None:
+                            client_interval_bps.add_metric(
+                                [sid, direction, measure], session_info.get('interval_' + direction + suffix), timestamp=session_info.get('timestamp')
+                            )
+                if session_info.get('hub_received_messages') is not None:
+                    client_hub_received_messages.add_metric(
+                        [sid], session_info.get('hub_received_messages'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('hub_policed_messages') is not None:
+                    client_hub_policed_messages.add_metric(
+                        [sid], session_info.get('hub_policed_messages'), timestamp=session_info.get('timestamp')
+                    )
+                for measurement in ['rx', 'rtt']:
+                    if session_info.get(measurement + '_timestamp_source') is not None:
+                        client_timestamp_source.add_metric(
+                            [sid, measurement, session_info.get(measurement + '_timestamp_source')], 1, timestamp=session_info.get('timestamp')
+                        )
+                if session_info.get('forward_lost_messages') is not None:
+                    client_forward_lost_messages.add_metric(
+                        [sid], session_info.get('forward_lost_messages'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('reverse_lost_messages') is not None:
+                    client_reverse_lost_messages.add_metric(
+                        [sid], session_info.get('reverse_lost_messages'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('forward_reordered_messages') is not None:
+                    client_forward_reordered_messages.add_metric(
+                        [sid], session_info.get('forward_reordered_messages'), timestamp=session_info.get('timestamp')
+                    )
+                if session_info.get('reverse_reordered_messages') is not None:
+                    client_reverse_reordered_messages.add_metric(
+                        [sid], session_info.get('reverse_reordered_messages'), timestamp=session_info.get('timestamp')
+                    )
 
         yield hub_received_messages
         yield hub_holes_created
@@ -230,7 +575,18 @@ class CustomCollector(object):
         yield hub_holes_current
         yield hub_payload_bytes
         yield hub_intermessage_gap_mavg_seconds
+        yield hub_residence_time_mavg_seconds
+        yield hub_residence_time_worst_seconds
         yield hub_receive_time_window_messages
+        yield hub_receive_burstiness
+        yield hub_rejected_messages
+        yield hub_impaired_messages
+        yield hub_interval_loss_percent
+        yield hub_interval_received_pps
+        yield hub_corrupted_messages
+        yield hub_sent_payload_bytes
+        yield hub_interval_bps
+        yield hub_acl_hits
 
         yield client_unexpected_increments
         yield client_unexpected_decrements
@@ -244,25 +600,187 @@ class CustomCollector(object):
         yield client_rtt_mavg_seconds
         yield client_payload_bytes
         yield client_intermessage_gap_mavg_seconds
+        yield client_send_error_mavg_seconds
+        yield client_send_error_worst_seconds
+        yield client_send_lateness_seconds
+        yield client_skipped_sends
+        yield client_catch_up_sends
         yield client_receive_time_window_messages
+        yield client_receive_burstiness
+        yield client_interval_loss_percent
+        yield client_interval_rtt_seconds
+        yield client_interval_received_pps
+        yield client_corrupted_messages
+        yield client_sent_payload_bytes
+        yield client_interval_bps
+        yield client_hub_received_messages
+        yield client_hub_policed_messages
+        yield client_forward_lost_messages
+        yield client_reverse_lost_messages
+        yield client_forward_reordered_messages
+        yield client_reverse_reordered_messages
+        yield hub_timestamp_source
+        yield client_timestamp_source
+
+        with record_sequences_lock:
+            for clocktower_type, count in lost_records.items():
+                clocktower_lost_records.add_metric([clocktower_type], count)
+            for clocktower_type, count in counter_resets.items():
+                clocktower_counter_resets.add_metric([clocktower_type], count)
+        yield clocktower_lost_records
+        yield clocktower_counter_resets
+
+        with invalid_records_lock:
+            for clocktower_type, count in invalid_records.items():
+                clocktower_invalid_records.add_metric([clocktower_type], count)
+        yield clocktower_invalid_records
+
+
+def log_statistics(payload):
+    with output_lock:
+        print(json.dumps(payload))
+
+
+def track_record_sequence(clocktower_type, session_key, payload):
+    # False for duplicates and records older than one already seen, which would move counters backwards
+    epoch = payload.get('epoch')
+    stats_seq = payload.get('stats_seq')
+    if epoch is None or stats_seq is None:
+        return True
+    with record_sequences_lock:
+        previous = record_sequences.get((clocktower_type, session_key))
+        if previous is not None:
+            previous_epoch, previous_seq, _ = previous
+            if epoch != previous_epoch:
+                counter_resets[clocktower_type] = counter_resets.get(clocktower_type, 0) + 1
+            elif stats_seq <= previous_seq:
+                return False
+            elif stats_seq > previous_seq + 1:
+                lost_records[clocktower_type] = lost_records.get(clocktower_type, 0) + stats_seq - previous_seq - 1
+        record_sequences[(clocktower_type, session_key)] = (epoch, stats_seq, time.time())
+    return True
 
 
 def inject_client_session_statistics(payload):
     global client_sessions
     global client_sessions_lock
     sid = payload.get('sid')
+    if not track_record_sequence('client_session_statistics', sid, payload):
+        return
     with client_sessions_lock:
-        print(json.dumps(payload))
+        log_statistics(payload)
         client_sessions[sid] = payload
 
 
 def inject_hub_session_statistics(payload):
     global hub_sessions
     global hub_sessions_lock
-    sid = payload.get('sid')
+    session_key = (payload.get('tenant', 'default'), payload.get('sid'))
+    if not track_record_sequence('hub_session_statistics', session_key, payload):
+        return
     with hub_sessions_lock:
-        print(json.dumps(payload))
-        hub_sessions[sid] = payload
+        log_statistics(payload)
+        hub_sessions[session_key] = payload
+
+
+def inject_hub_acl_statistics(payload):
+    global hub_acl
+    global hub_acl_lock
+    with hub_acl_lock:
+        log_statistics(payload)
+        hub_acl = payload
+
+
+def validate_record(payload):
+    global last_invalid_record_printed
+    if record_validators is None:
+        return True
+    clocktower_type = str(payload.get('clocktower_type'))
+    if clocktower_type in record_validators:
+        error = jsonschema.exceptions.best_match(record_validators[clocktower_type].iter_errors(payload))
+        if error is None:
+            return True
+        reason = '{} at {}'.format(error.message, '/'.join(str(part) for part in error.absolute_path) or 'top level')
+    else:
+        reason = 'unknown clocktower_type'
+    with invalid_records_lock:
+        invalid_records[clocktower_type] = invalid_records.get(clocktower_type, 0) + 1
+        if time.time() - last_invalid_record_printed > 10.0:
+            print('dropping invalid {} record: {}'.format(clocktower_type, reason), file=sys.stderr)
+            last_invalid_record_printed = time.time()
+    return False
+
+
+def inject_statistics(payload):
+    if not isinstance(payload, dict):
+        return
+    if not validate_record(payload):
+        return
+    if payload.get('clocktower_type') == 'hub_session_statistics':
+        inject_hub_session_statistics(payload)
+    if payload.get('clocktower_type') == 'client_session_statistics':
+        inject_client_session_statistics(payload)
+    if payload.get('clocktower_type') == 'hub_acl_statistics':
+        inject_hub_acl_statistics(payload)
+
+
+def decode_statistics(data):
+    try:
+        return json.loads(data.decode('utf-8', errors='ignore'))
+    except json.decoder.JSONDecodeError:
+        return None
+
+
+class TcpStatisticsHandler(socketserver.BaseRequestHandler):
+    # Each record is a big-endian u32 length and the JSON, a zero length asks for the number
+    # of records taken since the last acknowledgement
+    def read_exactly(self, length):
+        data = b''
+        while len(data) < length:
+            chunk = self.request.recv(length - len(data))
+            if not chunk:
+                return None
+            data += chunk
+        return data
+
+    def handle(self):
+        received = 0
+        while True:
+            header = self.read_exactly(4)
+            if header is None:
+                return
+            length = struct.unpack('>I', header)[0]
+            if length == 0:
+                self.request.sendall(struct.pack('>I', received))
+                received = 0
+                continue
+            data = self.read_exactly(length)
+            if data is None:
+                return
+            inject_statistics(decode_statistics(data))
+            received += 1
+
+
+class HttpStatisticsHandler(http.server.BaseHTTPRequestHandler):
+    def do_POST(self):
+        length = int(self.headers.get('Content-Length', 0))
+        payload = decode_statistics(self.rfile.read(length))
+        if payload is None:
+            self.send_response(400)
+            self.end_headers()
+            return
+        for record in payload if isinstance(payload, list) else [payload]:
+            inject_statistics(record)
+        self.send_response(204)
+        self.end_headers()
+
+    def log_message(self, format, *args):
+        pass
+
+
+def serve_forever_in_background(server):
+    server.daemon_threads = True
+    threading.Thread(target=server.serve_forever, daemon=True).start()
 
 
 def cleanup_sessions():
@@ -289,6 +807,11 @@ def cleanup_sessions():
                     remove_list.append(sid)
             for sid in remove_list:
                 del hub_sessions[sid]
+        with record_sequences_lock:
+            # A session quiet for this long starts a new epoch if it comes back
+            remove_list = [key for key, (_, _, seen) in record_sequences.items() if current_time - seen > SESSION_TIMEOUT]
+            for key in remove_list:
+                del record_sequences[key]
         time.sleep(1)
 
 
@@ -296,15 +819,12 @@ def main():
     threading.Thread(target=cleanup_sessions, daemon=True).start()
     REGISTRY.register(CustomCollector())
     start_http_server(args.exporter_port)
+    if args.tcp_port is not None:
+        serve_forever_in_background(socketserver.ThreadingTCPServer((args.bind_address, args.tcp_port), TcpStatisticsHandler))
+    if args.http_port is not None:
+        serve_forever_in_background(http.server.ThreadingHTTPServer((args.bind_address, args.http_port), HttpStatisticsHandler))
     while True:
-        try:
-            payload = json.loads(msglistener.recv(4096).decode('ascii', errors='ignore'))
-            if payload.get('clocktower_type') == 'hub_session_statistics':
-                inject_hub_session_statistics(payload)
-            if payload.get('clocktower_type') == 'client_session_statistics':
-                inject_client_session_statistics(payload)
-        except json.decoder.JSONDecodeError:
-            pass
+        inject_statistics(decode_statistics(msglistener.recv(65535)))
 
 
 if __name__ == '__main__':
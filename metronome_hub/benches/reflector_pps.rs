@@ -0,0 +1,111 @@
+//! Measures how many pings per second a hub on localhost answers with and without batched I/O.
+//!
+//! Run with `cargo bench -p metronome_hub`. Each sender keeps a fixed window of pings in flight,
+//! the answered rate is the sustained reply rate of the hub.
+extern crate metronome_lib;
+use metronome_lib::datatypes::MetronomeMessage;
+
+const SENDERS: usize = 4;
+const WINDOW: usize = 64;
+const WARMUP_SECONDS: f64 = 0.5;
+const MEASURE_SECONDS: f64 = 3.0;
+const BATCH_SIZES: [&str; 2] = ["1", "32"];
+
+fn free_local_port() -> u16 {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("failed to bind probe socket");
+    return socket.local_addr().unwrap().port();
+}
+
+/// Keeps WINDOW pings in flight and sends a new one for every pong, so the senders only use as much
+/// CPU as the hub lets them and the answered rate reflects the hub rather than the load generator
+fn run_sender(hub: std::net::SocketAddr, sid: String, running: std::sync::Arc<std::sync::atomic::AtomicBool>, measuring: std::sync::Arc<std::sync::atomic::AtomicBool>, sent: std::sync::Arc<std::sync::atomic::AtomicU64>, answered: std::sync::Arc<std::sync::atomic::AtomicU64>) {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("failed to bind sender socket");
+    socket.connect(hub).expect("failed to connect sender socket");
+    socket.set_read_timeout(Some(std::time::Duration::from_millis(10))).unwrap();
+
+    let mut msg = MetronomeMessage {
+        mode: "ping".to_string(),
+        payload: Some("X".repeat(32)),
+        mul: 1.0,
+        seq: 0,
+        key: "bench".to_string(),
+        sid: sid,
+        hub_stats_requested: false,
+        hub_stats: None,
+        payload_checksum: None,
+    };
+    let mut rxbuf = [0; 65536];
+    let mut to_send: usize = WINDOW;
+    while running.load(std::sync::atomic::Ordering::Relaxed) {
+        for _i in 0..to_send {
+            if let Ok(serialized) = msg.as_vec() {
+                if socket.send(&serialized).is_ok() && measuring.load(std::sync::atomic::Ordering::Relaxed) {
+                    sent.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+            msg.seq += 1;
+        }
+        match socket.recv(&mut rxbuf) {
+            Ok(_) => {
+                if measuring.load(std::sync::atomic::Ordering::Relaxed) {
+                    answered.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+                to_send = 1;
+            },
+            Err(_) => {
+                // Replies were lost, refill the window
+                to_send = WINDOW;
+            }
+        }
+    }
+}
+
+fn measure(batch_size: &str) -> (f64, f64) {
+    let clocktower = std::net::UdpSocket::bind("127.0.0.1:0").expect("failed to bind clocktower socket");
+    let hub_addr: std::net::SocketAddr = format!("127.0.0.1:{}", free_local_port()).parse().unwrap();
+
+    let mut hub = std::process::Command::new(env!("CARGO_BIN_EXE_metronome_hub"))
+        .args(["--bind", &hub_addr.to_string(), "--key", "bench", "--clocktower", &clocktower.local_addr().unwrap().to_string(), "--batch-size", batch_size])
+        .spawn()
+        .expect("failed to start metronome_hub");
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    let running = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let measuring = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let sent = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let answered = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+
+    let mut senders = Vec::new();
+    for sender_index in 0..SENDERS {
+        let running_sender = running.clone();
+        let measuring_sender = measuring.clone();
+        let sent_sender = sent.clone();
+        let answered_sender = answered.clone();
+        senders.push(std::thread::spawn(move || {
+            run_sender(hub_addr, format!("bench-{}", sender_index), running_sender, measuring_sender, sent_sender, answered_sender);
+        }));
+    }
+
+    std::thread::sleep(std::time::Duration::from_secs_f64(WARMUP_SECONDS));
+    measuring.store(true, std::sync::atomic::Ordering::Relaxed);
+    std::thread::sleep(std::time::Duration::from_secs_f64(MEASURE_SECONDS));
+    measuring.store(false, std::sync::atomic::Ordering::Relaxed);
+    running.store(false, std::sync::atomic::Ordering::Relaxed);
+
+    for sender in senders {
+        sender.join().unwrap();
+    }
+    let _ = hub.kill();
+    let _ = hub.wait();
+
+    let sent_pps = sent.load(std::sync::atomic::Ordering::Relaxed) as f64 / MEASURE_SECONDS;
+    let answered_pps = answered.load(std::sync::atomic::Ordering::Relaxed) as f64 / MEASURE_SECONDS;
+    return (sent_pps, answered_pps);
+}
+
+fn main() {
+    for batch_size in BATCH_SIZES.iter() {
+        let (sent_pps, answered_pps) = measure(batch_size);
+        println!("batch-size {:>3}: {:>10.0} pps sent, {:>10.0} pps answered ({} senders, {} in flight each)", batch_size, sent_pps, answered_pps, SENDERS, WINDOW);
+    }
+}
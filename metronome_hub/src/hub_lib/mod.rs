@@ -9,60 +9,2246 @@ pub mod datatypes {
         pub noncontigous: u64,
     }
 
+    /// Seconds without messages after which a session is dropped
+    pub const SESSION_TIMEOUT: f64 = 5.0;
+
+    /// Identifies a session by tenant and sid, and carries the session's latest counters for the reply path
+    ///
+    /// Equality and hashing only look at tenant and sid, so the counters may change while the key sits in a map
+    pub struct SessionKey {
+        pub tenant: std::string::String,
+        pub sid: std::string::String,
+        pub counters: CountersSnapshot,
+    }
+
+    impl SessionKey {
+        pub fn new(tenant: &str, sid: &str) -> SessionKey {
+            return SessionKey {
+                tenant: tenant.to_string(),
+                sid: sid.to_string(),
+                counters: CountersSnapshot::default(),
+            };
+        }
+    }
+
+    impl PartialEq for SessionKey {
+        fn eq(&self, other: &SessionKey) -> bool {
+            return self.tenant == other.tenant && self.sid == other.sid;
+        }
+    }
+
+    impl Eq for SessionKey {}
+
+    impl std::hash::Hash for SessionKey {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            self.tenant.hash(state);
+            self.sid.hash(state);
+        }
+    }
+
+    impl std::fmt::Debug for SessionKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            return write!(f, "{}/{}", self.tenant, self.sid);
+        }
+    }
+
+    /// Session counters as of the last message the analyzer counted, so pongs can carry them without
+    /// taking the session table lock. Written by the analyzer of the session's shard only, a sequence
+    /// number keeps readers from seeing half of an update.
+    #[derive(Default)]
+    pub struct CountersSnapshot {
+        sequence: std::sync::atomic::AtomicU64,
+        last_rx: std::sync::atomic::AtomicU64,
+        received_messages: std::sync::atomic::AtomicU64,
+        holes_created: std::sync::atomic::AtomicU64,
+        holes_closed: std::sync::atomic::AtomicU64,
+        holes_timed_out: std::sync::atomic::AtomicU64,
+        holes_current: std::sync::atomic::AtomicU64,
+        policed_messages: std::sync::atomic::AtomicU64,
+        corrupted_messages: std::sync::atomic::AtomicU64,
+    }
+
+    impl CountersSnapshot {
+        /// `last_rx` is the monotonic time of the session's latest message
+        pub fn publish(&self, counters: &metronome_lib::datatypes::HubSessionCounters, last_rx: f64) {
+            use std::sync::atomic::Ordering;
+            let sequence = self.sequence.load(Ordering::Relaxed);
+            self.sequence.store(sequence + 1, Ordering::Relaxed);
+            std::sync::atomic::fence(Ordering::Release);
+            self.last_rx.store(last_rx.to_bits(), Ordering::Relaxed);
+            self.received_messages.store(counters.received_messages, Ordering::Relaxed);
+            self.holes_created.store(counters.holes_created, Ordering::Relaxed);
+            self.holes_closed.store(counters.holes_closed, Ordering::Relaxed);
+            self.holes_timed_out.store(counters.holes_timed_out, Ordering::Relaxed);
+            self.holes_current.store(counters.holes_current, Ordering::Relaxed);
+            self.policed_messages.store(counters.policed_messages, Ordering::Relaxed);
+            self.corrupted_messages.store(counters.corrupted_messages, Ordering::Relaxed);
+            self.sequence.store(sequence + 2, Ordering::Release);
+        }
+
+        /// None before anything was published and once the session has been quiet long enough to have expired
+        pub fn load(&self, current_time: f64) -> Option<metronome_lib::datatypes::HubSessionCounters> {
+            use std::sync::atomic::Ordering;
+            loop {
+                let sequence = self.sequence.load(Ordering::Acquire);
+                if sequence == 0 {
+                    return None;
+                }
+                if sequence % 2 == 1 {
+                    std::hint::spin_loop();
+                    continue;
+                }
+                let last_rx = f64::from_bits(self.last_rx.load(Ordering::Relaxed));
+                let counters = metronome_lib::datatypes::HubSessionCounters {
+                    received_messages: self.received_messages.load(Ordering::Relaxed),
+                    holes_created: self.holes_created.load(Ordering::Relaxed),
+                    holes_closed: self.holes_closed.load(Ordering::Relaxed),
+                    holes_timed_out: self.holes_timed_out.load(Ordering::Relaxed),
+                    holes_current: self.holes_current.load(Ordering::Relaxed),
+                    policed_messages: self.policed_messages.load(Ordering::Relaxed),
+                    corrupted_messages: self.corrupted_messages.load(Ordering::Relaxed),
+                };
+                std::sync::atomic::fence(Ordering::Acquire);
+                if self.sequence.load(Ordering::Relaxed) != sequence {
+                    continue;
+                }
+                if current_time - last_rx > SESSION_TIMEOUT {
+                    return None;
+                }
+                return Some(counters);
+            }
+        }
+    }
+
+    pub type SessionData = std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::sync::Arc<SessionKey>, metronome_lib::datatypes::SessionContainer>>>;
+
+    /// Session table split into independently locked shards by session key hash
+    #[derive(Clone)]
+    pub struct SessionShards {
+        shards: Vec<SessionData>,
+        // Sessions per tenant, kept up to date on insert and removal so the cap is checked without locking
+        tenant_sessions: std::sync::Arc<std::collections::HashMap<std::string::String, std::sync::atomic::AtomicUsize>>,
+    }
+
+    impl SessionShards {
+        pub fn new(shard_count: usize, tenants: &[TenantConfig]) -> SessionShards {
+            return SessionShards {
+                shards: (0..shard_count.max(1)).map(|_| std::sync::Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()))).collect(),
+                tenant_sessions: std::sync::Arc::new(tenants.iter().map(|tenant| (tenant.name.clone(), std::sync::atomic::AtomicUsize::new(0))).collect()),
+            };
+        }
+
+        pub fn shard_index(&self, session_key: &SessionKey) -> usize {
+            use std::hash::{Hash, Hasher};
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            session_key.hash(&mut hasher);
+            return (hasher.finish() % self.shards.len() as u64) as usize;
+        }
+
+        pub fn shard(&self, session_key: &SessionKey) -> &SessionData {
+            return &self.shards[self.shard_index(session_key)];
+        }
+
+        #[allow(clippy::mutable_key_type)]
+        pub fn shards(&self) -> &[SessionData] {
+            return &self.shards;
+        }
+
+        /// Counts handled messages into the sessions of one shard, a session starts with its first message
+        pub fn analyze(&self, shard_index: usize, handled_messages: Vec<HandledMessage>, receive_time_windows: &metronome_lib::datatypes::ReceiveTimeWindows) {
+            if let Ok(mut session_data) = self.shards[shard_index].lock() {
+                for handled_message in handled_messages {
+                    if let Some(session_statistics) = session_data.get_mut(&handled_message.session_key) {
+                        session_statistics.seq_analyze(handled_message.seq, handled_message.message_raw_size, &handled_message.rx_timestamps);
+                        handled_message.record(session_statistics);
+                        handled_message.session_key.counters.publish(&session_statistics.get_counters(), session_statistics.last_rx);
+                    } else {
+                        let mut session_statistics = metronome_lib::datatypes::SessionContainer::new(handled_message.seq, handled_message.message_raw_size, &handled_message.rx_timestamps);
+                        session_statistics.receive_time_windows = receive_time_windows.clone();
+                        handled_message.record(&mut session_statistics);
+                        handled_message.session_key.counters.publish(&session_statistics.get_counters(), session_statistics.last_rx);
+                        if let Some(count) = self.tenant_sessions.get(&handled_message.session_key.tenant) {
+                            count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        }
+                        session_data.insert(handled_message.session_key, session_statistics);
+                    }
+                }
+            }
+        }
+
+        /// Removes a session from the shard it belongs to, `session_data` being that shard locked
+        #[allow(clippy::mutable_key_type)]
+        pub fn remove(&self, session_data: &mut std::collections::HashMap<std::sync::Arc<SessionKey>, metronome_lib::datatypes::SessionContainer>, session_key: &SessionKey) {
+            if session_data.remove(session_key).is_some() {
+                if let Some(count) = self.tenant_sessions.get(&session_key.tenant) {
+                    count.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        }
+
+        pub fn contains(&self, session_key: &SessionKey) -> bool {
+            match self.shard(session_key).lock() {
+                Ok(session_data) => return session_data.contains_key(session_key),
+                Err(_) => return false,
+            }
+        }
+
+        pub fn tenant_session_count(&self, tenant: &str) -> usize {
+            return self.tenant_sessions.get(tenant).map(|count| count.load(std::sync::atomic::Ordering::Relaxed)).unwrap_or(0);
+        }
+    }
+
+    /// Routes handled messages to the analyzer of the shard their session belongs to
+    #[derive(Clone)]
+    pub struct AnalyzerDispatch {
+        session_shards: SessionShards,
+        senders: Vec<std::sync::mpsc::Sender<Vec<HandledMessage>>>,
+    }
+
+    impl AnalyzerDispatch {
+        pub fn new(session_shards: SessionShards, senders: Vec<std::sync::mpsc::Sender<Vec<HandledMessage>>>) -> AnalyzerDispatch {
+            return AnalyzerDispatch {
+                session_shards: session_shards,
+                senders: senders,
+            };
+        }
+
+        pub fn send(&self, handled_messages: Vec<HandledMessage>) -> Result<(), std::sync::mpsc::SendError<Vec<HandledMessage>>> {
+            if self.senders.len() == 1 {
+                return self.senders[0].send(handled_messages);
+            }
+            let mut sharded: Vec<Vec<HandledMessage>> = self.senders.iter().map(|_| Vec::new()).collect();
+            for handled_message in handled_messages {
+                sharded[self.session_shards.shard_index(&handled_message.session_key)].push(handled_message);
+            }
+            for (sender, shard_messages) in self.senders.iter().zip(sharded) {
+                if !shard_messages.is_empty() {
+                    sender.send(shard_messages)?;
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct TenantConfig {
+        pub name: std::string::String,
+        pub key: std::string::String,
+        #[serde(default)]
+        pub allowed_prefixes: Vec<super::cidr::IpPrefix>,
+        #[serde(default)]
+        pub rate_limit: Option<super::policy::RateLimitSpec>,
+        #[serde(default)]
+        pub session_rate_limit: Option<super::policy::RateLimitSpec>,
+        #[serde(default)]
+        pub max_sessions: Option<usize>,
+        #[serde(default)]
+        pub max_payload_size: Option<usize>,
+    }
+
+    impl TenantConfig {
+        pub fn allows_source(&self, source: &std::net::IpAddr) -> bool {
+            return self.allowed_prefixes.is_empty() || self.allowed_prefixes.iter().any(|prefix| prefix.contains(source));
+        }
+
+        pub fn load_from_file(path: &str) -> Result<Vec<TenantConfig>, std::string::String> {
+            let tenants_json = std::fs::read_to_string(path).map_err(|e| format!("failed to read tenants from {}: {}", path, e))?;
+            return serde_json::from_str(&tenants_json).map_err(|e| format!("failed to parse tenants from {}: {}", path, e));
+        }
+    }
+
     #[derive(Clone)]
     pub struct ServerConfig {
         pub bind: std::net::SocketAddr,
-        pub key: String,
+        pub tenants: Vec<TenantConfig>,
         pub stats_interval: f64,
+        pub stats_deltas: bool,
+        /// Copied into every new session
+        pub receive_time_windows: metronome_lib::datatypes::ReceiveTimeWindows,
+        pub max_reply_size: usize,
+        pub max_amplification: f64,
+        pub max_source_reply_bps: Option<f64>,
+        pub rate_limit: Option<super::policy::RateLimitSpec>,
+        pub session_rate_limit: Option<super::policy::RateLimitSpec>,
+        pub prefix_rate_limits: Vec<super::policy::PrefixRateLimit>,
+        pub acl_rules: Vec<super::acl::AclRule>,
+        pub batch_size: usize,
+        pub workers: usize,
+        pub inline_reflect: bool,
+        pub timestamping: metronome_lib::timestamping::TimestampingMode,
+        pub impairments: Vec<super::impairment::ImpairmentRule>,
+        pub impairment_seed: u64,
+    }
+
+    /// Defaults match those of the command line, without any tenants
+    impl Default for ServerConfig {
+        fn default() -> ServerConfig {
+            return ServerConfig {
+                bind: std::net::SocketAddr::from(([0, 0, 0, 0], 0)),
+                tenants: Vec::new(),
+                stats_interval: 1.0,
+                stats_deltas: false,
+                receive_time_windows: metronome_lib::datatypes::ReceiveTimeWindows::default(),
+                max_reply_size: 65507,
+                max_amplification: 10.0,
+                max_source_reply_bps: None,
+                rate_limit: None,
+                session_rate_limit: None,
+                prefix_rate_limits: Vec::new(),
+                acl_rules: Vec::new(),
+                batch_size: 1,
+                workers: 1,
+                inline_reflect: false,
+                timestamping: metronome_lib::timestamping::TimestampingMode::Off,
+                impairments: Vec::new(),
+                impairment_seed: 0,
+            };
+        }
+    }
+
+    impl ServerConfig {
+        pub fn tenants_by_key(&self) -> std::collections::HashMap<std::string::String, TenantConfig> {
+            return self.tenants.iter().map(|tenant| (tenant.key.clone(), tenant.clone())).collect();
+        }
     }
 
     pub struct WrappedSerializedMessage {
         pub addr: std::net::SocketAddr,
         pub serialized_message: Vec<u8>,
+        pub handled_message: HandledMessage,
+    }
+
+    pub struct HandledMessage {
+        pub verdict: super::policy::PolicyVerdict,
+        pub session_key: std::sync::Arc<SessionKey>,
+        pub seq: u64,
+        pub message_raw_size: usize,
+        /// Size of the pong, 0 when none was written
+        pub reply_size: usize,
+        /// IP and UDP header bytes per datagram exchanged with the client
+        pub ip_overhead: u64,
+        /// The payload did not match the checksum sent along with it
+        pub corrupted: bool,
+        pub rx_timestamps: metronome_lib::timestamping::Timestamps,
+        pub residence_time: Option<f64>,
+        pub residence_timestamp_source: Option<metronome_lib::timestamping::TimestampSource>,
+        pub impairment: super::impairment::ImpairmentVerdict,
+    }
+
+    impl HandledMessage {
+        /// Records how long the message stayed in the hub given the timestamps of its reply
+        pub fn replied(&mut self, tx_timestamps: &metronome_lib::timestamping::Timestamps) {
+            let (residence_time, source) = self.rx_timestamps.elapsed_until(tx_timestamps);
+            self.residence_time = Some(residence_time);
+            self.residence_timestamp_source = Some(source);
+        }
+
+        /// The pong could not be sent, so it is not counted as sent either
+        pub fn send_failed(&mut self) {
+            self.reply_size = 0;
+        }
+
+        /// Counts the verdict, pongs sent, residence time and timestamp sources into the session
+        pub fn record(&self, session_container: &mut metronome_lib::datatypes::SessionContainer) {
+            self.verdict.count(session_container);
+            self.impairment.count(session_container);
+            session_container.ip_overhead = self.ip_overhead;
+            if self.corrupted {
+                session_container.corrupted_messages += 1;
+            }
+            if self.verdict == super::policy::PolicyVerdict::Accepted && self.reply_size > 0 && !self.impairment.dropped && !self.impairment.rate_limited {
+                session_container.sent(self.reply_size);
+                if self.impairment.duplicated {
+                    session_container.sent(self.reply_size);
+                }
+            }
+            if let (Some(residence_time), Some(source)) = (self.residence_time, self.residence_timestamp_source) {
+                session_container.residence_analyze(residence_time, source);
+            }
+            session_container.rx_timestamp_source = Some(self.rx_timestamps.best_source());
+        }
+    }
+}
+
+pub mod cidr {
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct IpPrefix {
+        pub addr: std::net::IpAddr,
+        pub len: u8,
+    }
+
+    impl IpPrefix {
+        pub fn contains(&self, addr: &std::net::IpAddr) -> bool {
+            match (self.addr, normalize(addr)) {
+                (std::net::IpAddr::V4(network), std::net::IpAddr::V4(candidate)) => {
+                    return masked(u32::from(network) as u128, 32, self.len) == masked(u32::from(candidate) as u128, 32, self.len);
+                },
+                (std::net::IpAddr::V6(network), std::net::IpAddr::V6(candidate)) => {
+                    return masked(u128::from(network), 128, self.len) == masked(u128::from(candidate), 128, self.len);
+                },
+                _ => {
+                    return false;
+                }
+            }
+        }
+    }
+
+    impl std::str::FromStr for IpPrefix {
+        type Err = std::string::String;
+
+        fn from_str(s: &str) -> Result<IpPrefix, std::string::String> {
+            let mut parts = s.splitn(2, '/');
+            let addr: std::net::IpAddr = parts.next().unwrap_or("").parse().map_err(|e| format!("invalid prefix address in '{}': {}", s, e))?;
+            let max_len: u8 = if addr.is_ipv4() { 32 } else { 128 };
+            let len: u8 = match parts.next() {
+                Some(len_str) => len_str.parse().map_err(|e| format!("invalid prefix length in '{}': {}", s, e))?,
+                None => max_len,
+            };
+            if len > max_len {
+                return Err(format!("prefix length in '{}' exceeds {}", s, max_len));
+            }
+            // Candidates are compared as IPv4 once unmapped, so mapped prefixes have to be too
+            if let (std::net::IpAddr::V4(unmapped), true) = (normalize(&addr), addr.is_ipv6() && len >= 96) {
+                return Ok(IpPrefix {
+                    addr: std::net::IpAddr::V4(unmapped),
+                    len: len - 96,
+                });
+            }
+            return Ok(IpPrefix {
+                addr: addr,
+                len: len,
+            });
+        }
+    }
+
+    impl<'de> serde::Deserialize<'de> for IpPrefix {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<IpPrefix, D::Error> {
+            let prefix_str = std::string::String::deserialize(deserializer)?;
+            return prefix_str.parse().map_err(serde::de::Error::custom);
+        }
+    }
+
+    impl std::fmt::Display for IpPrefix {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            return write!(f, "{}/{}", self.addr, self.len);
+        }
+    }
+
+    /// IPv4 clients talking to a dual-stack socket show up as IPv4-mapped IPv6 addresses
+    pub fn normalize(addr: &std::net::IpAddr) -> std::net::IpAddr {
+        if let std::net::IpAddr::V6(v6) = addr {
+            let segments = v6.segments();
+            if segments[0..5].iter().all(|segment| *segment == 0) && segments[5] == 0xffff {
+                let octets = v6.octets();
+                return std::net::IpAddr::V4(std::net::Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+            }
+        }
+        return *addr;
+    }
+
+    fn masked(value: u128, bits: u8, len: u8) -> u128 {
+        if len == 0 {
+            return 0;
+        }
+        return value >> (bits - len);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::IpPrefix;
+
+        fn prefix(s: &str) -> IpPrefix {
+            return s.parse().unwrap();
+        }
+
+        fn addr(s: &str) -> std::net::IpAddr {
+            return s.parse().unwrap();
+        }
+
+        #[test]
+        fn parses_prefixes() {
+            assert_eq!(prefix("192.0.2.0/24"), IpPrefix { addr: addr("192.0.2.0"), len: 24 });
+            assert_eq!(prefix("192.0.2.1").len, 32);
+            assert_eq!(prefix("2001:db8::1").len, 128);
+            assert_eq!(prefix("2001:db8::/32").to_string(), "2001:db8::/32");
+            assert!("192.0.2.0/33".parse::<IpPrefix>().is_err());
+            assert!("2001:db8::/129".parse::<IpPrefix>().is_err());
+            assert!("192.0.2.0/x".parse::<IpPrefix>().is_err());
+            assert!("192.0.2/24".parse::<IpPrefix>().is_err());
+        }
+
+        #[test]
+        fn matches_within_mask() {
+            let half = prefix("192.0.2.128/25");
+            assert!(half.contains(&addr("192.0.2.128")));
+            assert!(half.contains(&addr("192.0.2.255")));
+            assert!(!half.contains(&addr("192.0.2.127")));
+            // Not on an octet boundary, 10.8.0.0 - 10.15.255.255
+            let odd = prefix("10.8.0.0/13");
+            assert!(odd.contains(&addr("10.15.255.255")));
+            assert!(!odd.contains(&addr("10.16.0.0")));
+            assert!(!odd.contains(&addr("10.7.255.255")));
+            // Host bits set in the prefix address are ignored
+            assert!(prefix("192.0.2.77/24").contains(&addr("192.0.2.1")));
+
+            let site = prefix("2001:db8:1::/48");
+            assert!(site.contains(&addr("2001:db8:1:ffff::1")));
+            assert!(!site.contains(&addr("2001:db8:2::1")));
+            assert!(!site.contains(&addr("192.0.2.1")));
+        }
+
+        #[test]
+        fn zero_length_matches_the_whole_family() {
+            assert!(prefix("0.0.0.0/0").contains(&addr("203.0.113.9")));
+            assert!(prefix("0.0.0.0/0").contains(&addr("255.255.255.255")));
+            assert!(!prefix("0.0.0.0/0").contains(&addr("2001:db8::1")));
+            assert!(prefix("::/0").contains(&addr("2001:db8::1")));
+            assert!(!prefix("::/0").contains(&addr("203.0.113.9")));
+        }
+
+        #[test]
+        fn host_prefixes_match_exactly() {
+            assert!(prefix("192.0.2.1/32").contains(&addr("192.0.2.1")));
+            assert!(!prefix("192.0.2.1/32").contains(&addr("192.0.2.0")));
+            assert!(!prefix("192.0.2.1/32").contains(&addr("192.0.2.2")));
+            assert!(prefix("2001:db8::1/128").contains(&addr("2001:db8::1")));
+            assert!(!prefix("2001:db8::1/128").contains(&addr("2001:db8::")));
+            assert!(!prefix("2001:db8::1/128").contains(&addr("2001:db8::2")));
+        }
+
+        #[test]
+        fn v4_mapped_addresses_match_v4_prefixes() {
+            // IPv4 sources on a dual-stack socket
+            assert!(prefix("192.0.2.0/24").contains(&addr("::ffff:192.0.2.10")));
+            assert!(!prefix("192.0.2.0/24").contains(&addr("::ffff:198.51.100.10")));
+            assert!(prefix("192.0.2.10/32").contains(&addr("::ffff:192.0.2.10")));
+
+            // Mapped prefixes become the IPv4 prefix they cover
+            assert_eq!(prefix("::ffff:192.0.2.0/120"), prefix("192.0.2.0/24"));
+            assert!(prefix("::ffff:192.0.2.0/120").contains(&addr("192.0.2.10")));
+            assert!(prefix("::ffff:192.0.2.0/120").contains(&addr("::ffff:192.0.2.10")));
+            assert_eq!(prefix("::ffff:0.0.0.0/96"), prefix("0.0.0.0/0"));
+            assert_eq!(prefix("::ffff:192.0.2.1"), prefix("192.0.2.1/32"));
+        }
+    }
+}
+
+pub mod acl {
+    use super::cidr::IpPrefix;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum AclAction {
+        Allow,
+        Deny,
+    }
+
+    impl std::fmt::Display for AclAction {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                AclAction::Allow => return write!(f, "allow"),
+                AclAction::Deny => return write!(f, "deny"),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct AclRule {
+        pub action: AclAction,
+        pub prefix: IpPrefix,
+    }
+
+    /// Source address filter, the longest matching prefix decides and deny wins ties.
+    /// Sources matching no rule are allowed unless allow rules have been configured.
+    /// Packets are checked through a `WorkerAcl` per thread so hits are counted without contention.
+    pub struct SourceAcl {
+        rules: Vec<AclRule>,
+        default_action: AclAction,
+        worker_hits: std::sync::Mutex<Vec<std::sync::Arc<AclHits>>>,
+    }
+
+    /// Hit counters of one worker, written by that worker only. Aligned so workers never share a cache line.
+    #[repr(align(64))]
+    struct AclHits {
+        rules: Vec<std::sync::atomic::AtomicU64>,
+        default: std::sync::atomic::AtomicU64,
+    }
+
+    fn count_hit(hits: &std::sync::atomic::AtomicU64) {
+        // Single writer, a plain increment does not need an atomic read-modify-write
+        hits.store(hits.load(std::sync::atomic::Ordering::Relaxed) + 1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    impl SourceAcl {
+        pub fn new(rules: &[AclRule]) -> SourceAcl {
+            let mut sorted_rules = rules.to_vec();
+            sorted_rules.sort_by_key(|rule| (std::cmp::Reverse(rule.prefix.len), rule.action == AclAction::Allow));
+            let default_action = if rules.iter().any(|rule| rule.action == AclAction::Allow) {
+                AclAction::Deny
+            } else {
+                AclAction::Allow
+            };
+            return SourceAcl {
+                rules: sorted_rules,
+                default_action: default_action,
+                worker_hits: std::sync::Mutex::new(Vec::new()),
+            };
+        }
+
+        /// Handle for one thread checking packets, with its own hit counters
+        pub fn worker(self: &std::sync::Arc<SourceAcl>) -> WorkerAcl {
+            let hits = std::sync::Arc::new(AclHits {
+                rules: self.rules.iter().map(|_| std::sync::atomic::AtomicU64::new(0)).collect(),
+                default: std::sync::atomic::AtomicU64::new(0),
+            });
+            if let Ok(mut worker_hits) = self.worker_hits.lock() {
+                worker_hits.push(hits.clone());
+            }
+            return WorkerAcl {
+                acl: self.clone(),
+                hits: hits,
+            };
+        }
+
+        pub fn is_empty(&self) -> bool {
+            return self.rules.is_empty();
+        }
+
+        fn decide(&self, addr: &std::net::IpAddr) -> Option<usize> {
+            return self.rules.iter().position(|rule| rule.prefix.contains(addr));
+        }
+
+        pub fn rules(&self) -> &[AclRule] {
+            return &self.rules;
+        }
+
+        /// Hits per rule summed over all workers
+        pub fn hits(&self) -> Vec<u64> {
+            let mut hits: Vec<u64> = vec![0; self.rules.len()];
+            if let Ok(worker_hits) = self.worker_hits.lock() {
+                for worker in worker_hits.iter() {
+                    for (total, rule_hits) in hits.iter_mut().zip(worker.rules.iter()) {
+                        *total += rule_hits.load(std::sync::atomic::Ordering::Relaxed);
+                    }
+                }
+            }
+            return hits;
+        }
+
+        pub fn default_action(&self) -> AclAction {
+            return self.default_action;
+        }
+
+        pub fn default_hits(&self) -> u64 {
+            match self.worker_hits.lock() {
+                Ok(worker_hits) => return worker_hits.iter().map(|worker| worker.default.load(std::sync::atomic::Ordering::Relaxed)).sum(),
+                Err(_) => return 0,
+            }
+        }
+
+        pub fn statistics(&self, timestamp: f64) -> metronome_lib::schema::HubAclStatistics {
+            let rules = self.rules().iter().zip(self.hits().iter()).map(|(rule, hits)| metronome_lib::schema::AclRuleStatistics {
+                action: rule.action.to_string(),
+                prefix: rule.prefix.to_string(),
+                hits: *hits,
+            }).collect();
+            return metronome_lib::schema::HubAclStatistics::new(timestamp, rules, &self.default_action().to_string(), self.default_hits());
+        }
+    }
+
+    pub struct WorkerAcl {
+        acl: std::sync::Arc<SourceAcl>,
+        hits: std::sync::Arc<AclHits>,
+    }
+
+    impl WorkerAcl {
+        pub fn allows(&self, addr: &std::net::IpAddr) -> bool {
+            if self.acl.rules.is_empty() {
+                return true;
+            }
+            match self.acl.decide(addr) {
+                Some(index) => {
+                    count_hit(&self.hits.rules[index]);
+                    return self.acl.rules[index].action == AclAction::Allow;
+                },
+                None => {
+                    count_hit(&self.hits.default);
+                    return self.acl.default_action == AclAction::Allow;
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{AclAction, AclRule, SourceAcl};
+
+        fn rule(action: AclAction, prefix: &str) -> AclRule {
+            return AclRule {
+                action: action,
+                prefix: prefix.parse().unwrap(),
+            };
+        }
+
+        fn addr(s: &str) -> std::net::IpAddr {
+            return s.parse().unwrap();
+        }
+
+        #[test]
+        fn longest_prefix_decides_and_hits_add_up_over_workers() {
+            let acl = std::sync::Arc::new(SourceAcl::new(&[rule(AclAction::Deny, "192.0.2.0/24"), rule(AclAction::Allow, "192.0.2.128/25")]));
+            let first = acl.worker();
+            let second = acl.worker();
+            assert!(first.allows(&addr("192.0.2.200")));
+            assert!(second.allows(&addr("192.0.2.201")));
+            assert!(!second.allows(&addr("192.0.2.1")));
+            // Allow rules make everything else denied
+            assert!(!first.allows(&addr("198.51.100.1")));
+
+            assert_eq!(acl.rules()[0].prefix.len, 25);
+            assert_eq!(acl.hits(), vec![2, 1]);
+            assert_eq!(acl.default_action(), AclAction::Deny);
+            assert_eq!(acl.default_hits(), 1);
+        }
+
+        #[test]
+        fn deny_wins_ties() {
+            let acl = std::sync::Arc::new(SourceAcl::new(&[rule(AclAction::Allow, "192.0.2.0/24"), rule(AclAction::Deny, "192.0.2.0/24")]));
+            assert!(!acl.worker().allows(&addr("192.0.2.1")));
+        }
+
+        #[test]
+        fn empty_acl_allows_without_counting() {
+            let acl = std::sync::Arc::new(SourceAcl::new(&[]));
+            let worker = acl.worker();
+            assert!(worker.allows(&addr("192.0.2.1")));
+            assert!(worker.allows(&addr("2001:db8::1")));
+            assert!(acl.is_empty());
+            assert_eq!(acl.default_hits(), 0);
+        }
+    }
+}
+
+pub mod policy {
+    use super::datatypes::{ServerConfig, SessionKey, TenantConfig};
+    use super::cidr::IpPrefix;
+
+    const BUCKET_PRUNE_INTERVAL: f64 = 10.0;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum PolicyVerdict {
+        Accepted,
+        ReplyTooLarge,
+        AmplificationExceeded,
+        BandwidthExceeded,
+        RateLimited,
+        PayloadTooLarge,
+    }
+
+    impl PolicyVerdict {
+        pub fn count(&self, session_container: &mut metronome_lib::datatypes::SessionContainer) {
+            match self {
+                PolicyVerdict::Accepted => {},
+                PolicyVerdict::ReplyTooLarge => session_container.rejected_reply_size += 1,
+                PolicyVerdict::AmplificationExceeded => session_container.rejected_amplification += 1,
+                PolicyVerdict::BandwidthExceeded => session_container.rejected_bandwidth += 1,
+                PolicyVerdict::RateLimited => session_container.rate_limited += 1,
+                PolicyVerdict::PayloadTooLarge => session_container.rejected_payload_size += 1,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct RateLimitSpec {
+        pub rate: f64,
+        pub burst: f64,
+    }
+
+    impl RateLimitSpec {
+        pub fn bucket(&self, current_time: f64) -> TokenBucket {
+            return TokenBucket::new(self.rate, self.burst, current_time);
+        }
+    }
+
+    /// Parses `PPS[:BURST]`, burst defaults to one second worth of messages
+    impl std::str::FromStr for RateLimitSpec {
+        type Err = std::string::String;
+
+        fn from_str(s: &str) -> Result<RateLimitSpec, std::string::String> {
+            let mut parts = s.splitn(2, ':');
+            let rate: f64 = parts.next().unwrap_or("").parse().map_err(|e| format!("invalid rate in '{}': {}", s, e))?;
+            let burst: f64 = match parts.next() {
+                Some(burst_str) => burst_str.parse().map_err(|e| format!("invalid burst in '{}': {}", s, e))?,
+                None => rate.max(1.0),
+            };
+            if rate.is_nan() || rate <= 0.0 || burst.is_nan() || burst < 1.0 {
+                return Err(format!("rate limit '{}' must have a positive rate and a burst of at least 1", s));
+            }
+            return Ok(RateLimitSpec {
+                rate: rate,
+                burst: burst,
+            });
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct PrefixRateLimit {
+        pub prefix: IpPrefix,
+        pub spec: RateLimitSpec,
+    }
+
+    impl<'de> serde::Deserialize<'de> for RateLimitSpec {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<RateLimitSpec, D::Error> {
+            let spec_str = std::string::String::deserialize(deserializer)?;
+            return spec_str.parse().map_err(serde::de::Error::custom);
+        }
+    }
+
+    /// Parses `CIDR=PPS[:BURST]`
+    impl std::str::FromStr for PrefixRateLimit {
+        type Err = std::string::String;
+
+        fn from_str(s: &str) -> Result<PrefixRateLimit, std::string::String> {
+            let mut parts = s.splitn(2, '=');
+            let prefix: IpPrefix = parts.next().unwrap_or("").parse()?;
+            let spec: RateLimitSpec = parts.next().ok_or(format!("missing rate limit in '{}'", s))?.parse()?;
+            return Ok(PrefixRateLimit {
+                prefix: prefix,
+                spec: spec,
+            });
+        }
+    }
+
+    pub struct TokenBucket {
+        rate: f64,
+        burst: f64,
+        tokens: f64,
+        last_update: f64,
+    }
+
+    impl TokenBucket {
+        pub fn new(rate: f64, burst: f64, current_time: f64) -> TokenBucket {
+            return TokenBucket {
+                rate: rate,
+                burst: burst,
+                tokens: burst,
+                last_update: current_time,
+            };
+        }
+
+        /// Timestamps older than the last update add nothing, workers sharing a bucket may pass them slightly out of order
+        fn refill(&mut self, current_time: f64) {
+            if current_time > self.last_update {
+                self.tokens = (self.tokens + (current_time - self.last_update) * self.rate).min(self.burst);
+                self.last_update = current_time;
+            }
+        }
+
+        pub fn consume(&mut self, amount: f64, current_time: f64) -> bool {
+            self.refill(current_time);
+            if self.tokens >= amount {
+                self.tokens -= amount;
+                return true;
+            }
+            return false;
+        }
+
+        pub fn is_full(&mut self, current_time: f64) -> bool {
+            self.refill(current_time);
+            return self.tokens >= self.burst;
+        }
+    }
+
+    /// The global and tenant buckets, each shared by every worker so the configured rates hold for the hub as a whole
+    #[derive(Clone)]
+    pub struct SharedRateLimits {
+        global_bucket: Option<std::sync::Arc<std::sync::Mutex<TokenBucket>>>,
+        tenant_buckets: std::sync::Arc<std::collections::HashMap<std::string::String, std::sync::Mutex<TokenBucket>>>,
+    }
+
+    impl SharedRateLimits {
+        pub fn new(config: &ServerConfig) -> SharedRateLimits {
+            return SharedRateLimits {
+                global_bucket: config.rate_limit.as_ref().map(|spec| std::sync::Arc::new(std::sync::Mutex::new(spec.bucket(0.0)))),
+                tenant_buckets: std::sync::Arc::new(config.tenants.iter().filter_map(|tenant| tenant.rate_limit.as_ref().map(|spec| (tenant.name.clone(), std::sync::Mutex::new(spec.bucket(0.0))))).collect()),
+            };
+        }
+
+        fn consume(bucket: &std::sync::Mutex<TokenBucket>, current_time: f64) -> bool {
+            return bucket.lock().unwrap_or_else(|e| e.into_inner()).consume(1.0, current_time);
+        }
+
+        /// False when the tenant's bucket is empty, tenants without a limit always pass
+        pub fn consume_tenant(&self, tenant: &str, current_time: f64) -> bool {
+            match self.tenant_buckets.get(tenant) {
+                Some(bucket) => return SharedRateLimits::consume(bucket, current_time),
+                None => return true,
+            }
+        }
+
+        /// False when the global bucket is empty, always passes without a global limit
+        pub fn consume_global(&self, current_time: f64) -> bool {
+            match &self.global_bucket {
+                Some(bucket) => return SharedRateLimits::consume(bucket, current_time),
+                None => return true,
+            }
+        }
+    }
+
+    pub struct ReplyPolicy {
+        max_reply_size: usize,
+        max_amplification: f64,
+        max_source_reply_bps: Option<f64>,
+        source_buckets: std::collections::HashMap<std::net::IpAddr, TokenBucket>,
+        last_prune: f64,
+
+        shared_rate_limits: SharedRateLimits,
+        session_rate_limit: Option<RateLimitSpec>,
+        session_buckets: std::collections::HashMap<std::sync::Arc<SessionKey>, TokenBucket>,
+        prefix_rate_limits: Vec<PrefixRateLimit>,
+        prefix_source_buckets: std::collections::HashMap<std::net::IpAddr, TokenBucket>,
+    }
+
+    impl ReplyPolicy {
+        /// Every worker enforces its own policy, sources and sessions stick to a worker while the
+        /// global and tenant limits come from `shared_rate_limits`
+        pub fn new(config: &ServerConfig, shared_rate_limits: SharedRateLimits) -> ReplyPolicy {
+            // Most specific prefix wins
+            let mut prefix_rate_limits = config.prefix_rate_limits.clone();
+            prefix_rate_limits.sort_by_key(|prefix_rate_limit| std::cmp::Reverse(prefix_rate_limit.prefix.len));
+
+            return ReplyPolicy {
+                max_reply_size: config.max_reply_size,
+                max_amplification: config.max_amplification,
+                max_source_reply_bps: config.max_source_reply_bps,
+                source_buckets: std::collections::HashMap::new(),
+                last_prune: 0.0,
+
+                shared_rate_limits: shared_rate_limits,
+                session_rate_limit: config.session_rate_limit.clone(),
+                session_buckets: std::collections::HashMap::new(),
+                prefix_rate_limits: prefix_rate_limits,
+                prefix_source_buckets: std::collections::HashMap::new(),
+            };
+        }
+
+        /// Applies the message rate limits, each source address gets its own bucket sized by the most specific matching prefix
+        pub fn limit(&mut self, source: std::net::IpAddr, tenant: &TenantConfig, session_key: &std::sync::Arc<SessionKey>, current_time: f64) -> PolicyVerdict {
+            self.prune_buckets(current_time);
+
+            if let Some(session_rate_limit) = tenant.session_rate_limit.as_ref().or(self.session_rate_limit.as_ref()) {
+                let bucket = self.session_buckets.entry(session_key.clone()).or_insert_with(|| session_rate_limit.bucket(current_time));
+                if !bucket.consume(1.0, current_time) {
+                    return PolicyVerdict::RateLimited;
+                }
+            }
+
+            if let Some(prefix_rate_limit) = self.prefix_rate_limits.iter().find(|prefix_rate_limit| prefix_rate_limit.prefix.contains(&source)) {
+                let bucket = self.prefix_source_buckets.entry(source).or_insert_with(|| prefix_rate_limit.spec.bucket(current_time));
+                if !bucket.consume(1.0, current_time) {
+                    return PolicyVerdict::RateLimited;
+                }
+            }
+
+            if !self.shared_rate_limits.consume_tenant(&tenant.name, current_time) {
+                return PolicyVerdict::RateLimited;
+            }
+
+            if !self.shared_rate_limits.consume_global(current_time) {
+                return PolicyVerdict::RateLimited;
+            }
+
+            return PolicyVerdict::Accepted;
+        }
+
+        /// Rejects requests based on the payload the pong would carry, before it is built
+        pub fn precheck(&self, tenant: &TenantConfig, message: &metronome_lib::datatypes::MetronomeMessageRef, request_size: usize) -> PolicyVerdict {
+            if let Some(max_payload_size) = tenant.max_payload_size {
+                let payload_len = message.payload.map(|payload| payload.len()).unwrap_or(0);
+                if payload_len > max_payload_size || message.pong_payload_len() > max_payload_size {
+                    return PolicyVerdict::PayloadTooLarge;
+                }
+            }
+            let reply_payload_len = message.pong_payload_len();
+            if reply_payload_len > self.max_reply_size {
+                return PolicyVerdict::ReplyTooLarge;
+            }
+            if reply_payload_len as f64 > request_size as f64 * self.max_amplification {
+                return PolicyVerdict::AmplificationExceeded;
+            }
+            return PolicyVerdict::Accepted;
+        }
+
+        /// Checks the serialized pong against the limits and charges it to the source address
+        pub fn check(&mut self, source: std::net::IpAddr, request_size: usize, reply_size: usize, current_time: f64) -> PolicyVerdict {
+            if reply_size > self.max_reply_size {
+                return PolicyVerdict::ReplyTooLarge;
+            }
+            if reply_size as f64 > request_size as f64 * self.max_amplification {
+                return PolicyVerdict::AmplificationExceeded;
+            }
+            if let Some(max_source_reply_bps) = self.max_source_reply_bps {
+                self.prune_buckets(current_time);
+                let bytes_per_second = max_source_reply_bps / 8.0;
+                let bucket = self.source_buckets.entry(source).or_insert_with(|| TokenBucket::new(bytes_per_second, bytes_per_second, current_time));
+                if !bucket.consume(reply_size as f64, current_time) {
+                    return PolicyVerdict::BandwidthExceeded;
+                }
+            }
+            return PolicyVerdict::Accepted;
+        }
+
+        fn prune_buckets(&mut self, current_time: f64) {
+            if current_time - self.last_prune < BUCKET_PRUNE_INTERVAL {
+                return;
+            }
+            self.last_prune = current_time;
+            self.source_buckets.retain(|_, bucket| !bucket.is_full(current_time));
+            self.session_buckets.retain(|_, bucket| !bucket.is_full(current_time));
+            self.prefix_source_buckets.retain(|_, bucket| !bucket.is_full(current_time));
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{PolicyVerdict, ReplyPolicy, SharedRateLimits, TokenBucket};
+        use super::super::datatypes::{ServerConfig, SessionKey, TenantConfig};
+        use metronome_lib::datatypes::MetronomeMessage;
+
+        fn tenant(name: &str, rate_limit: Option<&str>) -> TenantConfig {
+            return TenantConfig {
+                name: name.to_string(),
+                key: name.to_string(),
+                allowed_prefixes: Vec::new(),
+                rate_limit: rate_limit.map(|spec| spec.parse().unwrap()),
+                session_rate_limit: None,
+                max_sessions: None,
+                max_payload_size: None,
+            };
+        }
+
+        fn accepted(policies: &mut [ReplyPolicy], tenant: &TenantConfig, messages: usize, current_time: f64) -> usize {
+            let source: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+            let session_key = std::sync::Arc::new(SessionKey::new(&tenant.name, "sid"));
+            let policy_count = policies.len();
+            return (0..messages).filter(|i| policies[i % policy_count].limit(source, tenant, &session_key, current_time) == PolicyVerdict::Accepted).count();
+        }
+
+        fn ping(payload_len: usize, mul: f32) -> MetronomeMessage {
+            return MetronomeMessage {
+                mode: "ping".to_string(),
+                payload: Some("X".repeat(payload_len)),
+                mul: mul,
+                seq: 1,
+                key: "key".to_string(),
+                sid: "sid".to_string(),
+                hub_stats_requested: false,
+                hub_stats: None,
+                payload_checksum: None,
+            };
+        }
+
+        #[test]
+        fn token_bucket_allows_a_burst_then_refills_at_its_rate() {
+            let mut bucket = TokenBucket::new(10.0, 5.0, 0.0);
+            assert!(bucket.is_full(0.0));
+            assert_eq!((0..10).filter(|_| bucket.consume(1.0, 0.0)).count(), 5);
+            assert!(!bucket.consume(1.0, 0.05));
+            assert!(bucket.consume(1.0, 0.1));
+            assert!(!bucket.consume(1.0, 0.1));
+            // A long pause refills no more than the burst
+            assert!(bucket.is_full(100.0));
+            assert_eq!((0..10).filter(|_| bucket.consume(1.0, 100.0)).count(), 5);
+            // Time going backwards adds nothing
+            assert!(!bucket.consume(1.0, 50.0));
+            assert!(!bucket.is_full(100.0));
+        }
+
+        #[test]
+        fn reply_policy_denies_oversized_and_amplifying_replies() {
+            let config = ServerConfig {
+                max_reply_size: 1000,
+                max_amplification: 2.0,
+                ..ServerConfig::default()
+            };
+            let policy = ReplyPolicy::new(&config, SharedRateLimits::new(&config));
+            let mut tenant = tenant("tenant", None);
+
+            assert_eq!(policy.precheck(&tenant, &ping(100, 1.0).borrowed(), 150), PolicyVerdict::Accepted);
+            assert_eq!(policy.precheck(&tenant, &ping(100, 20.0).borrowed(), 1500), PolicyVerdict::ReplyTooLarge);
+            assert_eq!(policy.precheck(&tenant, &ping(100, 4.0).borrowed(), 150), PolicyVerdict::AmplificationExceeded);
+
+            tenant.max_payload_size = Some(200);
+            assert_eq!(policy.precheck(&tenant, &ping(100, 1.0).borrowed(), 150), PolicyVerdict::Accepted);
+            assert_eq!(policy.precheck(&tenant, &ping(300, 1.0).borrowed(), 350), PolicyVerdict::PayloadTooLarge);
+            assert_eq!(policy.precheck(&tenant, &ping(100, 3.0).borrowed(), 150), PolicyVerdict::PayloadTooLarge);
+        }
+
+        #[test]
+        fn reply_policy_limits_source_bandwidth() {
+            let config = ServerConfig {
+                max_source_reply_bps: Some(8000.0),
+                ..ServerConfig::default()
+            };
+            let mut policy = ReplyPolicy::new(&config, SharedRateLimits::new(&config));
+            let source: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+            let other_source: std::net::IpAddr = "192.0.2.2".parse().unwrap();
+
+            assert_eq!(policy.check(source, 500, 500, 0.0), PolicyVerdict::Accepted);
+            assert_eq!(policy.check(source, 500, 500, 0.0), PolicyVerdict::Accepted);
+            assert_eq!(policy.check(source, 500, 500, 0.0), PolicyVerdict::BandwidthExceeded);
+            assert_eq!(policy.check(other_source, 500, 500, 0.0), PolicyVerdict::Accepted);
+            assert_eq!(policy.check(source, 500, 500, 0.5), PolicyVerdict::Accepted);
+        }
+
+        #[test]
+        fn session_and_prefix_limits_apply_per_session_and_source() {
+            let config = ServerConfig {
+                session_rate_limit: Some("10:2".parse().unwrap()),
+                prefix_rate_limits: vec!["192.0.2.0/24=10:3".parse().unwrap(), "192.0.2.128/25=10:1".parse().unwrap()],
+                ..ServerConfig::default()
+            };
+            let mut policy = ReplyPolicy::new(&config, SharedRateLimits::new(&config));
+            let tenant = tenant("tenant", None);
+            let limit = |policy: &mut ReplyPolicy, source: &str, sid: &str| {
+                let session_key = std::sync::Arc::new(SessionKey::new("tenant", sid));
+                return policy.limit(source.parse().unwrap(), &tenant, &session_key, 0.0);
+            };
+
+            // The session bucket runs out first
+            assert_eq!(limit(&mut policy, "192.0.2.1", "a"), PolicyVerdict::Accepted);
+            assert_eq!(limit(&mut policy, "192.0.2.1", "a"), PolicyVerdict::Accepted);
+            assert_eq!(limit(&mut policy, "192.0.2.1", "a"), PolicyVerdict::RateLimited);
+            // Then the source's prefix bucket
+            assert_eq!(limit(&mut policy, "192.0.2.1", "b"), PolicyVerdict::Accepted);
+            assert_eq!(limit(&mut policy, "192.0.2.1", "b"), PolicyVerdict::RateLimited);
+            // The most specific prefix sizes the bucket of each source
+            assert_eq!(limit(&mut policy, "192.0.2.200", "c"), PolicyVerdict::Accepted);
+            assert_eq!(limit(&mut policy, "192.0.2.200", "d"), PolicyVerdict::RateLimited);
+            // Sources outside every prefix are not limited by them
+            assert_eq!(limit(&mut policy, "198.51.100.1", "e"), PolicyVerdict::Accepted);
+            assert_eq!(limit(&mut policy, "198.51.100.1", "f"), PolicyVerdict::Accepted);
+            assert_eq!(limit(&mut policy, "198.51.100.1", "f"), PolicyVerdict::Accepted);
+        }
+
+        #[test]
+        fn workers_share_global_and_tenant_buckets() {
+            let config = ServerConfig {
+                rate_limit: Some("100:10".parse().unwrap()),
+                tenants: vec![tenant("limited", Some("100:5")), tenant("unlimited", None)],
+                ..ServerConfig::default()
+            };
+            let shared_rate_limits = SharedRateLimits::new(&config);
+            let mut policies: Vec<ReplyPolicy> = (0..4).map(|_| ReplyPolicy::new(&config, shared_rate_limits.clone())).collect();
+
+            // The tenant burst holds across all workers, not per worker
+            assert_eq!(accepted(&mut policies, &config.tenants[0], 20, 0.0), 5);
+            // The global burst has 5 tokens left for everybody else
+            assert_eq!(accepted(&mut policies, &config.tenants[1], 20, 0.0), 5);
+            // And refills at the configured rate, not a worker's share of it
+            assert_eq!(accepted(&mut policies, &config.tenants[1], 20, 0.05), 5);
+        }
+    }
+}
+
+pub mod impairment {
+    use super::datatypes::{HandledMessage, ServerConfig, SessionKey, WrappedSerializedMessage};
+    use super::policy::{RateLimitSpec, TokenBucket};
+    use metronome_lib::simulation::Rng;
+    use metronome_lib::timestamping::TimestampingMode;
+
+    const SESSION_STATE_PRUNE_INTERVAL: f64 = 10.0;
+
+    /// What the impairment stage did to a pong, counted into the session by the analyzer
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct ImpairmentVerdict {
+        pub dropped: bool,
+        pub rate_limited: bool,
+        pub duplicated: bool,
+        pub reordered: bool,
+    }
+
+    impl ImpairmentVerdict {
+        pub fn count(&self, session_container: &mut metronome_lib::datatypes::SessionContainer) {
+            if self.dropped {
+                session_container.impairment_dropped += 1;
+            }
+            if self.rate_limited {
+                session_container.impairment_rate_limited += 1;
+            }
+            if self.duplicated {
+                session_container.impairment_duplicated += 1;
+            }
+            if self.reordered {
+                session_container.impairment_reordered += 1;
+            }
+        }
+    }
+
+    /// Impairments applied to pongs. Times are in seconds, probabilities between 0 and 1.
+    /// With `loss_burst` above 1 losses follow a two state Gilbert model averaging `loss` with
+    /// bursts of `loss_burst` pongs, otherwise each pong is lost independently.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct ImpairmentSpec {
+        pub delay: f64,
+        pub jitter: f64,
+        pub loss: f64,
+        pub loss_burst: f64,
+        pub reorder: f64,
+        pub reorder_delay: f64,
+        pub duplicate: f64,
+        pub rate: Option<RateLimitSpec>,
+    }
+
+    /// Impairments for the sessions of one tenant key, one sid or, without either, every session
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct ImpairmentRule {
+        pub key: Option<std::string::String>,
+        pub sid: Option<std::string::String>,
+        pub spec: ImpairmentSpec,
+    }
+
+    impl ImpairmentRule {
+        fn matches(&self, tenant: Option<&str>, session_key: &SessionKey) -> bool {
+            if self.key.is_some() && tenant != Some(session_key.tenant.as_str()) {
+                return false;
+            }
+            return self.sid.as_ref().is_none_or(|sid| *sid == session_key.sid);
+        }
+    }
+
+    fn parse_probability(name: &str, value: &str) -> Result<f64, std::string::String> {
+        let probability: f64 = value.parse().map_err(|e| format!("invalid {} '{}': {}", name, value, e))?;
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(format!("{} '{}' must be between 0 and 1", name, value));
+        }
+        return Ok(probability);
+    }
+
+    fn parse_seconds(name: &str, value: &str) -> Result<f64, std::string::String> {
+        let seconds: f64 = value.parse().map_err(|e| format!("invalid {} '{}': {}", name, value, e))?;
+        if seconds.is_nan() || seconds < 0.0 {
+            return Err(format!("{} '{}' must not be negative", name, value));
+        }
+        return Ok(seconds);
+    }
+
+    /// Parses comma separated `NAME=VALUE` pairs, e.g. `sid=s1,delay=0.05,jitter=0.01,loss=0.02,loss_burst=4,reorder=0.01,reorder_delay=0.02,duplicate=0.01,rate=50:10`
+    impl std::str::FromStr for ImpairmentRule {
+        type Err = std::string::String;
+
+        fn from_str(s: &str) -> Result<ImpairmentRule, std::string::String> {
+            let mut rule = ImpairmentRule {
+                key: None,
+                sid: None,
+                spec: ImpairmentSpec::default(),
+            };
+            for option in s.split(',').filter(|option| !option.is_empty()) {
+                let mut parts = option.splitn(2, '=');
+                let name = parts.next().unwrap_or("");
+                let value = parts.next().ok_or(format!("missing value for '{}' in '{}'", name, s))?;
+                match name {
+                    "key" => rule.key = Some(value.to_string()),
+                    "sid" => rule.sid = Some(value.to_string()),
+                    "delay" => rule.spec.delay = parse_seconds(name, value)?,
+                    "jitter" => rule.spec.jitter = parse_seconds(name, value)?,
+                    "loss" => rule.spec.loss = parse_probability(name, value)?,
+                    "loss_burst" => {
+                        rule.spec.loss_burst = value.parse().map_err(|e| format!("invalid loss_burst '{}': {}", value, e))?;
+                        if rule.spec.loss_burst.is_nan() || rule.spec.loss_burst < 1.0 {
+                            return Err(format!("loss_burst '{}' must be at least 1", value));
+                        }
+                    },
+                    "reorder" => rule.spec.reorder = parse_probability(name, value)?,
+                    "reorder_delay" => rule.spec.reorder_delay = parse_seconds(name, value)?,
+                    "duplicate" => rule.spec.duplicate = parse_probability(name, value)?,
+                    "rate" => rule.spec.rate = Some(value.parse()?),
+                    _ => {
+                        return Err(format!("unknown impairment '{}' in '{}'", name, s));
+                    }
+                }
+            }
+            if rule.spec.loss >= 1.0 && rule.spec.loss_burst > 1.0 {
+                return Err(format!("bursty loss in '{}' needs a loss below 1", s));
+            }
+            if rule.spec.reorder > 0.0 && rule.spec.reorder_delay == 0.0 {
+                // Hold reordered pongs back long enough for the next one to overtake them
+                rule.spec.reorder_delay = rule.spec.delay.max(0.001);
+            }
+            return Ok(rule);
+        }
+    }
+
+    /// A pong waiting in the impairment stage
+    pub struct DelayedReply {
+        pub addr: std::net::SocketAddr,
+        pub serialized_message: Vec<u8>,
+    }
+
+    struct SessionImpairmentState {
+        in_loss_burst: bool,
+        last_seen: f64,
+    }
+
+    /// Applies the first matching impairment rule to each pong and holds delayed pongs until they are due.
+    /// Impaired pongs leave the hub without a transmit timestamp, so impairments rule out timestamping.
+    pub struct Impairment {
+        rules: Vec<ImpairmentRule>,
+        // Tenant names the rule keys resolve to
+        rule_tenants: Vec<Option<std::string::String>>,
+        rule_buckets: Vec<Option<TokenBucket>>,
+        sessions: std::collections::HashMap<std::sync::Arc<SessionKey>, SessionImpairmentState>,
+        last_prune: f64,
+        rng: Rng,
+        // Keyed by due time in nanoseconds and a counter so pongs due at the same time keep their order
+        delayed: std::collections::BTreeMap<(u64, u64), DelayedReply>,
+        next_id: u64,
+    }
+
+    impl Impairment {
+        pub fn new(config: &ServerConfig, seed: u64) -> Result<Impairment, std::string::String> {
+            if config.timestamping != TimestampingMode::Off {
+                return Err("impairments cannot be combined with timestamping, impaired pongs would get no residence time".to_string());
+            }
+            let mut rule_tenants: Vec<Option<std::string::String>> = Vec::new();
+            for rule in config.impairments.iter() {
+                if let Some(key) = &rule.key {
+                    match config.tenants.iter().find(|tenant| tenant.key == *key) {
+                        Some(tenant) => rule_tenants.push(Some(tenant.name.clone())),
+                        None => {
+                            return Err(format!("impairment rule refers to unknown key '{}'", key));
+                        }
+                    }
+                } else {
+                    rule_tenants.push(None);
+                }
+            }
+            return Ok(Impairment {
+                rules: config.impairments.clone(),
+                rule_tenants: rule_tenants,
+                rule_buckets: config.impairments.iter().map(|rule| rule.spec.rate.as_ref().map(|spec| spec.bucket(0.0))).collect(),
+                sessions: std::collections::HashMap::new(),
+                last_prune: 0.0,
+                rng: Rng::new(seed),
+                delayed: std::collections::BTreeMap::new(),
+                next_id: 0,
+            });
+        }
+
+        fn schedule(&mut self, due: f64, delayed_reply: DelayedReply) {
+            let due_ns = (due.max(0.0) * 1e9).round() as u64;
+            self.delayed.insert((due_ns, self.next_id), delayed_reply);
+            self.next_id += 1;
+        }
+
+        fn sample_delay(&mut self, spec: &ImpairmentSpec, reordered: &mut bool) -> f64 {
+            let mut delay = spec.delay + spec.jitter * self.rng.next_f64();
+            if self.rng.chance(spec.reorder) {
+                *reordered = true;
+                delay += spec.reorder_delay;
+            }
+            return delay;
+        }
+
+        fn is_lost(&mut self, spec: &ImpairmentSpec, session_key: &std::sync::Arc<SessionKey>, current_time: f64) -> bool {
+            if spec.loss_burst <= 1.0 {
+                return self.rng.chance(spec.loss);
+            }
+            let leave_burst = 1.0 / spec.loss_burst;
+            let enter_burst = spec.loss * leave_burst / (1.0 - spec.loss);
+            let state = self.sessions.entry(session_key.clone()).or_insert(SessionImpairmentState {
+                in_loss_burst: false,
+                last_seen: current_time,
+            });
+            state.last_seen = current_time;
+            let probability = if state.in_loss_burst { leave_burst } else { enter_burst };
+            if self.rng.chance(probability) {
+                state.in_loss_burst = !state.in_loss_burst;
+            }
+            return state.in_loss_burst;
+        }
+
+        /// Takes a pong on its way out, returning it untouched when no rule matches. Otherwise the
+        /// pong is dropped or queued until due and its message goes to `completed` straight away,
+        /// keeping the analyzer's view of the forward path in order. `current_time` is a monotonic timestamp.
+        pub fn submit(&mut self, wrapped_message: WrappedSerializedMessage, current_time: f64, completed: &mut Vec<HandledMessage>) -> Option<WrappedSerializedMessage> {
+            self.prune_sessions(current_time);
+            let session_key = wrapped_message.handled_message.session_key.clone();
+            let rule_index = match (0..self.rules.len()).find(|index| self.rules[*index].matches(self.rule_tenants[*index].as_deref(), &session_key)) {
+                Some(rule_index) => rule_index,
+                None => {
+                    return Some(wrapped_message);
+                }
+            };
+            let spec = self.rules[rule_index].spec.clone();
+            let mut handled_message = wrapped_message.handled_message;
+
+            if let Some(bucket) = &mut self.rule_buckets[rule_index] {
+                if !bucket.consume(1.0, current_time) {
+                    handled_message.impairment.rate_limited = true;
+                    completed.push(handled_message);
+                    return None;
+                }
+            }
+            if self.is_lost(&spec, &session_key, current_time) {
+                handled_message.impairment.dropped = true;
+                completed.push(handled_message);
+                return None;
+            }
+
+            if self.rng.chance(spec.duplicate) {
+                handled_message.impairment.duplicated = true;
+                let duplicate_due = current_time + self.sample_delay(&spec, &mut handled_message.impairment.reordered);
+                self.schedule(duplicate_due, DelayedReply {
+                    addr: wrapped_message.addr,
+                    serialized_message: wrapped_message.serialized_message.clone(),
+                });
+            }
+            let due = current_time + self.sample_delay(&spec, &mut handled_message.impairment.reordered);
+            self.schedule(due, DelayedReply {
+                addr: wrapped_message.addr,
+                serialized_message: wrapped_message.serialized_message,
+            });
+            completed.push(handled_message);
+            return None;
+        }
+
+        /// Seconds until the next queued pong is due
+        pub fn next_due_in(&self, current_time: f64) -> Option<f64> {
+            return self.delayed.keys().next().map(|(due_ns, _)| (*due_ns as f64 / 1e9 - current_time).max(0.0));
+        }
+
+        /// Next pong due at or before `current_time`
+        pub fn due(&mut self, current_time: f64) -> Option<DelayedReply> {
+            if self.next_due_in(current_time).is_some_and(|due_in| due_in <= 0.0) {
+                return self.delayed.pop_first().map(|(_, delayed_reply)| delayed_reply);
+            }
+            return None;
+        }
+
+        fn prune_sessions(&mut self, current_time: f64) {
+            if current_time - self.last_prune < SESSION_STATE_PRUNE_INTERVAL {
+                return;
+            }
+            self.last_prune = current_time;
+            self.sessions.retain(|_, state| current_time - state.last_seen < SESSION_STATE_PRUNE_INTERVAL);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{Impairment, ImpairmentVerdict};
+        use super::super::datatypes::{HandledMessage, ServerConfig, SessionKey, WrappedSerializedMessage};
+        use super::super::policy::PolicyVerdict;
+        use metronome_lib::timestamping::TimestampingMode;
+
+        fn pong(session_key: &std::sync::Arc<SessionKey>, seq: u64) -> WrappedSerializedMessage {
+            return WrappedSerializedMessage {
+                addr: "192.0.2.1:40000".parse().unwrap(),
+                serialized_message: seq.to_be_bytes().to_vec(),
+                handled_message: HandledMessage {
+                    verdict: PolicyVerdict::Accepted,
+                    session_key: session_key.clone(),
+                    seq: seq,
+                    message_raw_size: 64,
+                    reply_size: 64,
+                    ip_overhead: 28,
+                    corrupted: false,
+                    rx_timestamps: metronome_lib::timestamping::Timestamps::at(0.0, 0.0),
+                    residence_time: None,
+                    residence_timestamp_source: None,
+                    impairment: ImpairmentVerdict::default(),
+                },
+            };
+        }
+
+        /// Sends `count` pongs a millisecond apart, returning the seq of every dropped one
+        fn dropped(impairment: &str, seed: u64, count: u64) -> Vec<u64> {
+            let config = ServerConfig {
+                impairments: vec![impairment.parse().unwrap()],
+                ..ServerConfig::default()
+            };
+            let mut impairment = Impairment::new(&config, seed).unwrap();
+            let session_key = std::sync::Arc::new(SessionKey::new("tenant", "sid"));
+            let mut completed: Vec<HandledMessage> = Vec::new();
+            for seq in 0..count {
+                assert!(impairment.submit(pong(&session_key, seq), seq as f64 * 0.001, &mut completed).is_none());
+            }
+            assert_eq!(completed.len() as u64, count);
+            return completed.iter().filter(|handled_message| handled_message.impairment.dropped).map(|handled_message| handled_message.seq).collect();
+        }
+
+        #[test]
+        fn seeded_loss_drops_the_configured_share() {
+            let dropped_seqs = dropped("loss=0.1", 7, 10000);
+            assert!((900..=1100).contains(&dropped_seqs.len()), "dropped {}", dropped_seqs.len());
+            // The same seed drops the same pongs, another seed others
+            assert_eq!(dropped("loss=0.1", 7, 10000), dropped_seqs);
+            assert_ne!(dropped("loss=0.1", 8, 10000), dropped_seqs);
+        }
+
+        #[test]
+        fn bursty_loss_keeps_the_average_and_groups_losses() {
+            let dropped_seqs = dropped("loss=0.1,loss_burst=5", 7, 20000);
+            assert!((1600..=2400).contains(&dropped_seqs.len()), "dropped {}", dropped_seqs.len());
+            let bursts = 1 + dropped_seqs.windows(2).filter(|pair| pair[1] != pair[0] + 1).count();
+            let mean_burst = dropped_seqs.len() as f64 / bursts as f64;
+            assert!((4.0..=6.0).contains(&mean_burst), "mean burst {}", mean_burst);
+        }
+
+        #[test]
+        fn delayed_pongs_come_out_when_due() {
+            let config = ServerConfig {
+                impairments: vec!["delay=0.05".parse().unwrap()],
+                ..ServerConfig::default()
+            };
+            let mut impairment = Impairment::new(&config, 0).unwrap();
+            let session_key = std::sync::Arc::new(SessionKey::new("tenant", "sid"));
+            let mut completed: Vec<HandledMessage> = Vec::new();
+            assert!(impairment.submit(pong(&session_key, 1), 1.0, &mut completed).is_none());
+            assert!(impairment.submit(pong(&session_key, 2), 1.01, &mut completed).is_none());
+            assert!(impairment.due(1.04).is_none());
+            assert_eq!(impairment.due(1.05).unwrap().serialized_message, 1u64.to_be_bytes().to_vec());
+            assert!(impairment.due(1.05).is_none());
+            assert_eq!(impairment.due(1.1).unwrap().serialized_message, 2u64.to_be_bytes().to_vec());
+            assert_eq!(impairment.next_due_in(1.1), None);
+        }
+
+        #[test]
+        fn timestamping_is_refused() {
+            let config = ServerConfig {
+                impairments: vec!["delay=0.05".parse().unwrap()],
+                timestamping: TimestampingMode::Software,
+                ..ServerConfig::default()
+            };
+            assert!(Impairment::new(&config, 0).is_err());
+        }
+    }
+}
+
+pub mod handler {
+    use super::datatypes::{HandledMessage, ServerConfig, SessionShards, SessionKey, TenantConfig};
+    use metronome_lib::timestamping::{PendingTxTimestamps, Timestamps, TimestampingMode};
+
+    /// Seconds to wait for a transmit timestamp before settling for the userspace send time
+    const TX_TIMESTAMP_WAIT: f64 = 0.05;
+    use super::policy::{PolicyVerdict, ReplyPolicy, SharedRateLimits};
+    use super::impairment::ImpairmentVerdict;
+
+    const MAX_INTERNED_SESSION_KEYS: usize = 65536;
+
+    /// Decides how to answer a ping and serializes the pong, shared by the threaded and the batched hub paths
+    pub struct PingHandler {
+        reply_policy: ReplyPolicy,
+        tenants_by_key: std::collections::HashMap<std::string::String, std::sync::Arc<TenantConfig>>,
+        session_shards: SessionShards,
+        session_keys: std::collections::HashMap<std::string::String, std::collections::HashMap<std::string::String, std::sync::Arc<SessionKey>>>,
+        interned_session_keys: usize,
+        payload_scratch: std::string::String,
+        last_session_limit_printed: f64,
+    }
+
+    impl PingHandler {
+        pub fn new(config: &ServerConfig, session_shards: SessionShards, shared_rate_limits: SharedRateLimits) -> PingHandler {
+            return PingHandler {
+                reply_policy: ReplyPolicy::new(config, shared_rate_limits),
+                tenants_by_key: config.tenants_by_key().into_iter().map(|(key, tenant)| (key, std::sync::Arc::new(tenant))).collect(),
+                session_shards: session_shards,
+                session_keys: std::collections::HashMap::new(),
+                interned_session_keys: 0,
+                payload_scratch: std::string::String::new(),
+                last_session_limit_printed: f64::NEG_INFINITY,
+            };
+        }
+
+        /// Returns None for messages that are ignored outright. The pong is written into `reply` only
+        /// when the returned verdict is Accepted.
+        pub fn handle(&mut self, message: &metronome_lib::datatypes::MetronomeMessageRef, addr: std::net::SocketAddr, message_raw_size: usize, rx_timestamps: Timestamps, reply: &mut Vec<u8>) -> Option<HandledMessage> {
+            if message.mode != "ping" {
+                return None;
+            }
+            let timestamp = rx_timestamps.monotonic;
+
+            let tenant = match self.tenants_by_key.get(message.key) {
+                Some(tenant) => tenant.clone(),
+                None => return None,
+            };
+            if !tenant.allows_source(&addr.ip()) {
+                return None;
+            }
+            let session_key = self.intern_session_key(&tenant.name, message.sid);
+
+            if let Some(max_sessions) = tenant.max_sessions {
+                // Only a tenant at its cap needs the session table consulted
+                if self.session_shards.tenant_session_count(&tenant.name) >= max_sessions && !self.session_shards.contains(&session_key) {
+                    if (timestamp - self.last_session_limit_printed) > 10.0 {
+                        eprintln!("tenant {} has reached its limit of {} sessions, ignoring session {}", tenant.name, max_sessions, session_key.sid);
+                        self.last_session_limit_printed = timestamp;
+                    }
+                    return None;
+                }
+            }
+
+            let mut verdict = self.reply_policy.limit(addr.ip(), &tenant, &session_key, timestamp);
+            if verdict == PolicyVerdict::Accepted {
+                verdict = self.reply_policy.precheck(&tenant, message, message_raw_size);
+            }
+
+            if verdict == PolicyVerdict::Accepted {
+                let mut response = message.get_pong(&mut self.payload_scratch);
+
+                if response.hub_stats_requested {
+                    response.hub_stats = session_key.counters.load(timestamp);
+                }
+
+                if let Err(e) = response.write_to(reply) {
+                    eprintln!("failed to serialize MetronomeMessage for transmission: {}", e);
+                    return None;
+                }
+                verdict = self.reply_policy.check(addr.ip(), message_raw_size, reply.len(), timestamp);
+            }
+
+            return Some(HandledMessage {
+                verdict: verdict,
+                session_key: session_key,
+                seq: message.seq,
+                message_raw_size: message_raw_size,
+                reply_size: if verdict == PolicyVerdict::Accepted { reply.len() } else { 0 },
+                ip_overhead: metronome_lib::datatypes::ip_overhead(&addr),
+                corrupted: message.payload_intact() == Some(false),
+                rx_timestamps: rx_timestamps,
+                residence_time: None,
+                residence_timestamp_source: None,
+                impairment: ImpairmentVerdict::default(),
+            });
+        }
+
+        /// Session keys are shared with the session table so known sessions cost no allocations
+        fn intern_session_key(&mut self, tenant: &str, sid: &str) -> std::sync::Arc<SessionKey> {
+            if let Some(session_key) = self.session_keys.get(tenant).and_then(|tenant_keys| tenant_keys.get(sid)) {
+                return session_key.clone();
+            }
+
+            if self.interned_session_keys >= MAX_INTERNED_SESSION_KEYS {
+                // Drop keys no longer referenced by the session table
+                for tenant_keys in self.session_keys.values_mut() {
+                    tenant_keys.retain(|_, session_key| std::sync::Arc::strong_count(session_key) > 1);
+                }
+                self.interned_session_keys = self.session_keys.values().map(|tenant_keys| tenant_keys.len()).sum();
+            }
+
+            let session_key = std::sync::Arc::new(SessionKey::new(tenant, sid));
+            self.session_keys.entry(tenant.to_string()).or_default().insert(sid.to_string(), session_key.clone());
+            self.interned_session_keys += 1;
+            return session_key;
+        }
+    }
+
+    /// Completes answered messages with their residence time once the reply has left the hub,
+    /// waiting for the transmit timestamp when kernel timestamps are enabled
+    pub struct ReplyTimestamps {
+        pending: Option<PendingTxTimestamps<HandledMessage>>,
+        released: Vec<(HandledMessage, Timestamps)>,
+    }
+
+    impl ReplyTimestamps {
+        pub fn new(mode: TimestampingMode) -> ReplyTimestamps {
+            return ReplyTimestamps {
+                pending: if mode.transmit() { Some(PendingTxTimestamps::new(TX_TIMESTAMP_WAIT)) } else { None },
+                released: Vec::new(),
+            };
+        }
+
+        pub fn sent(&mut self, mut handled_message: HandledMessage, sent_at: Timestamps, completed: &mut Vec<HandledMessage>) {
+            if let Some(pending) = &mut self.pending {
+                pending.sent(sent_at, handled_message);
+            } else {
+                handled_message.replied(&sent_at);
+                completed.push(handled_message);
+            }
+        }
+
+        pub fn is_waiting(&self) -> bool {
+            return self.pending.as_ref().is_some_and(|pending| !pending.is_empty());
+        }
+
+        /// `current_time` is a monotonic timestamp
+        pub fn poll(&mut self, socket: &std::net::UdpSocket, current_time: f64, completed: &mut Vec<HandledMessage>) {
+            if let Some(pending) = &mut self.pending {
+                pending.poll(socket, current_time, &mut self.released);
+                for (mut handled_message, tx_timestamps) in self.released.drain(..) {
+                    handled_message.replied(&tx_timestamps);
+                    completed.push(handled_message);
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::PingHandler;
+        use super::super::datatypes::{HandledMessage, ServerConfig, SessionKey, SessionShards, TenantConfig};
+        use super::super::policy::{PolicyVerdict, SharedRateLimits};
+        use metronome_lib::datatypes::{MetronomeMessage, ReceiveTimeWindows};
+        use metronome_lib::timestamping::Timestamps;
+
+        fn tenant(name: &str, max_sessions: Option<usize>) -> TenantConfig {
+            return TenantConfig {
+                name: name.to_string(),
+                key: name.to_string(),
+                allowed_prefixes: Vec::new(),
+                rate_limit: None,
+                session_rate_limit: None,
+                max_sessions: max_sessions,
+                max_payload_size: None,
+            };
+        }
+
+        fn ping(handler: &mut PingHandler, key: &str, sid: &str, seq: u64) -> Option<HandledMessage> {
+            let message = MetronomeMessage {
+                mode: "ping".to_string(),
+                payload: None,
+                mul: 1.0,
+                seq: seq,
+                key: key.to_string(),
+                sid: sid.to_string(),
+                hub_stats_requested: false,
+                hub_stats: None,
+                payload_checksum: None,
+            };
+            let mut reply: Vec<u8> = Vec::new();
+            return handler.handle(&message.borrowed(), "192.0.2.1:40000".parse().unwrap(), 32, Timestamps::at(seq as f64, seq as f64), &mut reply);
+        }
+
+        #[test]
+        fn tenant_session_cap_admits_known_sessions_only() {
+            let config = ServerConfig {
+                tenants: vec![tenant("capped", Some(2)), tenant("open", None)],
+                ..ServerConfig::default()
+            };
+            let session_shards = SessionShards::new(1, &config.tenants);
+            let mut handler = PingHandler::new(&config, session_shards.clone(), SharedRateLimits::new(&config));
+            let receive_time_windows = ReceiveTimeWindows::default();
+
+            for sid in ["a", "b"].iter() {
+                let handled_message = ping(&mut handler, "capped", sid, 1).unwrap();
+                assert_eq!(handled_message.verdict, PolicyVerdict::Accepted);
+                session_shards.analyze(0, vec![handled_message], &receive_time_windows);
+            }
+            assert_eq!(session_shards.tenant_session_count("capped"), 2);
+
+            // At the cap new sessions are ignored, known ones and other tenants carry on
+            assert!(ping(&mut handler, "capped", "c", 2).is_none());
+            assert!(ping(&mut handler, "capped", "a", 2).is_some());
+            assert!(ping(&mut handler, "open", "c", 2).is_some());
+
+            // A session expiring makes room again
+            if let Ok(mut session_data) = session_shards.shard(&SessionKey::new("capped", "a")).lock() {
+                session_shards.remove(&mut session_data, &SessionKey::new("capped", "a"));
+            }
+            assert_eq!(session_shards.tenant_session_count("capped"), 1);
+            assert!(ping(&mut handler, "capped", "c", 3).is_some());
+        }
     }
+}
+
+/// A client session against the hub's own ping handling over simulated links, on a simulated clock
+pub mod simulation {
+    use super::acl::{SourceAcl, WorkerAcl};
+    use super::datatypes::{HandledMessage, ServerConfig, SessionKey, SessionShards, TenantConfig, WrappedSerializedMessage};
+    use super::handler::PingHandler;
+    use super::impairment::Impairment;
+    use super::policy::{PolicyVerdict, SharedRateLimits};
+    use metronome_lib::clock::{Clock, SimulatedClock};
+    use metronome_lib::datatypes::{HubSessionCounters, MessageWithSize, MetronomeMessage, MetronomeMessageRef, SessionContainer, TimestampedMessage};
+    use metronome_lib::simulation::{LinkConfig, LinkCounters, SimulatedLink};
+    use metronome_lib::timestamping::Timestamps;
+
+    const SCAN_INTERVAL: f64 = 0.1;
+
+    /// The hub without sockets or threads: pings pass the source ACL and the PingHandler, pongs the
+    /// impairment stage, and handled messages are counted into the session table as soon as they are answered
+    pub struct SimulatedHub {
+        pub config: ServerConfig,
+        pub session_shards: SessionShards,
+        source_acl: WorkerAcl,
+        ping_handler: PingHandler,
+        impairment: Option<Impairment>,
+        hole_timeout: f64,
+        completed: Vec<HandledMessage>,
+    }
+
+    impl SimulatedHub {
+        pub fn new(config: &ServerConfig, hole_timeout: f64) -> Result<SimulatedHub, std::string::String> {
+            let session_shards = SessionShards::new(1, &config.tenants);
+            let mut impairment: Option<Impairment> = None;
+            if !config.impairments.is_empty() {
+                impairment = Some(Impairment::new(config, config.impairment_seed)?);
+            }
+            return Ok(SimulatedHub {
+                config: config.clone(),
+                source_acl: std::sync::Arc::new(SourceAcl::new(&config.acl_rules)).worker(),
+                ping_handler: PingHandler::new(config, session_shards.clone(), SharedRateLimits::new(config)),
+                session_shards: session_shards,
+                impairment: impairment,
+                hole_timeout: hole_timeout,
+                completed: Vec::new(),
+            });
+        }
+
+        /// Handles a datagram from `addr`, returning the pong to send straight away. Pongs the
+        /// impairment stage holds back come out of `due` instead.
+        pub fn handle(&mut self, datagram: &[u8], addr: std::net::SocketAddr, rx_timestamps: Timestamps) -> Option<Vec<u8>> {
+            if !self.source_acl.allows(&addr.ip()) {
+                return None;
+            }
+            let message = MetronomeMessageRef::parse_from_slice(datagram)?;
+            let mut reply: Vec<u8> = Vec::new();
+            let handled_message = self.ping_handler.handle(&message, addr, datagram.len(), rx_timestamps, &mut reply)?;
+
+            let mut sent: Option<Vec<u8>> = None;
+            if handled_message.verdict == PolicyVerdict::Accepted {
+                let wrapped_message = WrappedSerializedMessage {
+                    addr: addr,
+                    serialized_message: reply,
+                    handled_message: handled_message,
+                };
+                let unimpaired_message = match &mut self.impairment {
+                    Some(impairment) => impairment.submit(wrapped_message, rx_timestamps.monotonic, &mut self.completed),
+                    None => Some(wrapped_message),
+                };
+                if let Some(mut wrapped_message) = unimpaired_message {
+                    // No time passes in the hub
+                    wrapped_message.handled_message.replied(&rx_timestamps);
+                    self.completed.push(wrapped_message.handled_message);
+                    sent = Some(wrapped_message.serialized_message);
+                }
+            } else {
+                self.completed.push(handled_message);
+            }
+            self.session_shards.analyze(0, std::mem::take(&mut self.completed), &self.config.receive_time_windows);
+            return sent;
+        }
+
+        /// Monotonic time the next held back pong is due
+        pub fn next_due(&self, current_time: f64) -> Option<f64> {
+            return self.impairment.as_ref().and_then(|impairment| impairment.next_due_in(current_time)).map(|due_in| current_time + due_in);
+        }
+
+        /// Next held back pong due at or before `current_time`
+        pub fn due(&mut self, current_time: f64) -> Option<(std::net::SocketAddr, Vec<u8>)> {
+            let delayed_reply = self.impairment.as_mut()?.due(current_time)?;
+            return Some((delayed_reply.addr, delayed_reply.serialized_message));
+        }
 
-    #[derive(Serialize)]
-    pub struct ServerSessionStatistics {
-        pub clocktower_type: std::string::String,
+        pub fn prune_holes(&self, current_time: f64) {
+            for shard in self.session_shards.shards() {
+                if let Ok(mut session_data) = shard.lock() {
+                    for session_container in session_data.values_mut() {
+                        session_container.prune_holes(current_time - self.hole_timeout);
+                    }
+                }
+            }
+        }
+
+        /// Runs `f` on the state the hub keeps for a session
+        pub fn inspect_session<R, F: FnOnce(&SessionContainer) -> R>(&self, tenant: &str, sid: &str, f: F) -> Option<R> {
+            let session_key = SessionKey::new(tenant, sid);
+            let session_data = self.session_shards.shard(&session_key).lock().ok()?;
+            return session_data.get(&session_key).map(f);
+        }
 
+        pub fn counters(&self, tenant: &str, sid: &str) -> Option<HubSessionCounters> {
+            return self.inspect_session(tenant, sid, |session_container| session_container.get_counters());
+        }
+    }
+
+    /// One client session pinging the hub at `pps` for `duration` seconds over impaired links,
+    /// then for `settle` seconds over clean links so trailing holes time out and the hub's final
+    /// counters reach the client.
+    #[derive(Clone)]
+    pub struct SimulationConfig {
+        pub hub: ServerConfig,
+        pub client_addr: std::net::SocketAddr,
+        pub key: std::string::String,
         pub sid: std::string::String,
-        pub timestamp: f64,
-        pub received_messages: u64,
-        pub holes_created: u64,
-        pub holes_closed: u64,
-        pub holes_timed_out: u64,
-        pub holes_current: u64,
+        pub pps: u64,
+        pub duration: f64,
+        pub settle: f64,
+        pub payload_size: usize,
+        pub payload_pattern: metronome_lib::datatypes::PayloadPattern,
+        pub payload_checksum: bool,
+        pub forward: LinkConfig,
+        pub reverse: LinkConfig,
+        pub seed: u64,
+        pub request_hub_stats: bool,
+        pub wall_start: f64,
+        /// Steps the wall clock by the second value once the monotonic clock reaches the first
+        pub wall_step: Option<(f64, f64)>,
+        pub timeout: f64,
+        pub hole_timeout: f64,
+        pub receive_time_windows: metronome_lib::datatypes::ReceiveTimeWindows,
+    }
+
+    impl Default for SimulationConfig {
+        fn default() -> SimulationConfig {
+            return SimulationConfig {
+                hub: ServerConfig {
+                    tenants: vec![TenantConfig {
+                        name: "default".to_string(),
+                        key: "simulation".to_string(),
+                        allowed_prefixes: Vec::new(),
+                        rate_limit: None,
+                        session_rate_limit: None,
+                        max_sessions: None,
+                        max_payload_size: None,
+                    }],
+                    ..ServerConfig::default()
+                },
+                client_addr: std::net::SocketAddr::from(([192, 0, 2, 1], 40000)),
+                key: "simulation".to_string(),
+                sid: "simulation".to_string(),
+                pps: 100,
+                duration: 10.0,
+                settle: 2.0,
+                payload_size: 16,
+                payload_pattern: metronome_lib::datatypes::PayloadPattern::default(),
+                payload_checksum: true,
+                forward: LinkConfig::default(),
+                reverse: LinkConfig::default(),
+                seed: 1,
+                request_hub_stats: true,
+                wall_start: 1_600_000_000.0,
+                wall_step: None,
+                timeout: 5.0,
+                hole_timeout: 1.0,
+                receive_time_windows: metronome_lib::datatypes::ReceiveTimeWindows::default(),
+            };
+        }
+    }
+
+    pub struct SimulationResult {
+        pub client: metronome_lib::client::ClientSessionStatistics,
+        pub hub: SimulatedHub,
+        /// Tenant the hub put the session under
+        pub tenant: std::string::String,
+        pub forward: LinkCounters,
+        pub reverse: LinkCounters,
+    }
+
+    impl SimulationResult {
+        pub fn hub_counters(&self) -> Option<HubSessionCounters> {
+            return self.hub.counters(&self.tenant, &self.client.sid);
+        }
+
+        pub fn inspect_hub_session<R, F: FnOnce(&SessionContainer) -> R>(&self, f: F) -> Option<R> {
+            return self.hub.inspect_session(&self.tenant, &self.client.sid, f);
+        }
+    }
+
+    pub fn run(config: &SimulationConfig) -> SimulationResult {
+        let clock = SimulatedClock::new(config.wall_start);
+        let mut forward = SimulatedLink::new(config.forward, config.seed);
+        let mut reverse = SimulatedLink::new(config.reverse, config.seed.wrapping_add(1));
+        let mut hub = SimulatedHub::new(&config.hub, config.hole_timeout).unwrap_or_else(|e| panic!("{}", e));
+        let sent_pings = std::sync::Arc::new(metronome_lib::client::SentPings::new((config.pps as f64 * config.timeout * 2.0) as usize));
+        let mut session = metronome_lib::client::ClientSession::new(sent_pings.clone(), clock.wall());
+        session.tracker.receive_time_windows = config.receive_time_windows.clone();
+
+        let mut ping = MetronomeMessage {
+            mode: "ping".to_string(),
+            payload: None,
+            mul: 1.0,
+            seq: 0,
+            key: config.key.clone(),
+            sid: config.sid.clone(),
+            hub_stats_requested: config.request_hub_stats,
+            hub_stats: None,
+            payload_checksum: None,
+        };
+        ping.set_payload(config.payload_pattern.generate(config.payload_size, 0, config.seed), config.payload_checksum);
+        let interval = 1.0 / config.pps as f64;
+        let send_until = config.duration + config.settle;
+        let mut next_tx_at: f64 = 0.0;
+        let mut next_scan: f64 = SCAN_INTERVAL;
+        let mut wall_step = config.wall_step;
+        let mut settled = false;
+
+        loop {
+            let tx_at = if ping.seq as f64 * interval < send_until { Some(next_tx_at) } else { None };
+            let hub_due_at = hub.next_due(clock.monotonic());
+            let next_event = [forward.next_delivery(), reverse.next_delivery(), hub_due_at, tx_at].iter().flatten().fold(f64::INFINITY, |a, b| a.min(*b));
+            if next_event == f64::INFINITY {
+                break;
+            }
+
+            while next_scan <= next_event {
+                clock.advance_to(next_scan);
+                session.expire(next_scan - config.timeout);
+                hub.prune_holes(next_scan);
+                next_scan += SCAN_INTERVAL;
+            }
+            clock.advance_to(next_event);
+            if let Some((step_at, step)) = wall_step {
+                if next_event >= step_at {
+                    clock.step_wall(step);
+                    wall_step = None;
+                }
+            }
+            if !settled && next_event >= config.duration {
+                forward.config = LinkConfig::clean(config.forward.delay);
+                reverse.config = LinkConfig::clean(config.reverse.delay);
+                settled = true;
+            }
+
+            while let Some(datagram) = forward.deliver(next_event) {
+                if let Some(reply) = hub.handle(&datagram, config.client_addr, clock.now()) {
+                    reverse.send(next_event, reply);
+                }
+            }
+            while let Some((_, reply)) = hub.due(next_event) {
+                reverse.send(next_event, reply);
+            }
+
+            while let Some(datagram) = reverse.deliver(next_event) {
+                if let Some(message) = MetronomeMessage::parse_from_slice(&datagram) {
+                    if message.key != config.key || message.sid != config.sid {
+                        continue;
+                    }
+                    session.received(&TimestampedMessage {
+                        timestamps: clock.now(),
+                        message_with_size: MessageWithSize {
+                            message_raw_size: datagram.len(),
+                            message: message,
+                        },
+                    });
+                }
+            }
 
-        pub received_bytes: u64,
+            if tx_at == Some(next_event) {
+                if config.payload_pattern.per_message() {
+                    ping.set_payload(config.payload_pattern.generate(config.payload_size, ping.seq, config.seed), config.payload_checksum);
+                }
+                match ping.as_vec() {
+                    Ok(serialized) => {
+                        let rtt_measurement = metronome_lib::client::RTTMeasurement {
+                            seq: ping.seq,
+                            timestamps: clock.now(),
+                            scheduled: next_event,
+                            catch_up: false,
+                            skipped: 0,
+                            message_raw_size: serialized.len(),
+                        };
+                        sent_pings.sending(&rtt_measurement);
+                        forward.send(next_event, serialized);
+                        sent_pings.sent(&rtt_measurement);
+                    },
+                    Err(e) => {
+                        eprintln!("failed to serialize MetronomeMessage for transmission: {}", e);
+                    }
+                }
+                ping.seq += 1;
+                next_tx_at = ping.seq as f64 * interval;
+            }
+        }
+
+        // Everything still unanswered once the links are empty has been lost
+        clock.advance(config.timeout);
+        session.expire(clock.monotonic());
+        hub.prune_holes(clock.monotonic());
+
+        let tenant = config.hub.tenants.iter().find(|tenant| tenant.key == config.key).map(|tenant| tenant.name.clone()).unwrap_or_default();
+        return SimulationResult {
+            client: session.statistics(clock.wall(), &config.sid, clock.monotonic()),
+            hub: hub,
+            tenant: tenant,
+            forward: forward.counters,
+            reverse: reverse.counters,
+        };
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub mod batched {
+    use std::os::unix::io::AsRawFd;
+    use metronome_lib::sockaddr::{sockaddr_to_socket_addr, socket_addr_to_sockaddr};
+    use metronome_lib::timestamping::{ControlBuffer, Timestamps, parse_control_messages};
+
+    pub const MAX_DATAGRAM_SIZE: usize = 65536;
+
+    /// Binds a UDP socket with SO_REUSEPORT so several workers can share the address and let the kernel spread flows between them
+    pub fn bind_reuseport(addr: &std::net::SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+        use std::os::unix::io::FromRawFd;
+
+        let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Owned from here on so the descriptor is closed on error
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+        let enable: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, &enable as *const libc::c_int as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let addr_len = socket_addr_to_sockaddr(addr, &mut storage);
+        let result = unsafe {
+            libc::bind(fd, &storage as *const libc::sockaddr_storage as *const libc::sockaddr, addr_len)
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        return Ok(socket);
+    }
 
-        #[serde(skip_serializing_if="Option::is_none")]
-        pub intermessage_gap_mavg: Option<f64>,
+    /// Preallocated buffers for receiving up to `batch_size` datagrams with one recvmmsg() call
+    pub struct RecvBatch {
+        buffers: Vec<Vec<u8>>,
+        addrs: Vec<libc::sockaddr_storage>,
+        iovecs: Vec<libc::iovec>,
+        controls: Vec<ControlBuffer>,
+        headers: Vec<libc::mmsghdr>,
+        received: usize,
+        received_at: Timestamps,
+    }
+
+    impl RecvBatch {
+        pub fn new(batch_size: usize) -> RecvBatch {
+            return RecvBatch {
+                buffers: (0..batch_size).map(|_| vec![0; MAX_DATAGRAM_SIZE]).collect(),
+                addrs: (0..batch_size).map(|_| unsafe { std::mem::zeroed() }).collect(),
+                iovecs: (0..batch_size).map(|_| unsafe { std::mem::zeroed() }).collect(),
+                controls: (0..batch_size).map(|_| ControlBuffer::new()).collect(),
+                headers: (0..batch_size).map(|_| unsafe { std::mem::zeroed() }).collect(),
+                received: 0,
+                received_at: Timestamps::at(0.0, 0.0),
+            };
+        }
+
+        /// Blocks until at least one datagram is available or the socket read timeout expires
+        pub fn recv(&mut self, socket: &std::net::UdpSocket) -> std::io::Result<usize> {
+            for index in 0..self.buffers.len() {
+                self.iovecs[index].iov_base = self.buffers[index].as_mut_ptr() as *mut libc::c_void;
+                self.iovecs[index].iov_len = self.buffers[index].len();
+                let header = &mut self.headers[index];
+                header.msg_hdr.msg_name = &mut self.addrs[index] as *mut libc::sockaddr_storage as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                header.msg_hdr.msg_iov = &mut self.iovecs[index];
+                header.msg_hdr.msg_iovlen = 1;
+                self.controls[index].attach(&mut header.msg_hdr);
+                header.msg_hdr.msg_flags = 0;
+                header.msg_len = 0;
+            }
+
+            let result = unsafe {
+                libc::recvmmsg(socket.as_raw_fd(), self.headers.as_mut_ptr(), self.headers.len() as libc::c_uint, libc::MSG_WAITFORONE, std::ptr::null_mut())
+            };
+            if result < 0 {
+                self.received = 0;
+                return Err(std::io::Error::last_os_error());
+            }
+            self.received = result as usize;
+            self.received_at = Timestamps::now();
+            return Ok(self.received);
+        }
 
-        pub receive_time_windows: Vec<u64>,
+        pub fn received_count(&self) -> usize {
+            return self.received;
+        }
+
+        /// Source, contents and receive timestamps of a datagram, kernel timestamps are only present when enabled on the socket
+        pub fn get(&self, index: usize) -> Option<(std::net::SocketAddr, &[u8], Timestamps)> {
+            if index >= self.received {
+                return None;
+            }
+            let addr = sockaddr_to_socket_addr(&self.addrs[index])?;
+            let mut timestamps = self.received_at;
+            parse_control_messages(&self.headers[index].msg_hdr, &mut timestamps);
+            return Some((addr, &self.buffers[index][..self.headers[index].msg_len as usize], timestamps));
+        }
     }
 
-    impl ServerSessionStatistics {
-        pub fn from_session_container(sid: &std::string::String, session_container: &metronome_lib::datatypes::SessionContainer) -> ServerSessionStatistics {
-            return ServerSessionStatistics {
-                clocktower_type: "hub_session_statistics".to_string(),
-                sid: sid.clone(),
-                timestamp: session_container.last_rx,
-                received_messages: session_container.received_messages,
-                holes_created: session_container.holes_created,
-                holes_closed: session_container.holes_closed,
-                holes_timed_out: session_container.holes_timed_out,
-                holes_current: session_container.holes.len() as u64,
+    /// Preallocated buffers for sending up to `batch_size` datagrams with sendmmsg()
+    pub struct SendBatch {
+        buffers: Vec<Vec<u8>>,
+        addrs: Vec<libc::sockaddr_storage>,
+        iovecs: Vec<libc::iovec>,
+        headers: Vec<libc::mmsghdr>,
+        queued: usize,
+        last_send_error_printed: f64,
+    }
 
-                received_bytes: session_container.received_bytes,
+    impl SendBatch {
+        pub fn new(batch_size: usize) -> SendBatch {
+            return SendBatch {
+                buffers: (0..batch_size).map(|_| Vec::with_capacity(MAX_DATAGRAM_SIZE)).collect(),
+                addrs: (0..batch_size).map(|_| unsafe { std::mem::zeroed() }).collect(),
+                iovecs: (0..batch_size).map(|_| unsafe { std::mem::zeroed() }).collect(),
+                headers: (0..batch_size).map(|_| unsafe { std::mem::zeroed() }).collect(),
+                queued: 0,
+                last_send_error_printed: f64::NEG_INFINITY,
+            };
+        }
 
-                intermessage_gap_mavg: session_container.intermessage_gap_mavg,
+        /// Buffer for the next datagram, it is only sent once committed with `queue`
+        pub fn next_buffer(&mut self) -> Option<&mut Vec<u8>> {
+            return self.buffers.get_mut(self.queued);
+        }
 
-                receive_time_windows: session_container.receive_time_windows.clone(),
+        pub fn queue(&mut self, addr: &std::net::SocketAddr) {
+            if self.queued >= self.buffers.len() {
+                return;
             }
+            let index = self.queued;
+            let addr_len = socket_addr_to_sockaddr(addr, &mut self.addrs[index]);
+            self.iovecs[index].iov_base = self.buffers[index].as_mut_ptr() as *mut libc::c_void;
+            self.iovecs[index].iov_len = self.buffers[index].len();
+            let header = &mut self.headers[index];
+            header.msg_hdr.msg_name = &mut self.addrs[index] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = addr_len;
+            header.msg_hdr.msg_iov = &mut self.iovecs[index];
+            header.msg_hdr.msg_iovlen = 1;
+            header.msg_hdr.msg_control = std::ptr::null_mut();
+            header.msg_hdr.msg_controllen = 0;
+            header.msg_hdr.msg_flags = 0;
+            self.queued += 1;
         }
 
-        pub fn to_json(self) -> Result<std::string::String, serde_json::Error> {
-            return serde_json::to_string(&self);
+        /// Sends everything queued, a datagram the kernel refuses is reported and skipped. Returns the
+        /// queue positions of the skipped datagrams in ascending order.
+        pub fn flush(&mut self, socket: &std::net::UdpSocket) -> Vec<usize> {
+            let mut failed: Vec<usize> = Vec::new();
+            let mut sent: usize = 0;
+            while sent < self.queued {
+                let result = unsafe {
+                    libc::sendmmsg(socket.as_raw_fd(), self.headers[sent..].as_mut_ptr(), (self.queued - sent) as libc::c_uint, 0)
+                };
+                if result < 0 {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    let current_time = metronome_lib::util::get_timestamp();
+                    if (current_time - self.last_send_error_printed) > 10.0 {
+                        if let Some(addr) = sockaddr_to_socket_addr(&self.addrs[sent]) {
+                            eprintln!("failed to sendmmsg() to metronome_client {}: {}", addr, e);
+                        }
+                        self.last_send_error_printed = current_time;
+                    }
+                    failed.push(sent);
+                    sent += 1;
+                } else {
+                    sent += result as usize;
+                }
+            }
+            self.queued = 0;
+            return failed;
         }
     }
-}
\ No newline at end of file
+}
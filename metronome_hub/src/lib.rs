@@ -0,0 +1,8 @@
+extern crate metronome_lib;
+extern crate serde;
+extern crate serde_json;
+#[macro_use] extern crate serde_derive;
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+pub mod hub_lib;
@@ -1,16 +1,22 @@
 extern crate clap;
 extern crate metronome_lib;
-extern crate serde;
-extern crate serde_json;
-#[macro_use] extern crate serde_derive;
+extern crate metronome_hub;
 use clap::{Arg, App};
-mod hub_lib;
-use metronome_lib::datatypes::{MetronomeMessage, MessageWithSize, OriginInfoMessage, SessionContainer};
-use hub_lib::datatypes::{ServerConfig, WrappedSerializedMessage, ServerSessionStatistics};
+use metronome_hub::hub_lib;
+use metronome_lib::datatypes::{MetronomeMessage, MetronomeMessageRef, MessageWithSize, OriginInfoMessage, ReceiveTimeWindows};
+use hub_lib::datatypes::{SESSION_TIMEOUT, ServerConfig, WrappedSerializedMessage, SessionShards, AnalyzerDispatch, SessionKey, HandledMessage, TenantConfig};
+use hub_lib::policy::{PolicyVerdict, SharedRateLimits};
+use hub_lib::handler::{PingHandler, ReplyTimestamps};
+use metronome_lib::timestamping::{TimestampedReceiver, Timestamps, TimestampingMode};
+#[cfg(target_os = "linux")]
+use hub_lib::batched::{RecvBatch, SendBatch, bind_reuseport};
+use hub_lib::acl::{AclAction, AclRule, SourceAcl};
+use hub_lib::impairment::Impairment;
+use metronome_lib::stats::{StatsDestination, StatsSink};
+use metronome_lib::schema::ServerSessionStatistics;
 
 
 const SLEEP_TIME: u64 = 100;
-const TIMEOUT_SECONDS: f64 = 5.0;
 const HOLE_TIMEOUT_SECONDS: f64 = 1.0;
 
 
@@ -33,36 +39,54 @@ fn prepare_client_socket(addr: std::net::SocketAddr) -> std::net::UdpSocket {
     return socket;
 }
 
-fn prepare_stats_socket(addr: std::net::SocketAddr) -> std::net::UdpSocket {
-    let socket: std::net::UdpSocket;
-
-    match std::net::UdpSocket::bind("0.0.0.0:0") {
-        Ok(bound_socket) => {
-            socket = bound_socket;
-        },
-        Err(_) => {
-            panic!("failed to bind socket");
+/// Binds one SO_REUSEPORT socket per worker so the kernel spreads incoming flows between them
+#[cfg(target_os = "linux")]
+fn prepare_worker_sockets(addr: std::net::SocketAddr, workers: usize) -> Vec<std::net::UdpSocket> {
+    let mut sockets: Vec<std::net::UdpSocket> = Vec::new();
+    for _i in 0..workers {
+        let socket = match bind_reuseport(&addr) {
+            Ok(bound_socket) => bound_socket,
+            Err(e) => {
+                panic!("failed to bind SO_REUSEPORT socket to {}: {}", addr, e);
+            }
+        };
+        if socket.set_read_timeout(Some(std::time::Duration::from_millis(SLEEP_TIME))).is_err() {
+            panic!("failed to set socket read timeout!");
         }
+        sockets.push(socket);
     }
+    return sockets;
+}
+
+#[cfg(not(target_os = "linux"))]
+fn prepare_worker_sockets(addr: std::net::SocketAddr, _workers: usize) -> Vec<std::net::UdpSocket> {
+    return vec![prepare_client_socket(addr)];
+}
 
-    if let Err(e) = socket.connect(addr) {
-        panic!("failed to connect clocktower socket to {}: {}", addr, e);
+fn enable_timestamping(socket: &std::net::UdpSocket, mode: TimestampingMode) -> TimestampingMode {
+    match metronome_lib::timestamping::enable(socket, mode) {
+        Ok(enabled_mode) => {
+            return enabled_mode;
+        },
+        Err(e) => {
+            eprintln!("failed to enable kernel timestamps, using userspace timestamps: {}", e);
+            return TimestampingMode::Off;
+        }
     }
-    
-    return socket;
 }
 
-fn receiver_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, socket: std::net::UdpSocket, receiver_tx: std::sync::mpsc::Sender<OriginInfoMessage>) {
+fn receiver_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, _config: ServerConfig, source_acl: std::sync::Arc<SourceAcl>, socket: std::net::UdpSocket, receiver_tx: std::sync::mpsc::Sender<OriginInfoMessage>) {
+    let source_acl = source_acl.worker();
     let mut rxbuf = [0;65536];
+    let mut timestamped_receiver = TimestampedReceiver::new();
     while running.load(std::sync::atomic::Ordering::Relaxed) {
-        if let Ok((size, addr)) = socket.recv_from(&mut rxbuf) {
+        if let Ok((size, addr, timestamps)) = timestamped_receiver.recv_from(&socket, &mut rxbuf) {
+            if !source_acl.allows(&addr.ip()) {
+                continue;
+            }
             if let Some(metronome_message) = MetronomeMessage::parse_from_buffer(&rxbuf) {
-                if metronome_message.key != config.key {
-                    continue;
-                }
-
                 let origin_info_message = OriginInfoMessage {
-                    timestamp: metronome_lib::util::get_timestamp(),
+                    timestamps: timestamps,
                     addr: addr,
                     message_with_size: MessageWithSize {
                         message_raw_size: size,
@@ -78,120 +102,236 @@ fn receiver_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, confi
     }
 }
 
-fn responder_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, _config: ServerConfig, socket: std::net::UdpSocket, responder_rx: std::sync::mpsc::Receiver<WrappedSerializedMessage>) {
-    let mut last_send_error_printed: f64 = 0.0;
+fn forward_to_analyzer(analyzer_tx: &AnalyzerDispatch, handled_messages: &mut Vec<HandledMessage>) {
+    if handled_messages.is_empty() {
+        return;
+    }
+    if let Err(e) = analyzer_tx.send(std::mem::take(handled_messages)) {
+        eprintln!("failed to send HandledMessage to analyzer: {}", e);
+    }
+}
+
+fn send_reply(socket: &std::net::UdpSocket, addr: std::net::SocketAddr, serialized_message: &[u8], last_send_error_printed: &mut f64) {
+    loop {
+        if let Err(e) = socket.send_to(serialized_message, addr) {
+            let current_time = metronome_lib::util::get_timestamp();
+            if (current_time - *last_send_error_printed) > 10.0 { 
+                eprintln!("failed to sendto() to metronome_client {}: {}", addr, e);
+                *last_send_error_printed = current_time;
+            }
+        } else {
+            break;
+        }
+    }
+}
+
+fn responder_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, socket: std::net::UdpSocket, responder_rx: std::sync::mpsc::Receiver<WrappedSerializedMessage>, responder_analyzer_tx: AnalyzerDispatch) {
+    let mut last_send_error_printed: f64 = f64::NEG_INFINITY;
+    let mut reply_timestamps = ReplyTimestamps::new(config.timestamping);
+    let mut completed: Vec<HandledMessage> = Vec::new();
+    let mut impairment: Option<Impairment> = None;
+    if !config.impairments.is_empty() {
+        impairment = Some(Impairment::new(&config, config.impairment_seed).expect("impairment rules were validated at startup"));
+    }
     while running.load(std::sync::atomic::Ordering::Relaxed) {
-        if let Ok(wrapped_message) = responder_rx.recv_timeout(std::time::Duration::from_millis(SLEEP_TIME)) {
-            loop {
-                if let Err(e) = socket.send_to(&wrapped_message.serialized_message, wrapped_message.addr) {
-                    let current_time = metronome_lib::util::get_timestamp();
-                    if (current_time - last_send_error_printed) > 10.0 { 
-                        eprintln!("failed to sendto() to metronome_client {}: {}", wrapped_message.addr, e);
-                        last_send_error_printed = current_time;
-                    }
-                } else {
-                    break;
-                }
+        // Keep polling the error queue while replies wait for their transmit timestamps
+        let mut wait_time = std::time::Duration::from_millis(if reply_timestamps.is_waiting() { 1 } else { SLEEP_TIME });
+        if let Some(due_in) = impairment.as_ref().and_then(|impairment| impairment.next_due_in(metronome_lib::util::get_monotonic_timestamp())) {
+            wait_time = wait_time.min(std::time::Duration::from_secs_f64(due_in));
+        }
+        if let Ok(wrapped_message) = responder_rx.recv_timeout(wait_time) {
+            let unimpaired_message = match &mut impairment {
+                Some(impairment) => impairment.submit(wrapped_message, metronome_lib::util::get_monotonic_timestamp(), &mut completed),
+                None => Some(wrapped_message),
+            };
+            if let Some(wrapped_message) = unimpaired_message {
+                send_reply(&socket, wrapped_message.addr, &wrapped_message.serialized_message, &mut last_send_error_printed);
+                reply_timestamps.sent(wrapped_message.handled_message, Timestamps::now(), &mut completed);
             }
         }
+        if let Some(impairment) = &mut impairment {
+            while let Some(delayed_reply) = impairment.due(metronome_lib::util::get_monotonic_timestamp()) {
+                send_reply(&socket, delayed_reply.addr, &delayed_reply.serialized_message, &mut last_send_error_printed);
+            }
+        }
+        reply_timestamps.poll(&socket, metronome_lib::util::get_monotonic_timestamp(), &mut completed);
+        forward_to_analyzer(&responder_analyzer_tx, &mut completed);
     }
 }
 
-fn handler_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, _config: ServerConfig, handler_receiver_rx: std::sync::mpsc::Receiver<OriginInfoMessage>, handler_responder_tx: std::sync::mpsc::Sender<WrappedSerializedMessage>, handler_analyzer_tx: std::sync::mpsc::Sender<OriginInfoMessage>) {
+/// Answers each ping from the receiving thread before queueing it for analysis, keeping channel hops out of the reply path
+fn inline_reflector_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, mut ping_handler: PingHandler, source_acl: std::sync::Arc<SourceAcl>, socket: std::net::UdpSocket, reflector_analyzer_tx: AnalyzerDispatch) {
+    let source_acl = source_acl.worker();
+    let mut rxbuf = [0;65536];
+    let mut timestamped_receiver = TimestampedReceiver::new();
+    let mut reply_timestamps = ReplyTimestamps::new(config.timestamping);
+    let mut completed: Vec<HandledMessage> = Vec::new();
+    let mut serialized: Vec<u8> = Vec::new();
+    let mut last_send_error_printed: f64 = f64::NEG_INFINITY;
     while running.load(std::sync::atomic::Ordering::Relaxed) {
-        if let Ok(origin_info_message) = handler_receiver_rx.recv_timeout(std::time::Duration::from_millis(SLEEP_TIME)) {
-            if origin_info_message.message_with_size.message.mode != "ping" {
+        if let Ok((size, addr, timestamps)) = timestamped_receiver.recv_from(&socket, &mut rxbuf) {
+            if !source_acl.allows(&addr.ip()) {
                 continue;
             }
+            if let Some(message) = MetronomeMessageRef::parse_from_slice(&rxbuf[..size]) {
+                if let Some(mut handled_message) = ping_handler.handle(&message, addr, size, timestamps, &mut serialized) {
+                    if handled_message.verdict == PolicyVerdict::Accepted {
+                        if let Err(e) = socket.send_to(&serialized, addr) {
+                            if (timestamps.monotonic - last_send_error_printed) > 10.0 {
+                                eprintln!("failed to sendto() to metronome_client {}: {}", addr, e);
+                                last_send_error_printed = timestamps.monotonic;
+                            }
+                            handled_message.send_failed();
+                            completed.push(handled_message);
+                        } else {
+                            reply_timestamps.sent(handled_message, Timestamps::now(), &mut completed);
+                        }
+                    } else {
+                        completed.push(handled_message);
+                    }
+                }
+            }
+        }
+        reply_timestamps.poll(&socket, metronome_lib::util::get_monotonic_timestamp(), &mut completed);
+        forward_to_analyzer(&reflector_analyzer_tx, &mut completed);
+    }
+}
 
-            let response = origin_info_message.message_with_size.clone().message.get_pong();
-            
-            match response.as_vec() {
-                Ok(serialized) => {
+fn handler_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, mut ping_handler: PingHandler, handler_receiver_rx: std::sync::mpsc::Receiver<OriginInfoMessage>, handler_responder_tx: std::sync::mpsc::Sender<WrappedSerializedMessage>, handler_analyzer_tx: AnalyzerDispatch) {
+    while running.load(std::sync::atomic::Ordering::Relaxed) {
+        if let Ok(origin_info_message) = handler_receiver_rx.recv_timeout(std::time::Duration::from_millis(SLEEP_TIME)) {
+            let mut serialized: Vec<u8> = Vec::new();
+            let message = origin_info_message.message_with_size.message.borrowed();
+            if let Some(handled_message) = ping_handler.handle(&message, origin_info_message.addr, origin_info_message.message_with_size.message_raw_size, origin_info_message.timestamps, &mut serialized) {
+                if handled_message.verdict == PolicyVerdict::Accepted {
+                    // The responder forwards the message to the analyzer once the reply is sent
                     if let Err(e) = handler_responder_tx.send(WrappedSerializedMessage {
                         addr: origin_info_message.addr,
-                        serialized_message: serialized
+                        serialized_message: serialized,
+                        handled_message: handled_message,
                     }) {
                         eprintln!("failed to send WrappedSerializedMessage to sender: {}", e);
                     }
-                    if let Err(e) = handler_analyzer_tx.send(origin_info_message) {
-                        eprintln!("failed to send MessageWithSize to analyzer: {}", e);
-                    }
-                },
-                Err(e) => {
-                    eprintln!("failed to serialize MetronomeMessage for transmission: {}", e);
+                } else if let Err(e) = handler_analyzer_tx.send(vec![handled_message]) {
+                    eprintln!("failed to send HandledMessage to analyzer: {}", e);
                 }
             }
         }
     }
 }
 
-fn send_stats(stats: ServerSessionStatistics, stats_socket: &std::net::UdpSocket) {
-    if let Ok(stats_json) = stats.to_json() {
-        let message_bytes = stats_json.into_bytes();
-        if let Err(e) = stats_socket.send(&message_bytes) {
-            eprintln!("failed to send statistics to clocktower: {}", e);
+/// Receives, answers and forwards a batch of pings per system call, replacing the receiver, handler and responder threads
+#[cfg(target_os = "linux")]
+fn batched_worker_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, mut ping_handler: PingHandler, source_acl: std::sync::Arc<SourceAcl>, socket: std::net::UdpSocket, worker_analyzer_tx: AnalyzerDispatch) {
+    let source_acl = source_acl.worker();
+    let mut recv_batch = RecvBatch::new(config.batch_size.max(1));
+    let mut send_batch = SendBatch::new(config.batch_size.max(1));
+    let mut reply_timestamps = ReplyTimestamps::new(config.timestamping);
+    let mut completed: Vec<HandledMessage> = Vec::new();
+    let mut replied: Vec<HandledMessage> = Vec::with_capacity(config.batch_size.max(1));
+    while running.load(std::sync::atomic::Ordering::Relaxed) {
+        if recv_batch.recv(&socket).is_ok() {
+            for index in 0..recv_batch.received_count() {
+                if let Some((addr, datagram, timestamps)) = recv_batch.get(index) {
+                    if !source_acl.allows(&addr.ip()) {
+                        continue;
+                    }
+                    if let Some(message) = MetronomeMessageRef::parse_from_slice(datagram) {
+                        if let Some(reply) = send_batch.next_buffer() {
+                            if let Some(handled_message) = ping_handler.handle(&message, addr, datagram.len(), timestamps, reply) {
+                                if handled_message.verdict == PolicyVerdict::Accepted {
+                                    send_batch.queue(&addr);
+                                    replied.push(handled_message);
+                                } else {
+                                    completed.push(handled_message);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            let mut failed = send_batch.flush(&socket).into_iter().peekable();
+            let sent_at = Timestamps::now();
+            for (position, mut handled_message) in replied.drain(..).enumerate() {
+                if failed.next_if_eq(&position).is_some() {
+                    handled_message.send_failed();
+                    completed.push(handled_message);
+                } else {
+                    reply_timestamps.sent(handled_message, sent_at, &mut completed);
+                }
+            }
         }
+        reply_timestamps.poll(&socket, metronome_lib::util::get_monotonic_timestamp(), &mut completed);
+        forward_to_analyzer(&worker_analyzer_tx, &mut completed);
     }
 }
 
-fn analyzer_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, analyzer_rx: std::sync::mpsc::Receiver<OriginInfoMessage>, clocktowers: Vec<std::net::UdpSocket>) {
-    let session_data_arced: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::string::String, SessionContainer>>> = std::sync::Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
-
-    let session_data_arced_inserter = session_data_arced.clone();
-    let running_inserter = running.clone();
-    let session_data_inserter_thread = std::thread::spawn(move || {
-        while running_inserter.load(std::sync::atomic::Ordering::Relaxed) {
-            if let Ok(origin_info_message) = analyzer_rx.recv_timeout(std::time::Duration::from_millis(SLEEP_TIME)) {
-                let message_with_size = origin_info_message.message_with_size;
-                if let Ok(mut session_data) = session_data_arced_inserter.lock() {
-                    if let Some(existing_session_statistics) = session_data.get_mut(&message_with_size.message.sid) {
-                        let session_statistics: &mut SessionContainer;
-                        session_statistics = existing_session_statistics;
-                        session_statistics.seq_analyze(message_with_size.message.seq, message_with_size.message_raw_size, origin_info_message.timestamp);
-                    } else {
-                        session_data.insert(message_with_size.message.sid.clone(), SessionContainer::new(message_with_size.message.seq, message_with_size.message_raw_size, origin_info_message.timestamp));
-                    }
-                }
-            }
+fn session_inserter_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, session_shards: SessionShards, shard_index: usize, inserter_rx: std::sync::mpsc::Receiver<Vec<HandledMessage>>, receive_time_windows: ReceiveTimeWindows) {
+    while running.load(std::sync::atomic::Ordering::Relaxed) {
+        if let Ok(handled_messages) = inserter_rx.recv_timeout(std::time::Duration::from_millis(SLEEP_TIME)) {
+            session_shards.analyze(shard_index, handled_messages, &receive_time_windows);
         }
-    });
+    }
+}
+
+fn analyzer_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, session_shards: SessionShards, source_acl: std::sync::Arc<SourceAcl>, inserter_rxs: Vec<std::sync::mpsc::Receiver<Vec<HandledMessage>>>, mut stats_sinks: Vec<StatsSink>) {
+    let mut session_data_inserter_threads: Vec<std::thread::JoinHandle<()>> = Vec::new();
+    for (shard_index, inserter_rx) in inserter_rxs.into_iter().enumerate() {
+        let running_inserter = running.clone();
+        let session_shards_inserter = session_shards.clone();
+        let receive_time_windows = config.receive_time_windows.clone();
+        session_data_inserter_threads.push(std::thread::spawn(move || {
+            session_inserter_thread(running_inserter, session_shards_inserter, shard_index, inserter_rx, receive_time_windows)
+        }));
+    }
     
-    let session_data_arced_scanner = session_data_arced.clone();
+    let session_shards_scanner = session_shards.clone();
     let running_scanner = running.clone();
     let session_scanner_thread = std::thread::spawn(move || {
         let mut last_session_data_scan: f64 = 0.0;
-        let session_data_scan_interval: f64 = TIMEOUT_SECONDS.min(config.stats_interval).min(HOLE_TIMEOUT_SECONDS);
+        let mut last_acl_stats: f64 = 0.0;
+        let session_data_scan_interval: f64 = SESSION_TIMEOUT.min(config.stats_interval).min(HOLE_TIMEOUT_SECONDS);
 
         while running_scanner.load(std::sync::atomic::Ordering::Relaxed) {
-            let current_time = metronome_lib::util::get_timestamp();
+            let current_time = metronome_lib::util::get_monotonic_timestamp();
             if last_session_data_scan < (current_time - session_data_scan_interval) {
                 last_session_data_scan = current_time;
-                let mut remove_items: Vec<std::string::String> = Vec::new();
-                if let Ok(mut session_data) = session_data_arced_scanner.lock() {
-                    for (session_key, session_container) in session_data.iter_mut() {
-                        let session_deadline = current_time - TIMEOUT_SECONDS;
-                        let hole_deadline = current_time - HOLE_TIMEOUT_SECONDS;
-                        let stats_deadline = current_time - config.stats_interval;
-                        session_container.prune_holes(hole_deadline);
-
-                        if session_container.last_rx < session_deadline {
-                            session_container.last_stats = current_time;
-                            for clocktower in clocktowers.iter() {
-                                send_stats(ServerSessionStatistics::from_session_container(session_key, session_container), &clocktower);
-                            }
-                            remove_items.push(session_key.clone());
-                        } else {
-                            if session_container.last_stats < stats_deadline {
+                for session_data_arced_scanner in session_shards_scanner.shards() {
+                    let mut remove_items: Vec<std::sync::Arc<SessionKey>> = Vec::new();
+                    if let Ok(mut session_data) = session_data_arced_scanner.lock() {
+                        for (session_key, session_container) in session_data.iter_mut() {
+                            let session_deadline = current_time - SESSION_TIMEOUT;
+                            let hole_deadline = current_time - HOLE_TIMEOUT_SECONDS;
+                            let stats_deadline = current_time - config.stats_interval;
+                            session_container.prune_holes(hole_deadline);
+
+                            if session_container.last_rx < session_deadline {
                                 session_container.last_stats = current_time;
-                                for clocktower in clocktowers.iter() {
-                                    send_stats(ServerSessionStatistics::from_session_container(session_key, session_container), &clocktower);
+                                let stats = ServerSessionStatistics::next_report(&session_key.tenant, &session_key.sid, session_container, config.stats_deltas, current_time);
+                                for stats_sink in stats_sinks.iter_mut() {
+                                    stats_sink.send(&stats);
+                                }
+                                remove_items.push(session_key.clone());
+                            } else {
+                                if session_container.last_stats < stats_deadline {
+                                    session_container.last_stats = current_time;
+                                    let stats = ServerSessionStatistics::next_report(&session_key.tenant, &session_key.sid, session_container, config.stats_deltas, current_time);
+                                    for stats_sink in stats_sinks.iter_mut() {
+                                        stats_sink.send(&stats);
+                                    }
                                 }
                             }
                         }
+                        for remove_item in remove_items.iter() {
+                            session_shards_scanner.remove(&mut session_data, remove_item);
+                        }
                     }
-                    for remove_item in remove_items.iter() {
-                        session_data.remove(remove_item);
+                }
+                if !source_acl.is_empty() && last_acl_stats < (current_time - config.stats_interval) {
+                    last_acl_stats = current_time;
+                    let stats = source_acl.statistics(metronome_lib::util::get_timestamp());
+                    for stats_sink in stats_sinks.iter_mut() {
+                        stats_sink.send(&stats);
                     }
                 }
             }
@@ -200,10 +340,50 @@ fn analyzer_thread(running: std::sync::Arc<std::sync::atomic::AtomicBool>, confi
         }
     });
 
-    session_data_inserter_thread.join().unwrap();
+    for session_data_inserter_thread in session_data_inserter_threads {
+        session_data_inserter_thread.join().unwrap();
+    }
     session_scanner_thread.join().unwrap();
 }
 
+fn spawn_threaded_pipeline(threads: &mut Vec<std::thread::JoinHandle<()>>, running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, ping_handler: PingHandler, source_acl: std::sync::Arc<SourceAcl>, socket: std::net::UdpSocket, analyzer_tx: AnalyzerDispatch) {
+    let (receiver_tx, handler_receiver_rx) = std::sync::mpsc::channel();
+    let (handler_responder_tx, responder_rx) = std::sync::mpsc::channel();
+
+    let socket_receiver = socket.try_clone().unwrap();
+    let socket_responder = socket;
+    let running_receiver = running.clone();
+    let running_handler = running.clone();
+    let running_responder = running;
+    let config_receiver = config.clone();
+    let config_responder = config;
+    let analyzer_tx_responder = analyzer_tx.clone();
+
+    threads.push(std::thread::spawn(move || {
+        receiver_thread(running_receiver, config_receiver, source_acl, socket_receiver, receiver_tx)
+    }));
+
+    threads.push(std::thread::spawn(move || {
+        handler_thread(running_handler, ping_handler, handler_receiver_rx, handler_responder_tx, analyzer_tx)
+    }));
+
+    threads.push(std::thread::spawn(move || {
+        responder_thread(running_responder, config_responder, socket_responder, responder_rx, analyzer_tx_responder)
+    }));
+}
+
+#[cfg(target_os = "linux")]
+fn spawn_batched_threads(threads: &mut Vec<std::thread::JoinHandle<()>>, running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, ping_handler: PingHandler, source_acl: std::sync::Arc<SourceAcl>, socket: std::net::UdpSocket, analyzer_tx: AnalyzerDispatch) {
+    threads.push(std::thread::spawn(move || {
+        batched_worker_thread(running, config, ping_handler, source_acl, socket, analyzer_tx)
+    }));
+}
+
+#[cfg(not(target_os = "linux"))]
+fn spawn_batched_threads(threads: &mut Vec<std::thread::JoinHandle<()>>, running: std::sync::Arc<std::sync::atomic::AtomicBool>, config: ServerConfig, ping_handler: PingHandler, source_acl: std::sync::Arc<SourceAcl>, socket: std::net::UdpSocket, analyzer_tx: AnalyzerDispatch) {
+    spawn_threaded_pipeline(threads, running, config, ping_handler, source_acl, socket, analyzer_tx);
+}
+
 fn main() {
     let matches = App::new("metronome-server")
         .version(env!("CARGO_PKG_VERSION"))
@@ -219,7 +399,13 @@ fn main() {
                 .short("k")
                 .long("key")
                 .takes_value(true)
-                .required(true)
+                .required_unless("tenants")
+        )
+        .arg(
+            Arg::with_name("tenants")
+                .long("tenants")
+                .takes_value(true)
+                .help("JSON file with a list of tenants, each with a name, key and optional allowed_prefixes, rate_limit, session_rate_limit, max_sessions and max_payload_size")
         )
         .arg(
             Arg::with_name("clocktower")
@@ -228,6 +414,7 @@ fn main() {
                 .multiple(true)
                 .takes_value(true)
                 .required(true)
+                .help("Statistics destination: host:port for the clocktower, influx-udp://host:port or influx-http://host:port/write?db=metronome for InfluxDB line protocol, statsd://host:port, dogstatsd://host:port, otlp-http://host:port for OpenTelemetry, or tcp://host:port and http://host:port/statistics for acknowledged delivery to the clocktower with ?spool=DIR&spool_records=N&spool_bytes=N")
         )
         .arg(
             Arg::with_name("stats_interval")
@@ -235,59 +422,274 @@ fn main() {
                 .takes_value(true)
                 .default_value("1.0")
         )
+        .arg(
+            Arg::with_name("stats_deltas")
+                .long("stats-deltas")
+                .help("Add what each counter gained since the previous report to session statistics")
+        )
+        .arg(
+            Arg::with_name("receive_windows")
+                .long("receive-windows")
+                .takes_value(true)
+                .default_value("10")
+                .help("Number of windows the receive time period is divided into, counted per stats interval")
+        )
+        .arg(
+            Arg::with_name("receive_window_period")
+                .long("receive-window-period")
+                .takes_value(true)
+                .default_value("1.0")
+                .help("Seconds the receive time windows repeat over, e.g. 0.02 to line up with 20 ms voice frames")
+        )
+        .arg(
+            Arg::with_name("max_reply_size")
+                .long("max-reply-size")
+                .takes_value(true)
+                .default_value("65507")
+        )
+        .arg(
+            Arg::with_name("max_amplification")
+                .long("max-amplification")
+                .takes_value(true)
+                .default_value("10.0")
+        )
+        .arg(
+            Arg::with_name("max_source_reply_bps")
+                .long("max-source-reply-bps")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("rate_limit")
+                .long("rate-limit")
+                .takes_value(true)
+                .help("Global message rate limit as PPS[:BURST]")
+        )
+        .arg(
+            Arg::with_name("key_rate_limit")
+                .long("key-rate-limit")
+                .takes_value(true)
+                .help("Message rate limit for the --key tenant as PPS[:BURST]")
+        )
+        .arg(
+            Arg::with_name("session_rate_limit")
+                .long("session-rate-limit")
+                .takes_value(true)
+                .help("Message rate limit for each session as PPS[:BURST]")
+        )
+        .arg(
+            Arg::with_name("prefix_rate_limit")
+                .long("prefix-rate-limit")
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+                .help("Message rate limit for each source address within a prefix as CIDR=PPS[:BURST]")
+        )
+        .arg(
+            Arg::with_name("allow")
+                .long("allow")
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+                .help("Only accept messages from sources within CIDR")
+        )
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+                .help("Drop messages from sources within CIDR")
+        )
+        .arg(
+            Arg::with_name("batch_size")
+                .long("batch-size")
+                .takes_value(true)
+                .default_value("1")
+                .help("Datagrams received and sent per system call, values above 1 enable recvmmsg/sendmmsg on Linux")
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .takes_value(true)
+                .default_value("1")
+                .help("Worker threads each with their own SO_REUSEPORT socket and shard of the session table, Linux only")
+        )
+        .arg(
+            Arg::with_name("inline_reflect")
+                .long("inline-reflect")
+                .help("Send each pong from the receiving thread before queueing the ping for analysis")
+        )
+        .arg(
+            Arg::with_name("timestamping")
+                .long("timestamping")
+                .takes_value(true)
+                .possible_values(&["off", "software", "hardware"])
+                .default_value("off")
+                .help("Kernel receive and transmit timestamps, Linux only. Hardware timestamps need the interface configured for them")
+        )
+        .arg(
+            Arg::with_name("impair")
+                .long("impair")
+                .multiple(true)
+                .takes_value(true)
+                .number_of_values(1)
+                .help("Impair pongs of sessions matching key=KEY and/or sid=SID, or all sessions, with comma separated delay, jitter, reorder_delay (seconds), loss, reorder, duplicate (probability), loss_burst (mean burst length) and rate (PPS[:BURST]). The first matching rule applies. Cannot be combined with --timestamping")
+        )
+        .arg(
+            Arg::with_name("impair_seed")
+                .long("impair-seed")
+                .takes_value(true)
+                .help("Random seed for --impair, defaults to the current time")
+        )
         .get_matches();
     
-    let mut clocktowers: Vec<std::net::UdpSocket> = Vec::new();
+    let mut stats_sinks: Vec<StatsSink> = Vec::new();
     if let Some(clocktower_strings) = matches.values_of("clocktower") {
         for clocktower_string in clocktower_strings {
-            let clocktower_address: std::net::SocketAddr = clocktower_string.parse().unwrap();
-            let clocktower_socket = prepare_stats_socket(clocktower_address);
-            clocktowers.push(clocktower_socket);
+            let destination: StatsDestination = clocktower_string.parse().unwrap();
+            match StatsSink::new(&destination, "metronome-hub") {
+                Ok(stats_sink) => {
+                    stats_sinks.push(stats_sink);
+                },
+                Err(e) => {
+                    panic!("failed to set up statistics destination '{}': {}", clocktower_string, e);
+                }
+            }
+        }
+    }
+
+    let mut tenants: Vec<TenantConfig> = Vec::new();
+    if let Some(tenants_path) = matches.value_of("tenants") {
+        match TenantConfig::load_from_file(tenants_path) {
+            Ok(loaded_tenants) => {
+                tenants = loaded_tenants;
+            },
+            Err(e) => {
+                panic!("{}", e);
+            }
+        }
+    }
+    if let Some(key) = matches.value_of("key") {
+        tenants.push(TenantConfig {
+            name: "default".to_string(),
+            key: key.to_string(),
+            allowed_prefixes: Vec::new(),
+            rate_limit: matches.value_of("key_rate_limit").map(|value| value.parse().unwrap()),
+            session_rate_limit: None,
+            max_sessions: None,
+            max_payload_size: None,
+        });
+    }
+
+    let mut acl_rules: Vec<AclRule> = Vec::new();
+    for (arg_name, action) in [("allow", AclAction::Allow), ("deny", AclAction::Deny)].iter() {
+        if let Some(prefix_strings) = matches.values_of(arg_name) {
+            for prefix_string in prefix_strings {
+                acl_rules.push(AclRule {
+                    action: *action,
+                    prefix: prefix_string.parse().unwrap(),
+                });
+            }
         }
     }
 
-    let config = ServerConfig {
+    let mut config = ServerConfig {
         bind: matches.value_of("bind").unwrap().parse().unwrap(),
-        key: matches.value_of("key").unwrap().to_string(),
+        tenants: tenants,
         stats_interval: matches.value_of("stats_interval").unwrap().parse().unwrap(),
+        stats_deltas: matches.is_present("stats_deltas"),
+        receive_time_windows: ReceiveTimeWindows::new(
+            matches.value_of("receive_windows").unwrap().parse().unwrap(),
+            matches.value_of("receive_window_period").unwrap().parse().unwrap(),
+        ).unwrap_or_else(|e| panic!("{}", e)),
+        max_reply_size: matches.value_of("max_reply_size").unwrap().parse().unwrap(),
+        max_amplification: matches.value_of("max_amplification").unwrap().parse().unwrap(),
+        max_source_reply_bps: matches.value_of("max_source_reply_bps").map(|value| value.parse().unwrap()),
+        rate_limit: matches.value_of("rate_limit").map(|value| value.parse().unwrap()),
+        session_rate_limit: matches.value_of("session_rate_limit").map(|value| value.parse().unwrap()),
+        prefix_rate_limits: matches.values_of("prefix_rate_limit").map(|values| values.map(|value| value.parse().unwrap()).collect()).unwrap_or_default(),
+        acl_rules: acl_rules,
+        batch_size: matches.value_of("batch_size").unwrap().parse().unwrap(),
+        workers: matches.value_of("workers").unwrap().parse::<usize>().unwrap().max(1),
+        inline_reflect: matches.is_present("inline_reflect"),
+        timestamping: matches.value_of("timestamping").unwrap().parse().unwrap(),
+        impairments: matches.values_of("impair").map(|values| values.map(|value| value.parse().unwrap()).collect()).unwrap_or_default(),
+        impairment_seed: matches.value_of("impair_seed").map(|value| value.parse().unwrap()).unwrap_or_else(|| metronome_lib::util::get_timestamp().to_bits()),
     };
 
-    let socket = prepare_client_socket(config.bind);
+    if !config.impairments.is_empty() {
+        if let Err(e) = Impairment::new(&config, config.impairment_seed) {
+            panic!("{}", e);
+        }
+        if config.workers > 1 || config.batch_size > 1 || config.inline_reflect {
+            eprintln!("impairments are applied by the responder thread, ignoring --workers, --batch-size and --inline-reflect");
+            config.workers = 1;
+            config.batch_size = 1;
+            config.inline_reflect = false;
+        }
+    }
+
     let running = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let source_acl = std::sync::Arc::new(SourceAcl::new(&config.acl_rules));
+    let mut io_threads: Vec<std::thread::JoinHandle<()>> = Vec::new();
 
-    let (receiver_tx, handler_receiver_rx) = std::sync::mpsc::channel();
-    let (handler_responder_tx, responder_rx) = std::sync::mpsc::channel();
-    let (handler_analyzer_tx, analyzer_rx) = std::sync::mpsc::channel();
+    if config.workers > 1 && !cfg!(target_os = "linux") {
+        eprintln!("multiple workers are only supported on Linux, falling back to a single worker");
+        config.workers = 1;
+    }
+    let session_shards = SessionShards::new(config.workers, &config.tenants);
+    let shared_rate_limits = SharedRateLimits::new(&config);
+    let mut inserter_txs: Vec<std::sync::mpsc::Sender<Vec<HandledMessage>>> = Vec::new();
+    let mut inserter_rxs: Vec<std::sync::mpsc::Receiver<Vec<HandledMessage>>> = Vec::new();
+    for _i in 0..session_shards.shards().len() {
+        let (inserter_tx, inserter_rx) = std::sync::mpsc::channel();
+        inserter_txs.push(inserter_tx);
+        inserter_rxs.push(inserter_rx);
+    }
+    let analyzer_dispatch = AnalyzerDispatch::new(session_shards.clone(), inserter_txs);
+
+    if config.workers > 1 {
+        let sockets = prepare_worker_sockets(config.bind, config.workers);
+        for socket in sockets.iter() {
+            config.timestamping = enable_timestamping(socket, config.timestamping);
+        }
+        for socket in sockets {
+            spawn_batched_threads(&mut io_threads, running.clone(), config.clone(), PingHandler::new(&config, session_shards.clone(), shared_rate_limits.clone()), source_acl.clone(), socket, analyzer_dispatch.clone());
+        }
+    } else if config.batch_size > 1 && cfg!(target_os = "linux") {
+        let socket = prepare_client_socket(config.bind);
+        config.timestamping = enable_timestamping(&socket, config.timestamping);
+        spawn_batched_threads(&mut io_threads, running.clone(), config.clone(), PingHandler::new(&config, session_shards.clone(), shared_rate_limits.clone()), source_acl.clone(), socket, analyzer_dispatch);
+    } else {
+        if config.batch_size > 1 {
+            eprintln!("batched I/O is only supported on Linux, falling back to one datagram per system call");
+        }
+        let socket = prepare_client_socket(config.bind);
+        config.timestamping = enable_timestamping(&socket, config.timestamping);
+        if config.inline_reflect {
+            let running_reflector = running.clone();
+            let config_reflector = config.clone();
+            let ping_handler = PingHandler::new(&config, session_shards.clone(), shared_rate_limits.clone());
+            let source_acl_reflector = source_acl.clone();
+            io_threads.push(std::thread::spawn(move || {
+                inline_reflector_thread(running_reflector, config_reflector, ping_handler, source_acl_reflector, socket, analyzer_dispatch)
+            }));
+        } else {
+            spawn_threaded_pipeline(&mut io_threads, running.clone(), config.clone(), PingHandler::new(&config, session_shards.clone(), shared_rate_limits.clone()), source_acl.clone(), socket, analyzer_dispatch);
+        }
+    }
 
-    let socket_receiver = socket.try_clone().unwrap();
-    let socket_responder = socket.try_clone().unwrap();
-    let running_receiver = running.clone();
-    let running_handler = running.clone();
-    let running_responder = running.clone();
     let running_analyzer = running.clone();
-    let config_receiver = config.clone();
-    let config_handler = config.clone();
-    let config_responder = config.clone();
     let config_analyzer = config.clone();
-
-    let receiver_thd = std::thread::spawn(move || {
-        receiver_thread(running_receiver, config_receiver, socket_receiver, receiver_tx)
-    });
-
-    let handler_thd = std::thread::spawn(move || {
-        handler_thread(running_handler, config_handler, handler_receiver_rx, handler_responder_tx, handler_analyzer_tx)
-    });
-
-    let responder_thd = std::thread::spawn(move || {
-        responder_thread(running_responder, config_responder, socket_responder, responder_rx)
-    });
+    let session_shards_analyzer = session_shards.clone();
+    let source_acl_analyzer = source_acl.clone();
 
     let analyzer_thd = std::thread::spawn(move || {
-        analyzer_thread(running_analyzer, config_analyzer, analyzer_rx, clocktowers)
+        analyzer_thread(running_analyzer, config_analyzer, session_shards_analyzer, source_acl_analyzer, inserter_rxs, stats_sinks)
     });
 
-    receiver_thd.join().unwrap();
-    handler_thd.join().unwrap();
-    responder_thd.join().unwrap();
+    for io_thread in io_threads {
+        io_thread.join().unwrap();
+    }
     analyzer_thd.join().unwrap();
 }
@@ -0,0 +1,53 @@
+extern crate metronome_hub;
+extern crate metronome_lib;
+
+use metronome_hub::hub_lib::simulation::{run, SimulatedHub, SimulationConfig};
+use metronome_lib::datatypes::{MetronomeMessage, MetronomeMessageRef, PayloadPattern};
+use metronome_lib::timestamping::Timestamps;
+
+/// A checksummed ping asking for the hub counters, with a random payload
+fn ping(seq: u64) -> MetronomeMessage {
+    let mut message = MetronomeMessage::ping("simulation", "simulation", seq);
+    message.hub_stats_requested = true;
+    message.set_payload(PayloadPattern::Random.generate(64, seq, 1), true);
+    return message;
+}
+
+#[test]
+fn hub_counts_corrupted_pings() {
+    let config = SimulationConfig::default();
+    let mut hub = SimulatedHub::new(&config.hub, config.hole_timeout).unwrap();
+    let message = ping(0);
+    let datagram = message.as_vec().unwrap();
+    hub.handle(&datagram, config.client_addr, Timestamps::at(1.0, 1600000000.0)).unwrap();
+
+    // Flip a bit in the middle of the payload of the next ping
+    let message = ping(1);
+    let mut datagram = message.as_vec().unwrap();
+    let payload = message.payload.as_ref().unwrap().as_bytes();
+    let offset = datagram.windows(payload.len()).position(|window| window == payload).unwrap() + payload.len() / 2;
+    datagram[offset] ^= 0x01;
+    let reply = hub.handle(&datagram, config.client_addr, Timestamps::at(1.1, 1600000000.1)).unwrap();
+
+    assert_eq!(hub.counters("default", "simulation").unwrap().corrupted_messages, 1);
+    // The pong is checksummed afresh, so only corruption on the way back shows at the client
+    let pong = MetronomeMessageRef::parse_from_slice(&reply).unwrap();
+    assert_eq!(pong.payload_intact(), Some(true));
+    assert_eq!(pong.hub_stats.unwrap().corrupted_messages, 0);
+}
+
+#[test]
+fn clean_runs_see_no_corruption_with_any_pattern() {
+    for pattern in ["fill", "zeros", "random", "prbs", "custom:metronome"].iter() {
+        let config = SimulationConfig {
+            payload_pattern: pattern.parse().unwrap(),
+            payload_size: 100,
+            ..SimulationConfig::default()
+        };
+        let result = run(&config);
+        assert_eq!(result.client.corrupted_messages, 0, "{}", pattern);
+        assert_eq!(result.client.hub_corrupted_messages, Some(0), "{}", pattern);
+        assert_eq!(Some(result.client.sent_bytes), result.inspect_hub_session(|session| session.received_bytes), "{}", pattern);
+        assert_eq!(result.client.received_messages, result.client.sent_messages, "{}", pattern);
+    }
+}
@@ -0,0 +1,306 @@
+extern crate metronome_hub;
+extern crate metronome_lib;
+
+use metronome_hub::hub_lib::acl::{AclAction, AclRule};
+use metronome_hub::hub_lib::simulation::{run, SimulationConfig};
+use metronome_lib::datatypes::ReceiveTimeWindows;
+use metronome_lib::simulation::LinkConfig;
+use metronome_lib::stats::StatisticsRecord;
+
+fn assert_close(actual: f64, expected: f64) {
+    assert!((actual - expected).abs() < 1e-6, "expected {}, got {}", expected, actual);
+}
+
+#[test]
+fn clean_link_loses_nothing() {
+    let result = run(&SimulationConfig::default());
+    let hub = result.hub_counters().unwrap();
+
+    // 10 s of pings and 2 s of settling at 100 pps
+    assert_eq!(result.client.sent_messages, 1200);
+    assert_eq!(result.client.received_messages, 1200);
+    assert_eq!(result.client.timely_received_messages, 1200);
+    assert_eq!(result.client.lost_messages, 0);
+    assert_eq!(result.client.inflight_messages, 0);
+    assert_eq!(result.client.seq_unexpected_decrement, 0);
+    assert_close(result.client.rtt_best.unwrap(), 0.02);
+    assert_close(result.client.rtt_worst.unwrap(), 0.02);
+    assert_close(result.client.intermessage_gap_mavg.unwrap(), 0.01);
+    assert_eq!(hub.received_messages, 1200);
+    assert_eq!(hub.holes_created, 0);
+}
+
+#[test]
+fn forward_loss_is_attributed_to_forward_path() {
+    let config = SimulationConfig {
+        forward: LinkConfig { loss: 0.05, ..LinkConfig::default() },
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+    let hub = result.hub_counters().unwrap();
+
+    assert!(result.forward.dropped > 20, "too few drops to be meaningful: {}", result.forward.dropped);
+    assert_eq!(result.reverse.dropped, 0);
+    assert_eq!(result.client.lost_messages, result.forward.dropped);
+    assert_eq!(hub.received_messages, result.client.sent_messages - result.forward.dropped);
+    assert_eq!(hub.holes_created, result.forward.dropped);
+    assert_eq!(hub.holes_timed_out, result.forward.dropped);
+    assert_eq!(hub.holes_current, 0);
+    assert_eq!(result.client.forward_lost_messages, Some(result.forward.dropped));
+    assert_eq!(result.client.reverse_lost_messages, Some(0));
+}
+
+#[test]
+fn reverse_loss_is_attributed_to_reverse_path() {
+    let config = SimulationConfig {
+        reverse: LinkConfig { loss: 0.05, ..LinkConfig::default() },
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+    let hub = result.hub_counters().unwrap();
+
+    assert!(result.reverse.dropped > 20, "too few drops to be meaningful: {}", result.reverse.dropped);
+    assert_eq!(result.forward.dropped, 0);
+    assert_eq!(result.client.lost_messages, result.reverse.dropped);
+    assert_eq!(hub.received_messages, result.client.sent_messages);
+    assert_eq!(hub.holes_created, 0);
+    assert_eq!(result.client.forward_lost_messages, Some(0));
+    assert_eq!(result.client.reverse_lost_messages, Some(result.reverse.dropped));
+}
+
+#[test]
+fn forward_reordering_closes_holes_without_loss() {
+    let config = SimulationConfig {
+        forward: LinkConfig { reorder: 0.05, reorder_delay: 0.025, ..LinkConfig::default() },
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+    let hub = result.hub_counters().unwrap();
+
+    assert!(result.forward.reordered > 20, "too few reorders to be meaningful: {}", result.forward.reordered);
+    assert_eq!(result.client.lost_messages, 0);
+    assert!(hub.holes_created > 0);
+    assert_eq!(hub.holes_closed, hub.holes_created);
+    assert_eq!(hub.holes_timed_out, 0);
+    assert_eq!(result.client.forward_lost_messages, Some(0));
+    assert_eq!(result.client.forward_reordered_messages, Some(hub.holes_closed));
+}
+
+#[test]
+fn reverse_reordering_is_seen_by_client_only() {
+    let config = SimulationConfig {
+        reverse: LinkConfig { reorder: 0.05, reorder_delay: 0.025, ..LinkConfig::default() },
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+    let hub = result.hub_counters().unwrap();
+
+    assert_eq!(result.client.lost_messages, 0);
+    assert_eq!(hub.holes_created, 0);
+    assert!(result.client.seq_unexpected_decrement > 0);
+    assert_eq!(result.client.reverse_reordered_messages, Some(result.client.seq_unexpected_decrement));
+}
+
+#[test]
+fn duplicates_are_received_but_not_lost() {
+    let config = SimulationConfig {
+        forward: LinkConfig { duplicate: 0.05, ..LinkConfig::default() },
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+    let hub = result.hub_counters().unwrap();
+
+    assert!(result.forward.duplicated > 20, "too few duplicates to be meaningful: {}", result.forward.duplicated);
+    assert_eq!(hub.received_messages, result.client.sent_messages + result.forward.duplicated);
+    assert_eq!(hub.holes_created, 0);
+    assert_eq!(result.client.received_messages, result.client.sent_messages + result.forward.duplicated);
+    assert_eq!(result.client.timely_received_messages, result.client.sent_messages);
+    assert_eq!(result.client.lost_messages, 0);
+}
+
+#[test]
+fn jitter_stays_within_bounds() {
+    let link = LinkConfig { delay: 0.01, jitter: 0.004, ..LinkConfig::default() };
+    let config = SimulationConfig {
+        forward: link,
+        reverse: link,
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+
+    let rtt_best = result.client.rtt_best.unwrap();
+    let rtt_worst = result.client.rtt_worst.unwrap();
+    assert!(rtt_best >= 0.02 - 1e-9 && rtt_worst <= 0.028 + 1e-9, "rtt {} - {} outside link bounds", rtt_best, rtt_worst);
+    assert!(rtt_worst - rtt_best > 0.004, "jitter not applied: {} - {}", rtt_best, rtt_worst);
+    assert_eq!(result.client.lost_messages, 0);
+}
+
+#[test]
+fn same_seed_gives_same_statistics() {
+    let link = LinkConfig { delay: 0.01, jitter: 0.02, loss: 0.02, reorder: 0.02, reorder_delay: 0.03, duplicate: 0.01 };
+    let config = SimulationConfig {
+        forward: link,
+        reverse: link,
+        seed: 42,
+        ..SimulationConfig::default()
+    };
+    let first = run(&config);
+    let second = run(&config);
+    assert_eq!(first.forward, second.forward);
+    assert_eq!(first.reverse, second.reverse);
+    assert_eq!(first.client.to_json().unwrap(), second.client.to_json().unwrap());
+
+    let other = run(&SimulationConfig { seed: 43, ..config });
+    assert_ne!(first.forward, other.forward);
+}
+
+#[test]
+fn wall_clock_step_does_not_disturb_session() {
+    let wall_start = 1_600_000_000.0;
+    let config = SimulationConfig {
+        wall_start: wall_start,
+        wall_step: Some((5.0, -3600.0)),
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+    let hub = result.hub_counters().unwrap();
+
+    assert_eq!(result.client.lost_messages, 0);
+    assert_close(result.client.rtt_best.unwrap(), 0.02);
+    assert_close(result.client.rtt_worst.unwrap(), 0.02);
+    assert_close(result.client.intermessage_gap_mavg.unwrap(), 0.01);
+    assert_eq!(hub.holes_created, 0);
+    assert_eq!(hub.holes_timed_out, 0);
+    // Reports carry the stepped wall time
+    assert!(result.client.timestamp < wall_start);
+}
+
+#[test]
+fn receive_windows_show_frame_alignment() {
+    // Pings every 10 ms spread evenly over the default ten windows of a second
+    let result = run(&SimulationConfig::default());
+    assert_eq!(result.client.receive_time_windows.iter().sum::<u64>(), result.client.received_messages);
+    assert_close(result.client.receive_time_window_period, 1.0);
+    assert!(result.client.receive_burstiness.unwrap() < 0.05);
+
+    // Against a 20 ms frame they only ever land in the same couple of windows
+    let config = SimulationConfig {
+        receive_time_windows: ReceiveTimeWindows::new(100, 0.02).unwrap(),
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+    assert_eq!(result.client.receive_time_windows.len(), 100);
+    assert_eq!(result.client.receive_time_windows.iter().sum::<u64>(), result.client.received_messages);
+    assert!(result.client.receive_time_windows.iter().filter(|count| **count > 0).count() <= 4);
+    assert!(result.client.receive_burstiness.unwrap() > 0.5);
+}
+
+#[test]
+fn single_interval_matches_lifetime_counters() {
+    let config = SimulationConfig {
+        forward: LinkConfig { loss: 0.05, ..LinkConfig::default() },
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+    let client = &result.client;
+
+    // The only report covers the whole run
+    assert_eq!(client.interval_sent_messages, client.sent_messages);
+    assert_eq!(client.interval_received_messages, client.received_messages);
+    assert_eq!(client.interval_lost_messages, client.lost_messages);
+    let loss_percent = client.lost_messages as f64 * 100.0 / (client.lost_messages + client.timely_received_messages) as f64;
+    assert_close(client.interval_loss_percent.unwrap(), loss_percent);
+    assert_close(client.interval_rtt_min.unwrap(), client.rtt_best.unwrap());
+    assert_close(client.interval_rtt_max.unwrap(), client.rtt_worst.unwrap());
+    assert_close(client.interval_rtt_avg.unwrap(), 0.02);
+    let duration = client.interval_duration.unwrap();
+    assert_close(client.interval_received_pps.unwrap(), client.received_messages as f64 / duration);
+}
+
+#[test]
+fn throughput_adds_header_overhead_per_message() {
+    let result = run(&SimulationConfig::default());
+    let client = &result.client;
+    assert!(client.sent_bytes > 0);
+    assert_eq!(client.interval_sent_bytes, client.sent_bytes);
+    assert_eq!(client.interval_received_bytes, client.received_bytes);
+
+    // IPv4 and UDP headers, then the Ethernet header and FCS on top
+    assert_eq!(client.interval_sent_ip_bytes, client.sent_bytes + client.sent_messages * 28);
+    assert_eq!(client.interval_sent_wire_bytes, client.interval_sent_ip_bytes + client.sent_messages * 18);
+    assert_eq!(client.interval_received_wire_bytes, client.received_bytes + client.received_messages * 46);
+
+    let duration = client.interval_duration.unwrap();
+    assert_close(client.interval_sent_bps.unwrap(), client.interval_sent_wire_bytes as f64 * 8.0 / duration);
+    assert_close(client.interval_received_goodput_bps.unwrap(), client.received_bytes as f64 * 8.0 / duration);
+    assert!(client.interval_sent_goodput_bps.unwrap() < client.interval_sent_bps.unwrap());
+}
+
+#[test]
+fn session_rate_limit_polices_pings() {
+    let mut config = SimulationConfig::default();
+    config.hub.tenants[0].session_rate_limit = Some("50:10".parse().unwrap());
+    let result = run(&config);
+    let hub = result.hub_counters().unwrap();
+
+    // Every ping reaches the hub, only those within 50 pps and the burst of 10 are answered
+    assert_eq!(hub.received_messages, result.client.sent_messages);
+    let answered = hub.received_messages - hub.policed_messages;
+    assert!((600..=610).contains(&answered), "unexpected answered count {}", answered);
+    assert_eq!(result.client.received_messages, result.client.sent_messages - hub.policed_messages);
+    assert_eq!(result.inspect_hub_session(|session| session.rate_limited), Some(hub.policed_messages));
+}
+
+#[test]
+fn denied_source_gets_no_session() {
+    let mut config = SimulationConfig::default();
+    config.hub.acl_rules = vec![AclRule {
+        action: AclAction::Deny,
+        prefix: "192.0.2.0/24".parse().unwrap(),
+    }];
+    let result = run(&config);
+
+    assert_eq!(result.client.received_messages, 0);
+    assert_eq!(result.client.lost_messages, result.client.sent_messages);
+    assert!(result.hub_counters().is_none());
+}
+
+#[test]
+fn unknown_key_is_ignored() {
+    let config = SimulationConfig {
+        key: "wrong".to_string(),
+        ..SimulationConfig::default()
+    };
+    let result = run(&config);
+
+    assert_eq!(result.client.received_messages, 0);
+    assert!(result.hub.counters("default", "simulation").is_none());
+}
+
+#[test]
+fn hub_impairment_loss_is_attributed_to_reverse_path() {
+    let mut config = SimulationConfig::default();
+    config.hub.impairments = vec!["loss=0.05".parse().unwrap()];
+    config.hub.impairment_seed = 7;
+    let result = run(&config);
+    let hub = result.hub_counters().unwrap();
+    let impairment_dropped = result.inspect_hub_session(|session| session.impairment_dropped).unwrap();
+
+    assert!(impairment_dropped > 20, "too few drops to be meaningful: {}", impairment_dropped);
+    assert_eq!(hub.received_messages, result.client.sent_messages);
+    assert_eq!(hub.holes_created, 0);
+    assert_eq!(result.client.lost_messages, impairment_dropped);
+    assert_eq!(result.client.forward_lost_messages, Some(0));
+    assert_eq!(result.client.reverse_lost_messages, Some(impairment_dropped));
+}
+
+#[test]
+fn hub_impairment_delay_adds_to_rtt() {
+    let mut config = SimulationConfig::default();
+    config.hub.impairments = vec!["delay=0.03".parse().unwrap()];
+    let result = run(&config);
+
+    assert_eq!(result.client.lost_messages, 0);
+    assert_close(result.client.rtt_best.unwrap(), 0.05);
+    assert_close(result.client.rtt_worst.unwrap(), 0.05);
+}
@@ -0,0 +1,7 @@
+extern crate metronome_lib;
+extern crate serde_json;
+
+/// Prints the JSON Schema of the statistics records, kept in schema/statistics.schema.json
+fn main() {
+    println!("{}", serde_json::to_string_pretty(&metronome_lib::schema::json_schema()).unwrap());
+}
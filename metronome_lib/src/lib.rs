@@ -1,10 +1,16 @@
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate rmp_serde;
+extern crate serde_json;
+extern crate schemars;
 extern crate time;
+#[cfg(target_os = "linux")]
+extern crate libc;
 
 pub mod datatypes {
-    #[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+    /// Encoded as a msgpack array, the optional fields after `sid` are only sent when set so that
+    /// plain pings and their pongs stay the six element array older peers expect
+    #[derive(Debug, PartialEq, Deserialize, Clone)]
     pub struct MetronomeMessage {
         pub mode: String,
         pub payload: Option<String>,
@@ -12,6 +18,84 @@ pub mod datatypes {
         pub seq: u64,
         pub key: String,
         pub sid: String,
+        #[serde(default)]
+        pub hub_stats_requested: bool,
+        #[serde(default)]
+        pub hub_stats: Option<HubSessionCounters>,
+        /// CRC-32 of the payload, opt-in as hubs predating it reject the longer message. Pongs carry one when the ping did
+        #[serde(default)]
+        pub payload_checksum: Option<u32>,
+    }
+
+    /// Borrowed counterpart of MetronomeMessage with an identical wire format, used where
+    /// messages are parsed and answered without allocating
+    #[derive(Debug, PartialEq, Deserialize, Clone)]
+    pub struct MetronomeMessageRef<'a> {
+        pub mode: &'a str,
+        #[serde(borrow)]
+        pub payload: Option<&'a str>,
+        pub mul: f32,
+        pub seq: u64,
+        pub key: &'a str,
+        pub sid: &'a str,
+        #[serde(default)]
+        pub hub_stats_requested: bool,
+        #[serde(default)]
+        pub hub_stats: Option<HubSessionCounters>,
+        #[serde(default)]
+        pub payload_checksum: Option<u32>,
+    }
+
+    impl serde::Serialize for MetronomeMessage {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            return self.borrowed().serialize(serializer);
+        }
+    }
+
+    impl<'a> serde::Serialize for MetronomeMessageRef<'a> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::SerializeSeq;
+            // Trailing fields left at their defaults are dropped, the decoder fills them back in
+            let len = if self.payload_checksum.is_some() {
+                9
+            } else if self.hub_stats.is_some() {
+                8
+            } else if self.hub_stats_requested {
+                7
+            } else {
+                6
+            };
+            let mut seq = serializer.serialize_seq(Some(len))?;
+            seq.serialize_element(self.mode)?;
+            seq.serialize_element(&self.payload)?;
+            seq.serialize_element(&self.mul)?;
+            seq.serialize_element(&self.seq)?;
+            seq.serialize_element(self.key)?;
+            seq.serialize_element(self.sid)?;
+            if len > 6 {
+                seq.serialize_element(&self.hub_stats_requested)?;
+            }
+            if len > 7 {
+                seq.serialize_element(&self.hub_stats)?;
+            }
+            if len > 8 {
+                seq.serialize_element(&self.payload_checksum)?;
+            }
+            return seq.end();
+        }
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+    pub struct HubSessionCounters {
+        pub received_messages: u64,
+        pub holes_created: u64,
+        pub holes_closed: u64,
+        pub holes_timed_out: u64,
+        pub holes_current: u64,
+        #[serde(default)]
+        pub policed_messages: u64,
+        #[serde(default)]
+        pub corrupted_messages: u64,
     }
 
     #[derive(Clone)]
@@ -21,17 +105,32 @@ pub mod datatypes {
     }
 
     pub struct OriginInfoMessage {
-        pub timestamp: f64,
+        pub timestamps: super::timestamping::Timestamps,
         pub addr: std::net::SocketAddr,
         pub message_with_size: MessageWithSize,
     }
 
     pub struct TimestampedMessage {
-        pub timestamp: f64,
+        pub timestamps: super::timestamping::Timestamps,
         pub message_with_size: MessageWithSize,
     }
     
     impl MetronomeMessage {
+        /// A ping without payload and with the pong the same size
+        pub fn ping(key: &str, sid: &str, seq: u64) -> MetronomeMessage {
+            return MetronomeMessage {
+                mode: "ping".to_string(),
+                payload: None,
+                mul: 1.0,
+                seq: seq,
+                key: key.to_string(),
+                sid: sid.to_string(),
+                hub_stats_requested: false,
+                hub_stats: None,
+                payload_checksum: None,
+            };
+        }
+
         pub fn parse_from_buffer(buffer: &[u8;65536]) -> Option<MetronomeMessage> {
             if let Ok(deserialized) = rmp_serde::from_slice::<MetronomeMessage>(buffer) {
                 return Some(deserialized);
@@ -40,17 +139,54 @@ pub mod datatypes {
             }
         }
 
+        pub fn parse_from_slice(buffer: &[u8]) -> Option<MetronomeMessage> {
+            if let Ok(deserialized) = rmp_serde::from_slice::<MetronomeMessage>(buffer) {
+                return Some(deserialized);
+            } else {
+                return None;
+            }
+        }
+
         pub fn as_vec(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
             return rmp_serde::to_vec(&self);
         }
 
+        pub fn borrowed(&self) -> MetronomeMessageRef<'_> {
+            return MetronomeMessageRef {
+                mode: &self.mode,
+                payload: self.payload.as_deref(),
+                mul: self.mul,
+                seq: self.seq,
+                key: &self.key,
+                sid: &self.sid,
+                hub_stats_requested: self.hub_stats_requested,
+                hub_stats: self.hub_stats,
+                payload_checksum: self.payload_checksum,
+            };
+        }
+
+        pub fn pong_payload_len(&self) -> usize {
+            return self.borrowed().pong_payload_len();
+        }
+
+        pub fn payload_intact(&self) -> Option<bool> {
+            return self.borrowed().payload_intact();
+        }
+
+        /// Replaces the payload, along with the checksum covering it when `checksummed`
+        pub fn set_payload(&mut self, payload: String, checksummed: bool) {
+            self.payload_checksum = if checksummed { Some(payload_checksum(&payload)) } else { None };
+            self.payload = Some(payload);
+        }
+
         pub fn get_pong(self) -> MetronomeMessage {
             let new_payload: Option<String>;
 
             if let Some(payload) = &self.payload {
                 if self.mul != 1.0 {
-                    let target_len : usize = ((payload.len() as f32) * self.mul) as usize;
-                    new_payload = Some(std::iter::repeat(payload.chars().next().unwrap()).take(target_len).collect::<String>());
+                    let mut resized = String::new();
+                    cycle_into(payload, self.pong_payload_len(), &mut resized);
+                    new_payload = Some(resized);
                 } else {
                     new_payload = self.payload;
                 }
@@ -60,24 +196,387 @@ pub mod datatypes {
 
             let reply_message = MetronomeMessage {
                 mode: "pong".to_string(),
+                payload_checksum: self.payload_checksum.map(|_| payload_checksum(new_payload.as_deref().unwrap_or(""))),
                 payload: new_payload,
                 mul: self.mul,
                 seq: self.seq,
                 key: self.key,
                 sid: self.sid,
+                hub_stats_requested: self.hub_stats_requested,
+                hub_stats: None,
             };
 
             return reply_message;
         }
     }
 
+    impl<'a> MetronomeMessageRef<'a> {
+        pub fn parse_from_slice(buffer: &'a [u8]) -> Option<MetronomeMessageRef<'a>> {
+            if let Ok(deserialized) = rmp_serde::from_slice::<MetronomeMessageRef>(buffer) {
+                return Some(deserialized);
+            } else {
+                return None;
+            }
+        }
+
+        pub fn write_to(&self, buffer: &mut Vec<u8>) -> Result<(), rmp_serde::encode::Error> {
+            buffer.clear();
+            return rmp_serde::encode::write(buffer, &self);
+        }
+
+        pub fn pong_payload_len(&self) -> usize {
+            if let Some(payload) = self.payload {
+                if self.mul != 1.0 {
+                    return ((payload.len() as f32) * self.mul) as usize;
+                }
+                return payload.len();
+            }
+            return 0;
+        }
+
+        /// Whether the payload matches its checksum, None when the message carries no checksum
+        pub fn payload_intact(&self) -> Option<bool> {
+            return self.payload_checksum.map(|checksum| payload_checksum(self.payload.unwrap_or("")) == checksum);
+        }
+
+        /// Builds the pong in place, a resized payload is written into `scratch` so it can be reused between messages
+        pub fn get_pong<'b>(&'b self, scratch: &'b mut String) -> MetronomeMessageRef<'b> {
+            let new_payload: Option<&'b str>;
+
+            if let Some(payload) = self.payload {
+                if self.mul != 1.0 {
+                    cycle_into(payload, self.pong_payload_len(), scratch);
+                    new_payload = Some(scratch.as_str());
+                } else {
+                    new_payload = Some(payload);
+                }
+            } else {
+                new_payload = None;
+            }
+
+            return MetronomeMessageRef {
+                mode: "pong",
+                payload_checksum: self.payload_checksum.map(|_| payload_checksum(new_payload.unwrap_or(""))),
+                payload: new_payload,
+                mul: self.mul,
+                seq: self.seq,
+                key: self.key,
+                sid: self.sid,
+                hub_stats_requested: self.hub_stats_requested,
+                hub_stats: None,
+            };
+        }
+    }
+
+    /// Repeats `source` into `out` up to `len` bytes, so resized pongs keep the pattern of the ping
+    fn cycle_into(source: &str, len: usize, out: &mut String) {
+        out.clear();
+        for c in source.chars().cycle() {
+            if source.is_empty() || out.len() + c.len_utf8() > len {
+                break;
+            }
+            out.push(c);
+        }
+    }
+
+    const CRC32_TABLE: [u32; 256] = crc32_table();
+
+    const fn crc32_table() -> [u32; 256] {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        return table;
+    }
+
+    /// CRC-32 (IEEE 802.3) of a payload
+    pub fn payload_checksum(payload: &str) -> u32 {
+        let mut crc: u32 = !0;
+        for byte in payload.bytes() {
+            crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        return !crc;
+    }
+
+    /// Content of ping payloads. Payloads travel as msgpack strings, so generated bytes stay below 0x80
+    /// and random payloads compress by at most an eighth.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum PayloadPattern {
+        /// One character repeated, 'X' unless given
+        Fill(char),
+        Zeros,
+        /// Fresh random bytes for every message, drawn from the session seed mixed with the sequence number
+        Random,
+        /// PRBS-31 (x^31 + x^28 + 1) seven bits per byte, started from a state derived from the sequence number
+        Prbs,
+        /// The given text repeated
+        Custom(String),
+    }
+
+    impl PayloadPattern {
+        /// Whether payloads differ between messages and have to be generated for each of them
+        pub fn per_message(&self) -> bool {
+            match self {
+                PayloadPattern::Random | PayloadPattern::Prbs => return true,
+                _ => return false,
+            }
+        }
+
+        /// Payload of message `seq`, `seed` is drawn once per session so random payloads differ between sessions
+        pub fn generate(&self, len: usize, seq: u64, seed: u64) -> String {
+            let mut payload = String::with_capacity(len);
+            match self {
+                PayloadPattern::Fill(fill) => cycle_into(&fill.to_string(), len, &mut payload),
+                PayloadPattern::Zeros => cycle_into("\0", len, &mut payload),
+                PayloadPattern::Random => {
+                    let mut rng = super::simulation::Rng::new(seed ^ seq.wrapping_mul(0xBF58_476D_1CE4_E5B9));
+                    while payload.len() < len {
+                        for byte in rng.next_u64().to_le_bytes().iter().take(len - payload.len()) {
+                            payload.push((byte & 0x7F) as char);
+                        }
+                    }
+                },
+                PayloadPattern::Prbs => {
+                    // Scrambled so consecutive sequence numbers start far apart in the sequence
+                    let mut state: u32 = (super::simulation::Rng::new(seq).next_u64() as u32 & 0x7FFF_FFFF).max(1);
+                    for _i in 0..len {
+                        let mut byte: u8 = 0;
+                        for _bit in 0..7 {
+                            let feedback = ((state >> 30) ^ (state >> 27)) & 1;
+                            state = ((state << 1) | feedback) & 0x7FFF_FFFF;
+                            byte = (byte << 1) | feedback as u8;
+                        }
+                        payload.push(byte as char);
+                    }
+                },
+                PayloadPattern::Custom(text) => cycle_into(text, len, &mut payload),
+            }
+            return payload;
+        }
+    }
+
+    impl Default for PayloadPattern {
+        fn default() -> PayloadPattern {
+            return PayloadPattern::Fill('X');
+        }
+    }
+
+    impl std::str::FromStr for PayloadPattern {
+        type Err = std::string::String;
+
+        fn from_str(s: &str) -> Result<PayloadPattern, std::string::String> {
+            if let Some(text) = s.strip_prefix("custom:") {
+                if text.is_empty() {
+                    return Err(format!("payload pattern '{}' needs some text to repeat", s));
+                }
+                return Ok(PayloadPattern::Custom(text.to_string()));
+            }
+            if let Some(fill) = s.strip_prefix("fill:") {
+                let mut chars = fill.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => return Ok(PayloadPattern::Fill(c)),
+                    _ => return Err(format!("payload pattern '{}' needs exactly one fill character", s)),
+                }
+            }
+            match s {
+                "fill" => return Ok(PayloadPattern::default()),
+                "zeros" => return Ok(PayloadPattern::Zeros),
+                "random" => return Ok(PayloadPattern::Random),
+                "prbs" => return Ok(PayloadPattern::Prbs),
+                _ => return Err(format!("unknown payload pattern '{}', expected fill[:CHAR], zeros, random, prbs or custom:TEXT", s)),
+            }
+        }
+    }
+
     pub struct Hole {
         pub created: f64,
     }
 
+    pub const UDP_HEADER_BYTES: u64 = 8;
+    pub const IPV4_HEADER_BYTES: u64 = 20;
+    pub const IPV6_HEADER_BYTES: u64 = 40;
+    /// Ethernet header and frame check sequence, the preamble and inter-frame gap are left out
+    pub const ETHERNET_OVERHEAD_BYTES: u64 = 18;
+
+    /// IP and UDP header bytes each datagram exchanged with `addr` carries on top of its payload
+    pub fn ip_overhead(addr: &std::net::SocketAddr) -> u64 {
+        let ipv4 = match addr.ip() {
+            std::net::IpAddr::V4(_) => true,
+            std::net::IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().is_some(),
+        };
+        return UDP_HEADER_BYTES + if ipv4 { IPV4_HEADER_BYTES } else { IPV6_HEADER_BYTES };
+    }
+
+    /// Traffic in one direction over a reporting interval
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct Throughput {
+        /// UDP payload bytes
+        pub bytes: u64,
+        /// Bytes including the IP and UDP headers
+        pub ip_bytes: u64,
+        /// Estimated Ethernet frame bytes
+        pub wire_bytes: u64,
+        /// Bits per second on the wire
+        pub bps: Option<f64>,
+        /// Bits per second of UDP payload
+        pub goodput_bps: Option<f64>,
+    }
+
+    /// Messages counted by where their wall clock receive time falls within a repeating period,
+    /// counted afresh every reporting interval
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct ReceiveTimeWindows {
+        pub period: f64,
+        pub counts: Vec<u64>,
+    }
+
+    impl ReceiveTimeWindows {
+        pub fn new(windows: usize, period: f64) -> Result<ReceiveTimeWindows, std::string::String> {
+            if windows == 0 || period.is_nan() || period <= 0.0 {
+                return Err(format!("receive time windows need at least one window and a positive period, got {} over {} s", windows, period));
+            }
+            return Ok(ReceiveTimeWindows {
+                period: period,
+                counts: vec![0; windows],
+            });
+        }
+
+        pub fn record(&mut self, wall_timestamp: f64) {
+            let phase = wall_timestamp.rem_euclid(self.period) / self.period;
+            let target_window = ((phase * self.counts.len() as f64).floor() as usize).min(self.counts.len() - 1);
+            self.counts[target_window] += 1;
+        }
+
+        /// Spread of the counts scaled to 0 when every window got as many messages and 1 when
+        /// they all landed in one window. Only meaningful with several messages per window.
+        pub fn burstiness(&self) -> Option<f64> {
+            let total: u64 = self.counts.iter().sum();
+            if self.counts.len() < 2 || total == 0 {
+                return None;
+            }
+            let windows = self.counts.len() as f64;
+            let mean = total as f64 / windows;
+            let variance = self.counts.iter().map(|count| (*count as f64 - mean).powi(2)).sum::<f64>() / windows;
+            return Some((variance.sqrt() / mean / (windows - 1.0).sqrt()).min(1.0));
+        }
+
+        pub fn reset(&mut self) {
+            for count in self.counts.iter_mut() {
+                *count = 0;
+            }
+        }
+    }
+
+    /// Counts since the previous report, started over with every report so short incidents are
+    /// not smeared by the lifetime counters and moving averages. Times are monotonic.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct IntervalAccumulator {
+        /// When the interval started, None until the session has seen anything
+        pub started: Option<f64>,
+        pub sent_messages: u64,
+        pub sent_bytes: u64,
+        pub received_messages: u64,
+        pub received_bytes: u64,
+        pub lost_messages: u64,
+        pub rtt_min: Option<f64>,
+        pub rtt_max: Option<f64>,
+        pub rtt_sum: f64,
+        pub rtt_count: u64,
+    }
+
+    impl IntervalAccumulator {
+        pub fn new(started: Option<f64>) -> IntervalAccumulator {
+            return IntervalAccumulator {
+                started: started,
+                ..IntervalAccumulator::default()
+            };
+        }
+
+        /// Starts the interval at the first event a new session sees
+        pub fn seen(&mut self, timestamp: f64) {
+            if self.started.is_none() {
+                self.started = Some(timestamp);
+            }
+        }
+
+        pub fn rtt(&mut self, rtt: f64) {
+            self.rtt_min = Some(self.rtt_min.map_or(rtt, |rtt_min| rtt_min.min(rtt)));
+            self.rtt_max = Some(self.rtt_max.map_or(rtt, |rtt_max| rtt_max.max(rtt)));
+            self.rtt_sum += rtt;
+            self.rtt_count += 1;
+        }
+
+        pub fn rtt_avg(&self) -> Option<f64> {
+            if self.rtt_count == 0 {
+                return None;
+            }
+            return Some(self.rtt_sum / self.rtt_count as f64);
+        }
+
+        pub fn duration(&self, now: f64) -> Option<f64> {
+            return self.started.map(|started| now - started).filter(|duration| *duration > 0.0);
+        }
+
+        /// `count` per second over the interval ending at `now`
+        pub fn rate(&self, count: u64, now: f64) -> Option<f64> {
+            return self.duration(now).map(|duration| count as f64 / duration);
+        }
+
+        /// Share of the messages whose fate was settled this interval that were lost
+        pub fn loss_percent(lost: u64, delivered: u64) -> Option<f64> {
+            if lost + delivered == 0 {
+                return None;
+            }
+            return Some(lost as f64 * 100.0 / (lost + delivered) as f64);
+        }
+
+        /// `messages` datagrams carrying `bytes` of payload over the interval ending at `now`,
+        /// with `ip_overhead` bytes of IP and UDP headers each
+        pub fn throughput(&self, messages: u64, bytes: u64, ip_overhead: u64, now: f64) -> Throughput {
+            let ip_bytes = bytes + messages * ip_overhead;
+            let wire_bytes = ip_bytes + messages * ETHERNET_OVERHEAD_BYTES;
+            return Throughput {
+                bytes: bytes,
+                ip_bytes: ip_bytes,
+                wire_bytes: wire_bytes,
+                bps: self.rate(wire_bytes * 8, now),
+                goodput_bps: self.rate(bytes * 8, now),
+            };
+        }
+
+        pub fn restart(&mut self, now: f64) {
+            *self = IntervalAccumulator::new(Some(now));
+        }
+    }
+
+    impl Default for ReceiveTimeWindows {
+        /// Ten windows over each second
+        fn default() -> ReceiveTimeWindows {
+            return ReceiveTimeWindows {
+                period: 1.0,
+                counts: vec![0; 10],
+            };
+        }
+    }
+
+    /// Hub side state of a session. `last_stats`, `last_rx` and hole creation times are monotonic
+    /// timestamps, `last_rx_wall` is only used for reporting.
     pub struct SessionContainer {
+        /// Wall clock nanoseconds of the first message, identifies this lifetime of the counters
+        pub epoch: u64,
+        pub stats_seq: u64,
+        pub counter_baseline: super::stats::CounterBaseline,
         pub last_stats: f64,
         pub last_rx: f64,
+        pub last_rx_wall: f64,
         pub last_seq: u64,
         pub received_messages: u64,
         pub holes_created: u64,
@@ -85,19 +584,40 @@ pub mod datatypes {
         pub holes_timed_out: u64,
         pub holes: std::collections::HashMap<u64, Hole>,
         pub received_bytes: u64,
+        /// Pongs handed to the socket, duplicates from the impairment emulator included
+        pub sent_messages: u64,
+        pub sent_bytes: u64,
+        /// IP and UDP header bytes per datagram, by the address family of the client
+        pub ip_overhead: u64,
         pub intermessage_gap_mavg: Option<f64>,
-        pub receive_time_windows: Vec<u64>,
+        pub receive_time_windows: ReceiveTimeWindows,
+        pub interval: IntervalAccumulator,
+        pub rejected_reply_size: u64,
+        pub rejected_amplification: u64,
+        pub rejected_bandwidth: u64,
+        pub rate_limited: u64,
+        pub rejected_payload_size: u64,
+        pub residence_time_mavg: Option<f64>,
+        pub residence_time_worst: Option<f64>,
+        pub rx_timestamp_source: Option<super::timestamping::TimestampSource>,
+        pub residence_timestamp_source: Option<super::timestamping::TimestampSource>,
+        pub impairment_dropped: u64,
+        pub impairment_rate_limited: u64,
+        pub impairment_duplicated: u64,
+        pub impairment_reordered: u64,
+        /// Pings whose payload did not match their checksum
+        pub corrupted_messages: u64,
     }
 
     impl SessionContainer {
-        pub fn new(seq: u64, received_bytes: usize, rx_time: f64) -> SessionContainer {
-            let mut receive_time_windows = Vec::new();
-            for _i in 0..10 {
-                receive_time_windows.push(0);
-            }
+        pub fn new(seq: u64, received_bytes: usize, rx_timestamps: &super::timestamping::Timestamps) -> SessionContainer {
             let new_session = SessionContainer {
+                epoch: (rx_timestamps.event_wall() * 1e9) as u64,
+                stats_seq: 0,
+                counter_baseline: super::stats::CounterBaseline::new(),
                 last_stats: 0.0,
-                last_rx: rx_time,
+                last_rx: rx_timestamps.event_monotonic(),
+                last_rx_wall: rx_timestamps.event_wall(),
                 last_seq: seq,
                 received_messages: 1,
                 holes_created: 0,
@@ -105,13 +625,38 @@ pub mod datatypes {
                 holes_timed_out: 0,
                 holes: std::collections::HashMap::new(),
                 received_bytes: received_bytes as u64,
+                sent_messages: 0,
+                sent_bytes: 0,
+                ip_overhead: UDP_HEADER_BYTES + IPV4_HEADER_BYTES,
                 intermessage_gap_mavg: None,
-                receive_time_windows: receive_time_windows,
+                receive_time_windows: ReceiveTimeWindows::default(),
+                interval: IntervalAccumulator {
+                    started: Some(rx_timestamps.event_monotonic()),
+                    received_messages: 1,
+                    received_bytes: received_bytes as u64,
+                    ..IntervalAccumulator::default()
+                },
+                rejected_reply_size: 0,
+                rejected_amplification: 0,
+                rejected_bandwidth: 0,
+                rate_limited: 0,
+                rejected_payload_size: 0,
+                residence_time_mavg: None,
+                residence_time_worst: None,
+                rx_timestamp_source: None,
+                residence_timestamp_source: None,
+                impairment_dropped: 0,
+                impairment_rate_limited: 0,
+                impairment_duplicated: 0,
+                impairment_reordered: 0,
+                corrupted_messages: 0,
             };
             return new_session;
         }
 
-        pub fn seq_analyze(&mut self, seq: u64, size: usize, current_time: f64) {
+        pub fn seq_analyze(&mut self, seq: u64, size: usize, rx_timestamps: &super::timestamping::Timestamps) {
+            let current_time = rx_timestamps.event_monotonic();
+            let current_wall_time = rx_timestamps.event_wall();
             if current_time > self.last_rx {
                 if let Some(current_intermessage_gap) = self.intermessage_gap_mavg {
                     self.intermessage_gap_mavg = Some(((current_intermessage_gap * 9.0) + ((current_time - self.last_rx) * 1.0)) / 10.0);
@@ -120,7 +665,10 @@ pub mod datatypes {
                 }
             }
             self.received_messages += 1;
+            self.interval.received_messages += 1;
+            self.interval.received_bytes += size as u64;
             self.last_rx = current_time;
+            self.last_rx_wall = current_wall_time;
             self.received_bytes += size as u64;
             if seq == (self.last_seq + 1) || seq == 0 {
                 self.last_seq = seq;
@@ -138,14 +686,42 @@ pub mod datatypes {
                 }
                 self.last_seq = seq;
             }
-            let target_bucket: usize = (current_time.fract() * self.receive_time_windows.len() as f64).floor() as usize;
-            if let Some(value) = self.receive_time_windows.get_mut(target_bucket) {
-                *value += 1;
+            self.receive_time_windows.record(current_wall_time);
+        }
+
+        /// Tracks the time between receiving a message and handing its reply to the kernel
+        pub fn residence_analyze(&mut self, residence_time: f64, source: super::timestamping::TimestampSource) {
+            self.residence_timestamp_source = Some(source);
+            if let Some(current_residence_time) = self.residence_time_mavg {
+                self.residence_time_mavg = Some(((current_residence_time * 9.0) + (residence_time * 1.0)) / 10.0);
             } else {
-                eprintln!("failed to assign receive time window to packet (tgtb={}, rxtw={})", target_bucket, current_time);
+                self.residence_time_mavg = Some(residence_time);
+            }
+            if self.residence_time_worst.is_none_or(|worst| residence_time > worst) {
+                self.residence_time_worst = Some(residence_time);
             }
         }
 
+        /// Counts a pong of `size` bytes handed to the socket
+        pub fn sent(&mut self, size: usize) {
+            self.sent_messages += 1;
+            self.sent_bytes += size as u64;
+            self.interval.sent_messages += 1;
+            self.interval.sent_bytes += size as u64;
+        }
+
+        pub fn get_counters(&self) -> HubSessionCounters {
+            return HubSessionCounters {
+                received_messages: self.received_messages,
+                holes_created: self.holes_created,
+                holes_closed: self.holes_closed,
+                holes_timed_out: self.holes_timed_out,
+                holes_current: self.holes.len() as u64,
+                policed_messages: self.rejected_reply_size + self.rejected_amplification + self.rejected_bandwidth + self.rate_limited + self.rejected_payload_size,
+                corrupted_messages: self.corrupted_messages,
+            };
+        }
+
         pub fn prune_holes(&mut self, deadline: f64) {
             let mut remove_items: Vec<u64> = Vec::new();
             for (hole_seq, hole) in self.holes.iter() {
@@ -155,6 +731,7 @@ pub mod datatypes {
             }
             for remove_item in remove_items.iter() {
                 self.holes_timed_out += 1;
+                self.interval.lost_messages += 1;
                 self.holes.remove(remove_item);
             }
         }
@@ -162,9 +739,3366 @@ pub mod datatypes {
 
 }
 
-pub mod util {
-    pub fn get_timestamp() -> f64 {
-        let current_time_duration = time::OffsetDateTime::now() - time::OffsetDateTime::unix_epoch();
-        return current_time_duration.as_seconds_f64();
+/// Statistics records as exported by the client and the hub, and the JSON Schema describing them
+pub mod schema {
+    use schemars::JsonSchema;
+
+    /// Bumped whenever a field is removed or changes meaning, adding fields keeps the version. Fields
+    /// added after the first release need a default so records written before them still parse.
+    pub const SCHEMA_VERSION: u32 = 2;
+
+    pub const CLIENT_SESSION_STATISTICS: &str = "client_session_statistics";
+    pub const HUB_SESSION_STATISTICS: &str = "hub_session_statistics";
+    pub const HUB_ACL_STATISTICS: &str = "hub_acl_statistics";
+
+    /// Hubs from before tenants served a single one by this name
+    fn default_tenant() -> std::string::String {
+        return "default".to_string();
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Clone)]
+    pub struct ClientSessionStatistics {
+        pub clocktower_type: std::string::String,
+        /// Version of this schema the record was written with, 0 for records from before it was versioned
+        #[serde(default)]
+        pub schema_version: u32,
+
+        pub sid: std::string::String,
+        pub timestamp: f64,
+        /// Counts the reports of this session from 0, a gap means a report was lost
+        #[serde(default)]
+        pub stats_seq: u64,
+        /// Wall clock nanoseconds at which the counters started, changes when they were reset
+        #[serde(default)]
+        pub epoch: u64,
+
+        pub seq_unexpected_increment: u64,
+        pub seq_unexpected_decrement: u64,
+
+        pub sent_messages: u64,
+        pub received_messages: u64,
+        pub timely_received_messages: u64,
+        
+        pub lost_messages: u64,
+        pub inflight_messages: u64,
+
+        pub received_bytes: u64,
+        #[serde(default)]
+        pub sent_bytes: u64,
+        /// Pongs whose payload did not match their checksum
+        #[serde(default)]
+        pub corrupted_messages: u64,
+
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub rtt_worst: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub rtt_best: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub rtt_mavg: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub intermessage_gap_mavg: Option<f64>,
+
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub send_error_mavg: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub send_error_worst: Option<f64>,
+        #[serde(default)]
+        pub send_skipped: u64,
+        #[serde(default)]
+        pub send_catch_up: u64,
+        // Lateness percentiles cover the sends since the previous report
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub send_lateness_p50: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub send_lateness_p90: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub send_lateness_p99: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub send_lateness_p999: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub send_lateness_max: Option<f64>,
+
+        /// Messages received in each window of the period over the last reporting interval
+        pub receive_time_windows: Vec<u64>,
+        /// Seconds the receive time windows divide between them
+        #[serde(default)]
+        pub receive_time_window_period: f64,
+        /// 0 when messages arrived evenly over the windows, 1 when all arrived in one of them
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub receive_burstiness: Option<f64>,
+
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub hub_received_messages: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub hub_holes_created: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub hub_holes_closed: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub hub_holes_timed_out: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub hub_holes_current: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub hub_policed_messages: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub hub_corrupted_messages: Option<u64>,
+
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub forward_lost_messages: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub reverse_lost_messages: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub forward_reordered_messages: Option<u64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub reverse_reordered_messages: Option<u64>,
+
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub rx_timestamp_source: Option<super::timestamping::TimestampSource>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub rtt_timestamp_source: Option<super::timestamping::TimestampSource>,
+
+        // Interval values cover the time since the previous report
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_duration: Option<f64>,
+        #[serde(default)]
+        pub interval_sent_messages: u64,
+        #[serde(default)]
+        pub interval_received_messages: u64,
+        #[serde(default)]
+        pub interval_lost_messages: u64,
+        /// Lost out of the pings answered or given up on during the interval
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_loss_percent: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_rtt_min: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_rtt_avg: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_rtt_max: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_received_pps: Option<f64>,
+        // Payload, IP and estimated Ethernet bytes, and the rates they make on the wire and as payload
+        #[serde(default)]
+        pub interval_sent_bytes: u64,
+        #[serde(default)]
+        pub interval_sent_ip_bytes: u64,
+        #[serde(default)]
+        pub interval_sent_wire_bytes: u64,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_sent_bps: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_sent_goodput_bps: Option<f64>,
+        #[serde(default)]
+        pub interval_received_bytes: u64,
+        #[serde(default)]
+        pub interval_received_ip_bytes: u64,
+        #[serde(default)]
+        pub interval_received_wire_bytes: u64,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_received_bps: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_received_goodput_bps: Option<f64>,
+
+        /// What each counter gained since the previous report, when enabled
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub deltas: Option<std::collections::BTreeMap<std::string::String, u64>>,
+
+        // Too large for the clocktower datagrams, only exported where histograms are understood
+        #[serde(skip)]
+        pub rtt_histogram: super::histogram::Histogram,
+    }
+
+    impl super::stats::StatisticsRecord for ClientSessionStatistics {
+        fn to_json(&self) -> Result<std::string::String, serde_json::Error> {
+            return serde_json::to_string(self);
+        }
+
+        fn points(&self) -> Vec<super::stats::StatisticsPoint> {
+            let mut point = super::stats::StatisticsPoint::new("metronome_client_session", self.timestamp);
+            if self.epoch != 0 {
+                point.start = Some(self.epoch as f64 / 1e9);
+            }
+            point.tag("sid", &self.sid);
+
+            point.counter("seq_unexpected_increment", self.seq_unexpected_increment);
+            point.counter("seq_unexpected_decrement", self.seq_unexpected_decrement);
+            point.counter("sent_messages", self.sent_messages);
+            point.counter("received_messages", self.received_messages);
+            point.counter("timely_received_messages", self.timely_received_messages);
+            point.counter("lost_messages", self.lost_messages);
+            point.gauge("inflight_messages", self.inflight_messages as f64);
+            point.counter("received_bytes", self.received_bytes);
+            point.counter("sent_bytes", self.sent_bytes);
+            point.counter("corrupted_messages", self.corrupted_messages);
+
+            point.optional_gauge("rtt_worst", self.rtt_worst);
+            point.optional_gauge("rtt_best", self.rtt_best);
+            point.optional_gauge("rtt_mavg", self.rtt_mavg);
+            if self.rtt_histogram.count() > 0 {
+                point.histogram("rtt", &self.rtt_histogram);
+            }
+            point.optional_gauge("intermessage_gap_mavg", self.intermessage_gap_mavg);
+
+            point.optional_gauge("send_error_mavg", self.send_error_mavg);
+            point.optional_gauge("send_error_worst", self.send_error_worst);
+            point.counter("send_skipped", self.send_skipped);
+            point.counter("send_catch_up", self.send_catch_up);
+            point.optional_gauge("send_lateness_p50", self.send_lateness_p50);
+            point.optional_gauge("send_lateness_p90", self.send_lateness_p90);
+            point.optional_gauge("send_lateness_p99", self.send_lateness_p99);
+            point.optional_gauge("send_lateness_p999", self.send_lateness_p999);
+            point.optional_gauge("send_lateness_max", self.send_lateness_max);
+
+            for (window, count) in self.receive_time_windows.iter().enumerate() {
+                point.gauge(&format!("receive_time_window_{}", window), *count as f64);
+            }
+            point.optional_gauge("receive_burstiness", self.receive_burstiness);
+
+            point.optional_counter("hub_received_messages", self.hub_received_messages);
+            point.optional_counter("hub_holes_created", self.hub_holes_created);
+            point.optional_counter("hub_holes_closed", self.hub_holes_closed);
+            point.optional_counter("hub_holes_timed_out", self.hub_holes_timed_out);
+            point.optional_gauge("hub_holes_current", self.hub_holes_current.map(|holes| holes as f64));
+            point.optional_counter("hub_policed_messages", self.hub_policed_messages);
+            point.optional_counter("hub_corrupted_messages", self.hub_corrupted_messages);
+
+            point.optional_counter("forward_lost_messages", self.forward_lost_messages);
+            point.optional_counter("reverse_lost_messages", self.reverse_lost_messages);
+            point.optional_counter("forward_reordered_messages", self.forward_reordered_messages);
+            point.optional_counter("reverse_reordered_messages", self.reverse_reordered_messages);
+
+            if let Some(source) = self.rx_timestamp_source {
+                point.text("rx_timestamp_source", &source.to_string());
+            }
+            point.optional_gauge("interval_duration", self.interval_duration);
+            point.gauge("interval_sent_messages", self.interval_sent_messages as f64);
+            point.gauge("interval_received_messages", self.interval_received_messages as f64);
+            point.gauge("interval_lost_messages", self.interval_lost_messages as f64);
+            point.optional_gauge("interval_loss_percent", self.interval_loss_percent);
+            point.optional_gauge("interval_rtt_min", self.interval_rtt_min);
+            point.optional_gauge("interval_rtt_avg", self.interval_rtt_avg);
+            point.optional_gauge("interval_rtt_max", self.interval_rtt_max);
+            point.optional_gauge("interval_received_pps", self.interval_received_pps);
+            point.gauge("interval_sent_bytes", self.interval_sent_bytes as f64);
+            point.gauge("interval_sent_ip_bytes", self.interval_sent_ip_bytes as f64);
+            point.gauge("interval_sent_wire_bytes", self.interval_sent_wire_bytes as f64);
+            point.optional_gauge("interval_sent_bps", self.interval_sent_bps);
+            point.optional_gauge("interval_sent_goodput_bps", self.interval_sent_goodput_bps);
+            point.gauge("interval_received_bytes", self.interval_received_bytes as f64);
+            point.gauge("interval_received_ip_bytes", self.interval_received_ip_bytes as f64);
+            point.gauge("interval_received_wire_bytes", self.interval_received_wire_bytes as f64);
+            point.optional_gauge("interval_received_bps", self.interval_received_bps);
+            point.optional_gauge("interval_received_goodput_bps", self.interval_received_goodput_bps);
+
+            if let Some(source) = self.rtt_timestamp_source {
+                point.text("rtt_timestamp_source", &source.to_string());
+            }
+            return vec![point];
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Clone)]
+    pub struct ServerSessionStatistics {
+        pub clocktower_type: std::string::String,
+        /// Version of this schema the record was written with, 0 for records from before it was versioned
+        #[serde(default)]
+        pub schema_version: u32,
+
+        #[serde(default = "default_tenant")]
+        pub tenant: std::string::String,
+        pub sid: std::string::String,
+        pub timestamp: f64,
+        /// Counts the reports of this session from 0, a gap means a report was lost
+        #[serde(default)]
+        pub stats_seq: u64,
+        /// Wall clock nanoseconds of the session's first message, changes when the counters were reset
+        #[serde(default)]
+        pub epoch: u64,
+        pub received_messages: u64,
+        pub holes_created: u64,
+        pub holes_closed: u64,
+        pub holes_timed_out: u64,
+        pub holes_current: u64,
+
+        pub received_bytes: u64,
+        #[serde(default)]
+        pub sent_messages: u64,
+        #[serde(default)]
+        pub sent_bytes: u64,
+
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub intermessage_gap_mavg: Option<f64>,
+
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub residence_time_mavg: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub residence_time_worst: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub residence_timestamp_source: Option<super::timestamping::TimestampSource>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub rx_timestamp_source: Option<super::timestamping::TimestampSource>,
+
+        /// Messages received in each window of the period over the last reporting interval
+        pub receive_time_windows: Vec<u64>,
+        /// Seconds the receive time windows divide between them
+        #[serde(default)]
+        pub receive_time_window_period: f64,
+        /// 0 when messages arrived evenly over the windows, 1 when all arrived in one of them
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub receive_burstiness: Option<f64>,
+
+        #[serde(default)]
+        pub rejected_reply_size: u64,
+        #[serde(default)]
+        pub rejected_amplification: u64,
+        #[serde(default)]
+        pub rejected_bandwidth: u64,
+        #[serde(default)]
+        pub rate_limited: u64,
+        #[serde(default)]
+        pub rejected_payload_size: u64,
+
+        #[serde(default)]
+        pub impairment_dropped: u64,
+        #[serde(default)]
+        pub impairment_rate_limited: u64,
+        #[serde(default)]
+        pub impairment_duplicated: u64,
+        #[serde(default)]
+        pub impairment_reordered: u64,
+
+        /// Pings whose payload did not match their checksum
+        #[serde(default)]
+        pub corrupted_messages: u64,
+
+        // Interval values cover the time since the previous report
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_duration: Option<f64>,
+        #[serde(default)]
+        pub interval_received_messages: u64,
+        #[serde(default)]
+        pub interval_sent_messages: u64,
+        /// Holes timed out during the interval
+        #[serde(default)]
+        pub interval_lost_messages: u64,
+        /// Lost out of the messages received or given up on during the interval
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_loss_percent: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_received_pps: Option<f64>,
+        // Payload, IP and estimated Ethernet bytes, and the rates they make on the wire and as payload
+        #[serde(default)]
+        pub interval_sent_bytes: u64,
+        #[serde(default)]
+        pub interval_sent_ip_bytes: u64,
+        #[serde(default)]
+        pub interval_sent_wire_bytes: u64,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_sent_bps: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_sent_goodput_bps: Option<f64>,
+        #[serde(default)]
+        pub interval_received_bytes: u64,
+        #[serde(default)]
+        pub interval_received_ip_bytes: u64,
+        #[serde(default)]
+        pub interval_received_wire_bytes: u64,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_received_bps: Option<f64>,
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub interval_received_goodput_bps: Option<f64>,
+
+        /// What each counter gained since the previous report, when enabled
+        #[serde(skip_serializing_if="Option::is_none")]
+        pub deltas: Option<std::collections::BTreeMap<std::string::String, u64>>,
+    }
+
+    impl ServerSessionStatistics {
+        /// `now` is the monotonic time the reporting interval ends
+        pub fn from_session_container(tenant: &str, sid: &str, session_container: &super::datatypes::SessionContainer, now: f64) -> ServerSessionStatistics {
+            let interval = &session_container.interval;
+            let sent = interval.throughput(interval.sent_messages, interval.sent_bytes, session_container.ip_overhead, now);
+            let received = interval.throughput(interval.received_messages, interval.received_bytes, session_container.ip_overhead, now);
+            return ServerSessionStatistics {
+                clocktower_type: HUB_SESSION_STATISTICS.to_string(),
+                schema_version: SCHEMA_VERSION,
+                tenant: tenant.to_string(),
+                sid: sid.to_string(),
+                timestamp: session_container.last_rx_wall,
+                stats_seq: session_container.stats_seq,
+                epoch: session_container.epoch,
+                received_messages: session_container.received_messages,
+                holes_created: session_container.holes_created,
+                holes_closed: session_container.holes_closed,
+                holes_timed_out: session_container.holes_timed_out,
+                holes_current: session_container.holes.len() as u64,
+
+                received_bytes: session_container.received_bytes,
+                sent_messages: session_container.sent_messages,
+                sent_bytes: session_container.sent_bytes,
+
+                intermessage_gap_mavg: session_container.intermessage_gap_mavg,
+
+                residence_time_mavg: session_container.residence_time_mavg,
+                residence_time_worst: session_container.residence_time_worst,
+                residence_timestamp_source: session_container.residence_timestamp_source,
+                rx_timestamp_source: session_container.rx_timestamp_source,
+
+                receive_time_windows: session_container.receive_time_windows.counts.clone(),
+                receive_time_window_period: session_container.receive_time_windows.period,
+                receive_burstiness: session_container.receive_time_windows.burstiness(),
+
+                rejected_reply_size: session_container.rejected_reply_size,
+                rejected_amplification: session_container.rejected_amplification,
+                rejected_bandwidth: session_container.rejected_bandwidth,
+                rate_limited: session_container.rate_limited,
+                rejected_payload_size: session_container.rejected_payload_size,
+
+                impairment_dropped: session_container.impairment_dropped,
+                impairment_rate_limited: session_container.impairment_rate_limited,
+                impairment_duplicated: session_container.impairment_duplicated,
+                impairment_reordered: session_container.impairment_reordered,
+
+                corrupted_messages: session_container.corrupted_messages,
+
+                interval_duration: interval.duration(now),
+                interval_received_messages: interval.received_messages,
+                interval_sent_messages: interval.sent_messages,
+                interval_lost_messages: interval.lost_messages,
+                interval_loss_percent: super::datatypes::IntervalAccumulator::loss_percent(interval.lost_messages, interval.received_messages),
+                interval_received_pps: interval.rate(interval.received_messages, now),
+                interval_sent_bytes: sent.bytes,
+                interval_sent_ip_bytes: sent.ip_bytes,
+                interval_sent_wire_bytes: sent.wire_bytes,
+                interval_sent_bps: sent.bps,
+                interval_sent_goodput_bps: sent.goodput_bps,
+                interval_received_bytes: received.bytes,
+                interval_received_ip_bytes: received.ip_bytes,
+                interval_received_wire_bytes: received.wire_bytes,
+                interval_received_bps: received.bps,
+                interval_received_goodput_bps: received.goodput_bps,
+
+                deltas: None,
+            }
+        }
+
+        /// The next report of a session, with deltas against the previous one if `stats_deltas` is set
+        pub fn next_report(tenant: &str, sid: &str, session_container: &mut super::datatypes::SessionContainer, stats_deltas: bool, now: f64) -> ServerSessionStatistics {
+            let mut statistics = ServerSessionStatistics::from_session_container(tenant, sid, session_container, now);
+            if stats_deltas {
+                statistics.deltas = Some(session_container.counter_baseline.deltas(&statistics));
+            }
+            session_container.stats_seq += 1;
+            session_container.receive_time_windows.reset();
+            session_container.interval.restart(now);
+            return statistics;
+        }
     }
-}
\ No newline at end of file
+
+    impl super::stats::StatisticsRecord for ServerSessionStatistics {
+        fn to_json(&self) -> Result<std::string::String, serde_json::Error> {
+            return serde_json::to_string(self);
+        }
+
+        fn points(&self) -> Vec<super::stats::StatisticsPoint> {
+            let mut point = super::stats::StatisticsPoint::new("metronome_hub_session", self.timestamp);
+            if self.epoch != 0 {
+                point.start = Some(self.epoch as f64 / 1e9);
+            }
+            point.tag("tenant", &self.tenant);
+            point.tag("sid", &self.sid);
+
+            point.counter("received_messages", self.received_messages);
+            point.counter("holes_created", self.holes_created);
+            point.counter("holes_closed", self.holes_closed);
+            point.counter("holes_timed_out", self.holes_timed_out);
+            point.gauge("holes_current", self.holes_current as f64);
+            point.counter("received_bytes", self.received_bytes);
+            point.counter("sent_messages", self.sent_messages);
+            point.counter("sent_bytes", self.sent_bytes);
+
+            point.optional_gauge("intermessage_gap_mavg", self.intermessage_gap_mavg);
+            point.optional_gauge("residence_time_mavg", self.residence_time_mavg);
+            point.optional_gauge("residence_time_worst", self.residence_time_worst);
+
+            for (window, count) in self.receive_time_windows.iter().enumerate() {
+                point.gauge(&format!("receive_time_window_{}", window), *count as f64);
+            }
+            point.optional_gauge("receive_burstiness", self.receive_burstiness);
+
+            point.counter("rejected_reply_size", self.rejected_reply_size);
+            point.counter("rejected_amplification", self.rejected_amplification);
+            point.counter("rejected_bandwidth", self.rejected_bandwidth);
+            point.counter("rate_limited", self.rate_limited);
+            point.counter("rejected_payload_size", self.rejected_payload_size);
+
+            point.counter("impairment_dropped", self.impairment_dropped);
+            point.counter("impairment_rate_limited", self.impairment_rate_limited);
+            point.counter("impairment_duplicated", self.impairment_duplicated);
+            point.counter("impairment_reordered", self.impairment_reordered);
+
+            point.counter("corrupted_messages", self.corrupted_messages);
+
+            point.optional_gauge("interval_duration", self.interval_duration);
+            point.gauge("interval_received_messages", self.interval_received_messages as f64);
+            point.gauge("interval_sent_messages", self.interval_sent_messages as f64);
+            point.gauge("interval_lost_messages", self.interval_lost_messages as f64);
+            point.optional_gauge("interval_loss_percent", self.interval_loss_percent);
+            point.optional_gauge("interval_received_pps", self.interval_received_pps);
+            point.gauge("interval_sent_bytes", self.interval_sent_bytes as f64);
+            point.gauge("interval_sent_ip_bytes", self.interval_sent_ip_bytes as f64);
+            point.gauge("interval_sent_wire_bytes", self.interval_sent_wire_bytes as f64);
+            point.optional_gauge("interval_sent_bps", self.interval_sent_bps);
+            point.optional_gauge("interval_sent_goodput_bps", self.interval_sent_goodput_bps);
+            point.gauge("interval_received_bytes", self.interval_received_bytes as f64);
+            point.gauge("interval_received_ip_bytes", self.interval_received_ip_bytes as f64);
+            point.gauge("interval_received_wire_bytes", self.interval_received_wire_bytes as f64);
+            point.optional_gauge("interval_received_bps", self.interval_received_bps);
+            point.optional_gauge("interval_received_goodput_bps", self.interval_received_goodput_bps);
+
+            if let Some(source) = self.residence_timestamp_source {
+                point.text("residence_timestamp_source", &source.to_string());
+            }
+            if let Some(source) = self.rx_timestamp_source {
+                point.text("rx_timestamp_source", &source.to_string());
+            }
+            return vec![point];
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Clone)]
+    pub struct AclRuleStatistics {
+        pub action: std::string::String,
+        pub prefix: std::string::String,
+        pub hits: u64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Clone)]
+    pub struct HubAclStatistics {
+        pub clocktower_type: std::string::String,
+        /// Version of this schema the record was written with, 0 for records from before it was versioned
+        #[serde(default)]
+        pub schema_version: u32,
+
+        pub timestamp: f64,
+        pub rules: Vec<AclRuleStatistics>,
+        pub default_action: std::string::String,
+        pub default_hits: u64,
+    }
+
+    impl HubAclStatistics {
+        pub fn new(timestamp: f64, rules: Vec<AclRuleStatistics>, default_action: &str, default_hits: u64) -> HubAclStatistics {
+            return HubAclStatistics {
+                clocktower_type: HUB_ACL_STATISTICS.to_string(),
+                schema_version: SCHEMA_VERSION,
+                timestamp: timestamp,
+                rules: rules,
+                default_action: default_action.to_string(),
+                default_hits: default_hits,
+            };
+        }
+    }
+
+    impl super::stats::StatisticsRecord for HubAclStatistics {
+        fn to_json(&self) -> Result<std::string::String, serde_json::Error> {
+            return serde_json::to_string(self);
+        }
+
+        /// One point per rule and one for the default action, tagged with the action and prefix
+        fn points(&self) -> Vec<super::stats::StatisticsPoint> {
+            let mut points: Vec<super::stats::StatisticsPoint> = Vec::new();
+            for rule in self.rules.iter() {
+                let mut point = super::stats::StatisticsPoint::new("metronome_hub_acl", self.timestamp);
+                point.tag("action", &rule.action);
+                point.tag("prefix", &rule.prefix);
+                point.counter("hits", rule.hits);
+                points.push(point);
+            }
+            let mut point = super::stats::StatisticsPoint::new("metronome_hub_acl", self.timestamp);
+            point.tag("action", &self.default_action);
+            point.tag("prefix", "default");
+            point.counter("hits", self.default_hits);
+            points.push(point);
+            return points;
+        }
+    }
+
+    /// Any record a consumer may receive, told apart by its `clocktower_type`
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum Statistics {
+        ClientSession(Box<ClientSessionStatistics>),
+        HubSession(Box<ServerSessionStatistics>),
+        HubAcl(HubAclStatistics),
+    }
+
+    impl Statistics {
+        pub fn from_json(json: &str) -> Result<Statistics, std::string::String> {
+            let value: serde_json::Value = serde_json::from_str(json).map_err(|e| format!("invalid JSON: {}", e))?;
+            let clocktower_type = value.get("clocktower_type").and_then(|clocktower_type| clocktower_type.as_str()).unwrap_or("").to_string();
+            let record = match clocktower_type.as_str() {
+                CLIENT_SESSION_STATISTICS => serde_json::from_value(value).map(|statistics| Statistics::ClientSession(Box::new(statistics))),
+                HUB_SESSION_STATISTICS => serde_json::from_value(value).map(|statistics| Statistics::HubSession(Box::new(statistics))),
+                HUB_ACL_STATISTICS => serde_json::from_value(value).map(Statistics::HubAcl),
+                _ => return Err(format!("unknown clocktower_type {:?}", clocktower_type)),
+            };
+            return record.map_err(|e| format!("invalid {}: {}", clocktower_type, e));
+        }
+
+        pub fn record(&self) -> &dyn super::stats::StatisticsRecord {
+            match self {
+                Statistics::ClientSession(statistics) => return statistics.as_ref(),
+                Statistics::HubSession(statistics) => return statistics.as_ref(),
+                Statistics::HubAcl(statistics) => return statistics,
+            }
+        }
+    }
+
+    /// JSON Schema (draft 2020-12) a record must match, one of the record types picked by `clocktower_type`
+    pub fn json_schema() -> serde_json::Value {
+        let mut generator = schemars::generate::SchemaSettings::draft2020_12().into_generator();
+        let records = vec![
+            (CLIENT_SESSION_STATISTICS, generator.subschema_for::<ClientSessionStatistics>()),
+            (HUB_SESSION_STATISTICS, generator.subschema_for::<ServerSessionStatistics>()),
+            (HUB_ACL_STATISTICS, generator.subschema_for::<HubAclStatistics>()),
+        ];
+        let mut definitions = generator.take_definitions(true);
+        let mut one_of: Vec<serde_json::Value> = Vec::new();
+        for (clocktower_type, reference) in records {
+            let reference = reference.to_value();
+            // The type name alone picks the record, so pin it instead of accepting any string
+            let name = reference["$ref"].as_str().and_then(|path| path.rsplit('/').next()).unwrap_or("");
+            if let Some(definition) = definitions.get_mut(name) {
+                definition["properties"]["clocktower_type"] = serde_json::json!({ "const": clocktower_type });
+            }
+            one_of.push(reference);
+        }
+        return serde_json::json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": "metronome statistics record",
+            "description": format!("Statistics records of schema version {}", SCHEMA_VERSION),
+            "oneOf": one_of,
+            "$defs": definitions,
+        });
+    }
+}
+
+/// Client side session state, kept here so it can be driven by the simulation as well as the client
+pub mod client {
+    pub use super::schema::ClientSessionStatistics;
+
+    pub struct ClientSessionTracker {
+        pub epoch: u64,
+        pub stats_seq: u64,
+        /// Counters as of the previous report, None unless deltas are reported
+        pub counter_baseline: Option<super::stats::CounterBaseline>,
+
+        pub last_rx: Option<f64>,
+        pub last_tx: Option<f64>,
+        
+        pub last_rx_seq: Option<u64>,
+        pub next_expected_seq: u64,
+        pub max_seq: u64,
+
+        pub seq_unexpected_increment: u64,
+        pub seq_unexpected_decrement: u64,
+
+        pub sent_messages: u64,
+        pub received_messages: u64,
+        pub timely_received_messages: u64,
+        
+        pub lost_messages: u64,
+        pub inflight_messages: u64,
+
+        pub received_bytes: u64,
+        pub sent_bytes: u64,
+        /// IP and UDP header bytes per datagram, by the address family of the hub
+        pub ip_overhead: u64,
+        pub corrupted_messages: u64,
+
+        pub rtt_worst: Option<f64>,
+        pub rtt_best: Option<f64>,
+        pub rtt_mavg: Option<f64>,
+        /// Every RTT since the session started
+        pub rtt_histogram: super::histogram::Histogram,
+
+        pub intermessage_gap_mavg: Option<f64>,
+
+        pub send_error_mavg: Option<f64>,
+        pub send_error_worst: Option<f64>,
+        /// Send errors since the previous report
+        pub send_lateness: super::histogram::Histogram,
+        pub send_skipped: u64,
+        pub send_catch_up: u64,
+
+        pub receive_time_windows: super::datatypes::ReceiveTimeWindows,
+        pub interval: super::datatypes::IntervalAccumulator,
+
+        pub hub_counters: Option<super::datatypes::HubSessionCounters>,
+        pub hub_counters_seq: u64,
+
+        pub rx_timestamp_source: Option<super::timestamping::TimestampSource>,
+        pub rtt_timestamp_source: Option<super::timestamping::TimestampSource>,
+    }
+
+    impl ClientSessionTracker {
+        pub fn new() -> ClientSessionTracker {
+            return ClientSessionTracker {
+                epoch: 0,
+                stats_seq: 0,
+                counter_baseline: None,
+
+                last_rx: None,
+                last_tx: None,
+                
+                last_rx_seq: None,
+                next_expected_seq: 0,
+                max_seq: 0,
+                seq_unexpected_increment: 0,
+                seq_unexpected_decrement: 0,
+
+                sent_messages: 0,
+                received_messages: 0,
+                timely_received_messages: 0,
+
+                lost_messages: 0,
+                inflight_messages: 0,
+
+                received_bytes: 0,
+                sent_bytes: 0,
+                ip_overhead: super::datatypes::UDP_HEADER_BYTES + super::datatypes::IPV4_HEADER_BYTES,
+                corrupted_messages: 0,
+
+                rtt_worst: None,
+                rtt_best: None,
+                rtt_mavg: None,
+                rtt_histogram: super::histogram::Histogram::new(),
+
+                intermessage_gap_mavg: None,
+
+                send_error_mavg: None,
+                send_error_worst: None,
+                send_lateness: super::histogram::Histogram::new(),
+                send_skipped: 0,
+                send_catch_up: 0,
+
+                receive_time_windows: super::datatypes::ReceiveTimeWindows::default(),
+                interval: super::datatypes::IntervalAccumulator::new(None),
+
+                hub_counters: None,
+                hub_counters_seq: 0,
+
+                rx_timestamp_source: None,
+                rtt_timestamp_source: None,
+            };
+        }
+
+        pub fn outgoing(&mut self, timestamp: f64) {
+            self.outgoing_many(1, timestamp, timestamp);
+        }
+
+        /// Counts `count` pings at once, sent between the monotonic `first` and `last`
+        pub fn outgoing_many(&mut self, count: u64, first: f64, last: f64) {
+            self.last_tx = Some(last);
+            self.sent_messages += count;
+            self.inflight_messages += count;
+            self.interval.seen(first);
+            self.interval.sent_messages += count;
+        }
+
+        pub fn incoming(&mut self, rx_timestamps: &super::timestamping::Timestamps, seq: u64, received_bytes: usize) {
+            // Gaps are measured on the monotonic clock, receive time windows follow the wall clock
+            let timestamp = rx_timestamps.event_monotonic();
+            let wall_timestamp = rx_timestamps.event_wall();
+            if let Some(last_rx_timestamp) = self.last_rx {
+                if timestamp > last_rx_timestamp {
+                    if let Some(current_intermessage_gap) = self.intermessage_gap_mavg {
+                        self.intermessage_gap_mavg = Some(((current_intermessage_gap * 9.0) + ((timestamp - last_rx_timestamp) * 1.0)) / 10.0);
+                    } else {
+                        self.intermessage_gap_mavg = Some(timestamp - last_rx_timestamp);
+                    }
+                }
+            }
+            if self.last_rx_seq.is_some() {
+                if seq == self.next_expected_seq {
+                    // All good, we are receiving the frame we though we were going to get
+                } else if seq > self.next_expected_seq && seq < (self.max_seq + 1) {
+                    // If sequence number is greater than next expected but smaller or equal than maximum seen
+                    // we can assume we are seeing reordered messages
+                    self.seq_unexpected_increment += 1;
+                } else if seq < self.next_expected_seq {
+                    // If sequence number is smaller than we expect, assume we are seeing reordered (older) frames
+                    self.seq_unexpected_decrement += 1;
+                }
+            } else {
+                self.last_rx_seq = Some(seq);
+            }
+            self.last_rx = Some(timestamp);
+            self.received_bytes += received_bytes as u64;
+            self.received_messages += 1;
+            self.interval.seen(timestamp);
+            self.interval.received_messages += 1;
+            self.interval.received_bytes += received_bytes as u64;
+            self.next_expected_seq = seq + 1;
+            self.last_rx_seq = Some(seq);
+            self.max_seq = self.max_seq.max(seq);
+            self.receive_time_windows.record(wall_timestamp);
+        }
+
+        pub fn hub_counters_update(&mut self, seq: u64, hub_counters: &super::datatypes::HubSessionCounters) {
+            // Pongs may arrive reordered, only keep the counters from the most recent ping the hub saw
+            if self.hub_counters.is_none() || seq >= self.hub_counters_seq {
+                self.hub_counters = Some(*hub_counters);
+                self.hub_counters_seq = seq;
+            }
+        }
+
+        pub fn rtt_timeout(&mut self) {
+            self.inflight_messages = self.inflight_messages.saturating_sub(1);
+            self.lost_messages += 1;
+            self.interval.lost_messages += 1;
+        }
+
+        pub fn rtt_success(&mut self, sent: &super::timestamping::Timestamps, recv: &super::timestamping::Timestamps) {
+            self.inflight_messages = self.inflight_messages.saturating_sub(1);
+            self.timely_received_messages += 1;
+            let (rtt, source) = sent.elapsed_until(recv);
+            self.rtt_timestamp_source = Some(source);
+            self.rtt_histogram.record(rtt);
+            self.interval.rtt(rtt);
+            
+            if let Some(rtt_worst) = self.rtt_worst {
+                self.rtt_worst = Some(rtt_worst.max(rtt));
+            } else {
+                self.rtt_worst = Some(rtt);
+            }
+            
+            if let Some(rtt_best) = self.rtt_best {
+                self.rtt_best = Some(rtt_best.min(rtt));
+            } else {
+                self.rtt_best = Some(rtt);
+            }
+
+            if let Some(rtt_mavg) = self.rtt_mavg {
+                self.rtt_mavg = Some((rtt_mavg * 9.0 + rtt) / 10.0);
+            } else {
+                self.rtt_mavg = Some(rtt);
+            }
+        }
+    }
+
+    impl Default for ClientSessionTracker {
+        fn default() -> ClientSessionTracker {
+            return ClientSessionTracker::new();
+        }
+    }
+
+    impl ClientSessionStatistics {
+        /// `now` is the monotonic time the reporting interval ends
+        pub fn from_session_tracker(timestamp: f64, sid: &str, st: &ClientSessionTracker, now: f64) -> ClientSessionStatistics {
+            let hub_counters = st.hub_counters.as_ref();
+
+            // Holes the hub gave up on never reached it, pings the hub policed reached it but were never answered
+            // and the remainder of our losses happened on the way back.
+            // Holes the hub closed were reordered on the way there and show up as decrements here as well.
+            let forward_lost_messages = hub_counters.map(|hc| hc.holes_timed_out);
+            let reverse_lost_messages = hub_counters.map(|hc| st.lost_messages.saturating_sub(hc.holes_timed_out + hc.policed_messages));
+            let forward_reordered_messages = hub_counters.map(|hc| hc.holes_closed);
+            let reverse_reordered_messages = forward_reordered_messages.map(|fr| st.seq_unexpected_decrement.saturating_sub(fr));
+
+            let sent = st.interval.throughput(st.interval.sent_messages, st.interval.sent_bytes, st.ip_overhead, now);
+            let received = st.interval.throughput(st.interval.received_messages, st.interval.received_bytes, st.ip_overhead, now);
+
+            return ClientSessionStatistics {
+                clocktower_type: super::schema::CLIENT_SESSION_STATISTICS.to_string(),
+                schema_version: super::schema::SCHEMA_VERSION,
+                sid: sid.to_string(),
+                timestamp: timestamp,
+                stats_seq: st.stats_seq,
+                epoch: st.epoch,
+                
+                seq_unexpected_decrement: st.seq_unexpected_decrement,
+                seq_unexpected_increment: st.seq_unexpected_increment,
+
+                sent_messages: st.sent_messages,
+                received_messages: st.received_messages,
+                timely_received_messages: st.timely_received_messages,
+
+                lost_messages: st.lost_messages,
+                inflight_messages: st.inflight_messages,
+
+                received_bytes: st.received_bytes,
+                sent_bytes: st.sent_bytes,
+                corrupted_messages: st.corrupted_messages,
+
+                rtt_worst: st.rtt_worst,
+                rtt_best: st.rtt_best,
+                rtt_mavg: st.rtt_mavg,
+
+                intermessage_gap_mavg: st.intermessage_gap_mavg,
+
+                send_error_mavg: st.send_error_mavg,
+                send_error_worst: st.send_error_worst,
+                send_skipped: st.send_skipped,
+                send_catch_up: st.send_catch_up,
+                send_lateness_p50: st.send_lateness.quantile(0.5),
+                send_lateness_p90: st.send_lateness.quantile(0.9),
+                send_lateness_p99: st.send_lateness.quantile(0.99),
+                send_lateness_p999: st.send_lateness.quantile(0.999),
+                send_lateness_max: st.send_lateness.max(),
+
+                receive_time_windows: st.receive_time_windows.counts.clone(),
+                receive_time_window_period: st.receive_time_windows.period,
+                receive_burstiness: st.receive_time_windows.burstiness(),
+
+                hub_received_messages: hub_counters.map(|hc| hc.received_messages),
+                hub_holes_created: hub_counters.map(|hc| hc.holes_created),
+                hub_holes_closed: hub_counters.map(|hc| hc.holes_closed),
+                hub_holes_timed_out: hub_counters.map(|hc| hc.holes_timed_out),
+                hub_holes_current: hub_counters.map(|hc| hc.holes_current),
+                hub_policed_messages: hub_counters.map(|hc| hc.policed_messages),
+                hub_corrupted_messages: hub_counters.map(|hc| hc.corrupted_messages),
+
+                forward_lost_messages: forward_lost_messages,
+                reverse_lost_messages: reverse_lost_messages,
+                forward_reordered_messages: forward_reordered_messages,
+                reverse_reordered_messages: reverse_reordered_messages,
+
+                rx_timestamp_source: st.rx_timestamp_source,
+                rtt_timestamp_source: st.rtt_timestamp_source,
+
+                interval_duration: st.interval.duration(now),
+                interval_sent_messages: st.interval.sent_messages,
+                interval_received_messages: st.interval.received_messages,
+                interval_lost_messages: st.interval.lost_messages,
+                interval_loss_percent: super::datatypes::IntervalAccumulator::loss_percent(st.interval.lost_messages, st.interval.rtt_count),
+                interval_rtt_min: st.interval.rtt_min,
+                interval_rtt_avg: st.interval.rtt_avg(),
+                interval_rtt_max: st.interval.rtt_max,
+                interval_received_pps: st.interval.rate(st.interval.received_messages, now),
+                interval_sent_bytes: sent.bytes,
+                interval_sent_ip_bytes: sent.ip_bytes,
+                interval_sent_wire_bytes: sent.wire_bytes,
+                interval_sent_bps: sent.bps,
+                interval_sent_goodput_bps: sent.goodput_bps,
+                interval_received_bytes: received.bytes,
+                interval_received_ip_bytes: received.ip_bytes,
+                interval_received_wire_bytes: received.wire_bytes,
+                interval_received_bps: received.bps,
+                interval_received_goodput_bps: received.goodput_bps,
+
+                deltas: None,
+
+                rtt_histogram: st.rtt_histogram.clone(),
+            }
+        }
+
+    }
+
+    pub struct RTTMeasurement {
+        pub seq: u64,
+        pub timestamps: super::timestamping::Timestamps,
+        /// Monotonic time the ping was due to be sent
+        pub scheduled: f64,
+        /// Sent in the same wakeup as an earlier ping because the sender had fallen behind
+        pub catch_up: bool,
+        /// Sends given up just before this one because the sender had fallen too far behind
+        pub skipped: u64,
+        pub message_raw_size: usize,
+    }
+
+    // Slot states besides the sequence number of the ping held
+    const SLOT_EMPTY: u64 = u64::MAX;
+    const SLOT_BUSY: u64 = u64::MAX - 1;
+    const SLOT_DONE: u64 = u64::MAX - 2;
+    // A NaN pattern standing for a missing kernel timestamp
+    const NO_TIMESTAMP: u64 = u64::MAX;
+
+    struct InflightSlot {
+        state: std::sync::atomic::AtomicU64,
+        // Odd while the timestamps are being written
+        version: std::sync::atomic::AtomicU64,
+        monotonic: std::sync::atomic::AtomicU64,
+        wall: std::sync::atomic::AtomicU64,
+        software: std::sync::atomic::AtomicU64,
+        hardware: std::sync::atomic::AtomicU64,
+    }
+
+    impl InflightSlot {
+        fn new() -> InflightSlot {
+            return InflightSlot {
+                state: std::sync::atomic::AtomicU64::new(SLOT_EMPTY),
+                version: std::sync::atomic::AtomicU64::new(0),
+                monotonic: std::sync::atomic::AtomicU64::new(0),
+                wall: std::sync::atomic::AtomicU64::new(0),
+                software: std::sync::atomic::AtomicU64::new(NO_TIMESTAMP),
+                hardware: std::sync::atomic::AtomicU64::new(NO_TIMESTAMP),
+            };
+        }
+
+        /// Only called with the slot in SLOT_BUSY
+        fn write(&self, timestamps: &super::timestamping::Timestamps) {
+            let version = self.version.load(std::sync::atomic::Ordering::Relaxed);
+            self.version.store(version + 1, std::sync::atomic::Ordering::Relaxed);
+            std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+            self.monotonic.store(timestamps.monotonic.to_bits(), std::sync::atomic::Ordering::Relaxed);
+            self.wall.store(timestamps.wall.to_bits(), std::sync::atomic::Ordering::Relaxed);
+            self.software.store(timestamps.software.map_or(NO_TIMESTAMP, f64::to_bits), std::sync::atomic::Ordering::Relaxed);
+            self.hardware.store(timestamps.hardware.map_or(NO_TIMESTAMP, f64::to_bits), std::sync::atomic::Ordering::Relaxed);
+            self.version.store(version + 2, std::sync::atomic::Ordering::Release);
+        }
+
+        /// A consistent copy of the timestamps, None while they are being written
+        fn read(&self) -> Option<super::timestamping::Timestamps> {
+            let version = self.version.load(std::sync::atomic::Ordering::Acquire);
+            if version % 2 == 1 {
+                return None;
+            }
+            let software = self.software.load(std::sync::atomic::Ordering::Relaxed);
+            let hardware = self.hardware.load(std::sync::atomic::Ordering::Relaxed);
+            let timestamps = super::timestamping::Timestamps {
+                monotonic: f64::from_bits(self.monotonic.load(std::sync::atomic::Ordering::Relaxed)),
+                wall: f64::from_bits(self.wall.load(std::sync::atomic::Ordering::Relaxed)),
+                software: if software == NO_TIMESTAMP { None } else { Some(f64::from_bits(software)) },
+                hardware: if hardware == NO_TIMESTAMP { None } else { Some(f64::from_bits(hardware)) },
+            };
+            std::sync::atomic::fence(std::sync::atomic::Ordering::Acquire);
+            if self.version.load(std::sync::atomic::Ordering::Relaxed) != version {
+                return None;
+            }
+            return Some(timestamps);
+        }
+    }
+
+    /// What the sending thread shares with the receiving one, without locks or per packet channel
+    /// messages: a ring of pings in flight indexed by sequence number and counters of how well the
+    /// send schedule was kept. Only one thread may send and only one may receive.
+    pub struct SentPings {
+        slots: Vec<InflightSlot>,
+        mask: u64,
+        // Pings with a lower sequence number have been handed to the socket
+        published: std::sync::atomic::AtomicU64,
+        // Pings the socket accepted, failed sends are left out
+        sent_messages: std::sync::atomic::AtomicU64,
+        sent_bytes: std::sync::atomic::AtomicU64,
+        first_sent_at: std::sync::atomic::AtomicU64,
+        last_sent_at: std::sync::atomic::AtomicU64,
+        // Unanswered pings overwritten by newer ones before they could expire
+        evicted: std::sync::atomic::AtomicU64,
+        skipped: std::sync::atomic::AtomicU64,
+        catch_up: std::sync::atomic::AtomicU64,
+        send_error_mavg: std::sync::atomic::AtomicU64,
+        send_error_worst: std::sync::atomic::AtomicU64,
+        send_lateness: super::histogram::AtomicHistogram,
+    }
+
+    impl SentPings {
+        /// `capacity` is rounded up to a power of two and should hold every ping sent within the RTT timeout
+        pub fn new(capacity: usize) -> SentPings {
+            let capacity = capacity.max(2).next_power_of_two();
+            return SentPings {
+                slots: (0..capacity).map(|_| InflightSlot::new()).collect(),
+                mask: capacity as u64 - 1,
+                published: std::sync::atomic::AtomicU64::new(0),
+                sent_messages: std::sync::atomic::AtomicU64::new(0),
+                sent_bytes: std::sync::atomic::AtomicU64::new(0),
+                first_sent_at: std::sync::atomic::AtomicU64::new(NO_TIMESTAMP),
+                last_sent_at: std::sync::atomic::AtomicU64::new(NO_TIMESTAMP),
+                evicted: std::sync::atomic::AtomicU64::new(0),
+                skipped: std::sync::atomic::AtomicU64::new(0),
+                catch_up: std::sync::atomic::AtomicU64::new(0),
+                send_error_mavg: std::sync::atomic::AtomicU64::new(NO_TIMESTAMP),
+                send_error_worst: std::sync::atomic::AtomicU64::new(NO_TIMESTAMP),
+                send_lateness: super::histogram::AtomicHistogram::new(),
+            };
+        }
+
+        fn slot(&self, seq: u64) -> &InflightSlot {
+            return &self.slots[(seq & self.mask) as usize];
+        }
+
+        fn load_optional(value: &std::sync::atomic::AtomicU64) -> Option<f64> {
+            let bits = value.load(std::sync::atomic::Ordering::Relaxed);
+            if bits == NO_TIMESTAMP {
+                return None;
+            }
+            return Some(f64::from_bits(bits));
+        }
+
+        /// Puts a ping in flight just before it is handed to the socket, so a fast reply always finds it
+        pub fn sending(&self, rtt_measurement: &RTTMeasurement) {
+            let slot = self.slot(rtt_measurement.seq);
+            let previous = slot.state.swap(SLOT_BUSY, std::sync::atomic::Ordering::AcqRel);
+            if previous < SLOT_DONE {
+                self.evicted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            slot.write(&rtt_measurement.timestamps);
+            slot.state.store(rtt_measurement.seq, std::sync::atomic::Ordering::Release);
+        }
+
+        pub fn send_failed(&self, seq: u64) {
+            // The sequence number is used again for the next ping
+            let _ = self.slot(seq).state.compare_exchange(seq, SLOT_EMPTY, std::sync::atomic::Ordering::AcqRel, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        /// Counts a ping the socket accepted, along with how late it went out
+        pub fn sent(&self, rtt_measurement: &RTTMeasurement) {
+            let send_error = rtt_measurement.timestamps.monotonic - rtt_measurement.scheduled;
+            // Only the sending thread writes these, plain loads and stores are enough
+            let send_error_mavg = match SentPings::load_optional(&self.send_error_mavg) {
+                Some(send_error_mavg) => (send_error_mavg * 9.0 + send_error) / 10.0,
+                None => send_error,
+            };
+            self.send_error_mavg.store(send_error_mavg.to_bits(), std::sync::atomic::Ordering::Relaxed);
+            if SentPings::load_optional(&self.send_error_worst).is_none_or(|worst| send_error > worst) {
+                self.send_error_worst.store(send_error.to_bits(), std::sync::atomic::Ordering::Relaxed);
+            }
+            self.send_lateness.record(send_error);
+            self.skipped.fetch_add(rtt_measurement.skipped, std::sync::atomic::Ordering::Relaxed);
+            if rtt_measurement.catch_up {
+                self.catch_up.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            if SentPings::load_optional(&self.first_sent_at).is_none() {
+                self.first_sent_at.store(rtt_measurement.timestamps.monotonic.to_bits(), std::sync::atomic::Ordering::Relaxed);
+            }
+            self.last_sent_at.store(rtt_measurement.timestamps.monotonic.to_bits(), std::sync::atomic::Ordering::Relaxed);
+            self.sent_bytes.fetch_add(rtt_measurement.message_raw_size as u64, std::sync::atomic::Ordering::Relaxed);
+            self.sent_messages.fetch_add(1, std::sync::atomic::Ordering::Release);
+            self.published.store(rtt_measurement.seq + 1, std::sync::atomic::Ordering::Release);
+        }
+
+        /// Replaces the send timestamps of a ping still in flight with its transmit timestamps
+        pub fn tx_timestamp(&self, seq: u64, tx_timestamps: &super::timestamping::Timestamps) {
+            let slot = self.slot(seq);
+            if slot.state.compare_exchange(seq, SLOT_BUSY, std::sync::atomic::Ordering::AcqRel, std::sync::atomic::Ordering::Relaxed).is_ok() {
+                slot.write(tx_timestamps);
+                slot.state.store(seq, std::sync::atomic::Ordering::Release);
+            }
+        }
+
+        pub fn published(&self) -> u64 {
+            return self.published.load(std::sync::atomic::Ordering::Acquire);
+        }
+
+        pub fn sent_messages(&self) -> u64 {
+            return self.sent_messages.load(std::sync::atomic::Ordering::Acquire);
+        }
+
+        pub fn sent_bytes(&self) -> u64 {
+            return self.sent_bytes.load(std::sync::atomic::Ordering::Relaxed);
+        }
+
+        /// Send timestamps of an answered ping, None if it is not in flight anymore
+        fn take(&self, seq: u64) -> Option<super::timestamping::Timestamps> {
+            let slot = self.slot(seq);
+            loop {
+                let state = slot.state.load(std::sync::atomic::Ordering::Acquire);
+                if state == SLOT_BUSY {
+                    std::hint::spin_loop();
+                    continue;
+                }
+                if state != seq {
+                    return None;
+                }
+                if let Some(timestamps) = slot.read() {
+                    // Losing this exchange means the ping expired or was overwritten since it was read
+                    if slot.state.compare_exchange(seq, SLOT_DONE, std::sync::atomic::Ordering::AcqRel, std::sync::atomic::Ordering::Relaxed).is_ok() {
+                        return Some(timestamps);
+                    }
+                }
+            }
+        }
+
+        /// Moves `cursor` over the pings that are no longer in flight, expiring those sent before
+        /// `deadline`. Stops at the first ping still waiting, so each ping is visited once.
+        fn expire(&self, cursor: &mut u64, deadline: f64) -> u64 {
+            let published = self.published();
+            let mut expired: u64 = 0;
+            // Anything older than the ring has been overwritten and counted as evicted
+            *cursor = (*cursor).max(published.saturating_sub(self.mask + 1));
+            while *cursor < published {
+                let slot = self.slot(*cursor);
+                let state = slot.state.load(std::sync::atomic::Ordering::Acquire);
+                if state == *cursor {
+                    let sent_at = f64::from_bits(slot.monotonic.load(std::sync::atomic::Ordering::Relaxed));
+                    if sent_at >= deadline {
+                        break;
+                    }
+                    if slot.state.compare_exchange(state, SLOT_DONE, std::sync::atomic::Ordering::AcqRel, std::sync::atomic::Ordering::Relaxed).is_err() {
+                        break;
+                    }
+                    expired += 1;
+                } else if state == SLOT_BUSY {
+                    break;
+                }
+                *cursor += 1;
+            }
+            return expired;
+        }
+    }
+
+    /// Session state of the receiving thread, fed by the pongs it reads and the pings the sending thread publishes
+    pub struct ClientSession {
+        pub tracker: ClientSessionTracker,
+        sent_pings: std::sync::Arc<SentPings>,
+        expiry_cursor: u64,
+        evicted_counted: u64,
+    }
+
+    impl ClientSession {
+        /// `started` is the wall clock time the counters start from, it becomes the reporting epoch
+        pub fn new(sent_pings: std::sync::Arc<SentPings>, started: f64) -> ClientSession {
+            let mut tracker = ClientSessionTracker::new();
+            tracker.epoch = (started * 1e9) as u64;
+            return ClientSession {
+                tracker: tracker,
+                sent_pings: sent_pings,
+                expiry_cursor: 0,
+                evicted_counted: 0,
+            };
+        }
+
+        /// Adds what each counter gained since the previous report to every report
+        pub fn report_deltas(&mut self) {
+            self.tracker.counter_baseline = Some(super::stats::CounterBaseline::new());
+        }
+
+        /// Catches up with the pings the sending thread has counted so far
+        fn sync_sent(&mut self) {
+            let sent_messages = self.sent_pings.sent_messages();
+            if sent_messages > self.tracker.sent_messages {
+                let first_sent_at = SentPings::load_optional(&self.sent_pings.first_sent_at).unwrap_or(0.0);
+                let last_sent_at = SentPings::load_optional(&self.sent_pings.last_sent_at).unwrap_or(0.0);
+                self.tracker.outgoing_many(sent_messages - self.tracker.sent_messages, first_sent_at, last_sent_at);
+            }
+            let sent_bytes = self.sent_pings.sent_bytes();
+            self.tracker.interval.sent_bytes += sent_bytes - self.tracker.sent_bytes;
+            self.tracker.sent_bytes = sent_bytes;
+        }
+
+        pub fn received(&mut self, timestamped_message: &super::datatypes::TimestampedMessage) {
+            let message = &timestamped_message.message_with_size.message;
+            self.tracker.incoming(&timestamped_message.timestamps, message.seq, timestamped_message.message_with_size.message_raw_size);
+            self.tracker.rx_timestamp_source = Some(timestamped_message.timestamps.best_source());
+            if message.payload_intact() == Some(false) {
+                self.tracker.corrupted_messages += 1;
+            }
+            if let Some(hub_counters) = &message.hub_stats {
+                self.tracker.hub_counters_update(message.seq, hub_counters);
+            }
+            if let Some(sent_timestamps) = self.sent_pings.take(message.seq) {
+                self.sync_sent();
+                if self.tracker.inflight_messages == 0 {
+                    // The reply beat the sending thread to counting its ping, count it ahead
+                    self.tracker.outgoing(sent_timestamps.monotonic);
+                }
+                self.tracker.rtt_success(&sent_timestamps, &timestamped_message.timestamps);
+            }
+        }
+
+        /// Counts pings sent before the monotonic `deadline` that are still unanswered as lost
+        pub fn expire(&mut self, deadline: f64) {
+            self.sync_sent();
+            let mut expired = self.sent_pings.expire(&mut self.expiry_cursor, deadline);
+            let evicted = self.sent_pings.evicted.load(std::sync::atomic::Ordering::Relaxed);
+            expired += evicted - self.evicted_counted;
+            self.evicted_counted = evicted;
+            for _i in 0..expired {
+                self.tracker.rtt_timeout();
+            }
+        }
+
+        /// Builds a report and starts the next reporting interval at the monotonic time `now`
+        pub fn statistics(&mut self, timestamp: f64, sid: &str, now: f64) -> ClientSessionStatistics {
+            self.sync_sent();
+            self.tracker.send_error_mavg = SentPings::load_optional(&self.sent_pings.send_error_mavg);
+            self.tracker.send_error_worst = SentPings::load_optional(&self.sent_pings.send_error_worst);
+            self.tracker.send_skipped = self.sent_pings.skipped.load(std::sync::atomic::Ordering::Relaxed);
+            self.tracker.send_catch_up = self.sent_pings.catch_up.load(std::sync::atomic::Ordering::Relaxed);
+            self.tracker.send_lateness = self.sent_pings.send_lateness.take();
+            let mut statistics = ClientSessionStatistics::from_session_tracker(timestamp, sid, &self.tracker, now);
+            if let Some(counter_baseline) = &mut self.tracker.counter_baseline {
+                statistics.deltas = Some(counter_baseline.deltas(&statistics));
+            }
+            self.tracker.stats_seq += 1;
+            self.tracker.receive_time_windows.reset();
+            self.tracker.interval.restart(now);
+            return statistics;
+        }
+    }
+}
+
+/// Fixed log-scale histogram of durations in seconds, cheap enough to record every packet
+pub mod histogram {
+    const MIN_VALUE: f64 = 0.000_001;
+    const BUCKETS_PER_DOUBLING: f64 = 4.0;
+    /// 1 µs to roughly 134 s in steps of about 19 %
+    const BUCKET_COUNT: usize = 109;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Histogram {
+        counts: Vec<u64>,
+        count: u64,
+        sum: f64,
+        max: Option<f64>,
+    }
+
+    impl Histogram {
+        pub fn new() -> Histogram {
+            return Histogram {
+                counts: vec![0; BUCKET_COUNT],
+                count: 0,
+                sum: 0.0,
+                max: None,
+            };
+        }
+
+        /// Largest value counted into bucket `index`, the last bucket also takes everything above it
+        pub fn bucket_upper_bound(index: usize) -> f64 {
+            return MIN_VALUE * (index as f64 / BUCKETS_PER_DOUBLING).exp2();
+        }
+
+        fn bucket_index(value: f64) -> usize {
+            if value.is_nan() || value <= MIN_VALUE {
+                return 0;
+            }
+            let index = ((value / MIN_VALUE).log2() * BUCKETS_PER_DOUBLING).ceil() as usize;
+            return index.min(BUCKET_COUNT - 1);
+        }
+
+        pub fn record(&mut self, value: f64) {
+            self.counts[Histogram::bucket_index(value)] += 1;
+            self.count += 1;
+            self.sum += value;
+            if self.max.is_none_or(|max| value > max) {
+                self.max = Some(value);
+            }
+        }
+
+        pub fn count(&self) -> u64 {
+            return self.count;
+        }
+
+        pub fn sum(&self) -> f64 {
+            return self.sum;
+        }
+
+        pub fn max(&self) -> Option<f64> {
+            return self.max;
+        }
+
+        /// Upper bound of the bucket holding the sample at `quantile` (0 to 1), never above the largest sample
+        pub fn quantile(&self, quantile: f64) -> Option<f64> {
+            let max = self.max?;
+            let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+            let mut cumulative: u64 = 0;
+            for (index, count) in self.counts.iter().enumerate() {
+                cumulative += count;
+                if cumulative >= rank {
+                    return Some(Histogram::bucket_upper_bound(index).min(max));
+                }
+            }
+            return Some(max);
+        }
+
+        /// Upper bounds of every bucket but the last, which is unbounded
+        pub fn explicit_bounds() -> Vec<f64> {
+            return (0..BUCKET_COUNT - 1).map(Histogram::bucket_upper_bound).collect();
+        }
+
+        /// Counts of all buckets, including the empty ones
+        pub fn bucket_counts(&self) -> &[u64] {
+            return &self.counts;
+        }
+
+        /// Non-empty buckets as (upper bound, count)
+        pub fn buckets(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+            return self.counts.iter().enumerate().filter(|(_, count)| **count > 0).map(|(index, count)| (Histogram::bucket_upper_bound(index), *count));
+        }
+
+        pub fn reset(&mut self) {
+            for count in self.counts.iter_mut() {
+                *count = 0;
+            }
+            self.count = 0;
+            self.sum = 0.0;
+            self.max = None;
+        }
+    }
+
+    impl Default for Histogram {
+        fn default() -> Histogram {
+            return Histogram::new();
+        }
+    }
+
+    /// Histogram one thread records into while another periodically takes the counts, without locking
+    pub struct AtomicHistogram {
+        counts: Vec<std::sync::atomic::AtomicU64>,
+        sum_nanoseconds: std::sync::atomic::AtomicU64,
+        max_bits: std::sync::atomic::AtomicU64,
+    }
+
+    impl AtomicHistogram {
+        pub fn new() -> AtomicHistogram {
+            return AtomicHistogram {
+                counts: (0..BUCKET_COUNT).map(|_| std::sync::atomic::AtomicU64::new(0)).collect(),
+                sum_nanoseconds: std::sync::atomic::AtomicU64::new(0),
+                max_bits: std::sync::atomic::AtomicU64::new(0),
+            };
+        }
+
+        /// Negative values are counted as zero
+        pub fn record(&self, value: f64) {
+            let value = value.max(0.0);
+            self.counts[Histogram::bucket_index(value)].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.sum_nanoseconds.fetch_add((value * 1e9) as u64, std::sync::atomic::Ordering::Relaxed);
+            // Non-negative floats order the same as their bit patterns
+            self.max_bits.fetch_max(value.to_bits(), std::sync::atomic::Ordering::Relaxed);
+        }
+
+        /// Everything recorded since the previous call
+        pub fn take(&self) -> Histogram {
+            let mut histogram = Histogram::new();
+            for (count, taken) in histogram.counts.iter_mut().zip(self.counts.iter()) {
+                *count = taken.swap(0, std::sync::atomic::Ordering::Relaxed);
+                histogram.count += *count;
+            }
+            histogram.sum = self.sum_nanoseconds.swap(0, std::sync::atomic::Ordering::Relaxed) as f64 / 1e9;
+            let max_bits = self.max_bits.swap(0, std::sync::atomic::Ordering::Relaxed);
+            if histogram.count > 0 {
+                histogram.max = Some(f64::from_bits(max_bits));
+            }
+            return histogram;
+        }
+    }
+
+    impl Default for AtomicHistogram {
+        fn default() -> AtomicHistogram {
+            return AtomicHistogram::new();
+        }
+    }
+}
+
+/// Time sources, the system clock for the binaries and a manually driven one for simulations
+pub mod clock {
+    use super::timestamping::Timestamps;
+
+    pub trait Clock: Send + Sync {
+        fn now(&self) -> Timestamps;
+
+        fn monotonic(&self) -> f64 {
+            return self.now().monotonic;
+        }
+
+        fn wall(&self) -> f64 {
+            return self.now().wall;
+        }
+    }
+
+    #[derive(Clone, Copy, Default)]
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        fn now(&self) -> Timestamps {
+            return Timestamps::now();
+        }
+
+        fn monotonic(&self) -> f64 {
+            return super::util::get_monotonic_timestamp();
+        }
+
+        fn wall(&self) -> f64 {
+            return super::util::get_timestamp();
+        }
+    }
+
+    /// Clock that only moves when told to, clones share the same time
+    #[derive(Clone)]
+    pub struct SimulatedClock {
+        state: std::sync::Arc<std::sync::Mutex<(f64, f64)>>,
+    }
+
+    impl SimulatedClock {
+        pub fn new(wall_start: f64) -> SimulatedClock {
+            return SimulatedClock {
+                state: std::sync::Arc::new(std::sync::Mutex::new((0.0, wall_start))),
+            };
+        }
+
+        pub fn advance(&self, seconds: f64) {
+            let mut state = self.state.lock().unwrap();
+            state.0 += seconds;
+            state.1 += seconds;
+        }
+
+        /// Moves both clocks forward to the monotonic time `monotonic`, never backwards
+        pub fn advance_to(&self, monotonic: f64) {
+            let current = self.monotonic();
+            if monotonic > current {
+                self.advance(monotonic - current);
+            }
+        }
+
+        /// Steps only the wall clock, as NTP or an operator would
+        pub fn step_wall(&self, seconds: f64) {
+            self.state.lock().unwrap().1 += seconds;
+        }
+    }
+
+    impl Clock for SimulatedClock {
+        fn now(&self) -> Timestamps {
+            let state = self.state.lock().unwrap();
+            return Timestamps::at(state.0, state.1);
+        }
+    }
+}
+
+/// Waits for send deadlines without burning a core: sleeps most of the way and spins the last
+/// stretch, sized by how late the scheduler has been waking the thread
+pub mod pacing {
+    use super::clock::Clock;
+    use super::timestamping::Timestamps;
+
+    const MIN_SPIN_TAIL: f64 = 0.000_01;
+    const MAX_SPIN_TAIL: f64 = 0.001;
+    const INITIAL_WAKEUP_LATENESS: f64 = 0.000_05;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum PacingMode {
+        Sleep,
+        Spin,
+    }
+
+    impl std::str::FromStr for PacingMode {
+        type Err = std::string::String;
+
+        fn from_str(s: &str) -> Result<PacingMode, std::string::String> {
+            match s {
+                "sleep" => return Ok(PacingMode::Sleep),
+                "spin" => return Ok(PacingMode::Spin),
+                _ => return Err(format!("unknown pacing mode '{}'", s)),
+            }
+        }
+    }
+
+    pub struct Pacer {
+        mode: PacingMode,
+        wakeup_lateness_mavg: f64,
+        timer_slack_reduced: bool,
+    }
+
+    impl Pacer {
+        pub fn new(mode: PacingMode) -> Pacer {
+            return Pacer {
+                mode: mode,
+                wakeup_lateness_mavg: INITIAL_WAKEUP_LATENESS,
+                timer_slack_reduced: false,
+            };
+        }
+
+        /// Seconds before a deadline at which sleeping gives way to spinning
+        pub fn spin_tail(&self) -> f64 {
+            return (self.wakeup_lateness_mavg * 2.0).clamp(MIN_SPIN_TAIL, MAX_SPIN_TAIL);
+        }
+
+        /// Returns the time once the monotonic clock has reached `deadline`. Sleeps in real time,
+        /// so `clock` must advance on its own.
+        pub fn wait_until(&mut self, clock: &dyn Clock, deadline: f64) -> Timestamps {
+            if self.mode == PacingMode::Sleep {
+                if !self.timer_slack_reduced {
+                    // Timer slack is per thread, the default 50 µs would dominate the wakeup error
+                    reduce_timer_slack();
+                    self.timer_slack_reduced = true;
+                }
+                let sleep_until = deadline - self.spin_tail();
+                let current_time = clock.monotonic();
+                if sleep_until > current_time {
+                    sleep_for(sleep_until - current_time);
+                    let wakeup_lateness = (clock.monotonic() - sleep_until).max(0.0);
+                    self.wakeup_lateness_mavg = ((self.wakeup_lateness_mavg * 15.0) + wakeup_lateness) / 16.0;
+                }
+            }
+            loop {
+                let current_timestamps = clock.now();
+                if current_timestamps.monotonic >= deadline {
+                    return current_timestamps;
+                }
+                std::hint::spin_loop();
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reduce_timer_slack() {
+        unsafe {
+            libc::prctl(libc::PR_SET_TIMERSLACK, 1 as libc::c_ulong);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn reduce_timer_slack() {
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sleep_for(seconds: f64) {
+        let mut target = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe {
+            libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut target);
+        }
+        target.tv_sec += seconds.trunc() as libc::time_t;
+        target.tv_nsec += (seconds.fract() * 1e9) as libc::c_long;
+        if target.tv_nsec >= 1_000_000_000 {
+            target.tv_sec += 1;
+            target.tv_nsec -= 1_000_000_000;
+        }
+        // An absolute deadline keeps signals from stretching the sleep
+        while unsafe { libc::clock_nanosleep(libc::CLOCK_MONOTONIC, libc::TIMER_ABSTIME, &target, std::ptr::null_mut()) } == libc::EINTR {}
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn sleep_for(seconds: f64) {
+        std::thread::sleep(std::time::Duration::from_secs_f64(seconds));
+    }
+}
+
+/// In-process network for driving sessions deterministically on a simulated clock
+pub mod simulation {
+    /// xorshift64* generator, good enough for impairments and reproducible from a seed
+    pub struct Rng {
+        state: u64,
+    }
+
+    impl Rng {
+        pub fn new(seed: u64) -> Rng {
+            let state = seed ^ 0x9E37_79B9_7F4A_7C15;
+            return Rng {
+                state: if state == 0 { 1 } else { state },
+            };
+        }
+
+        pub fn next_u64(&mut self) -> u64 {
+            self.state ^= self.state >> 12;
+            self.state ^= self.state << 25;
+            self.state ^= self.state >> 27;
+            return self.state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        }
+
+        /// Uniform in [0, 1)
+        pub fn next_f64(&mut self) -> f64 {
+            return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        }
+
+        pub fn chance(&mut self, probability: f64) -> bool {
+            return probability > 0.0 && self.next_f64() < probability;
+        }
+    }
+
+    /// Impairments of one direction. Every datagram is delayed by `delay` plus up to `jitter`,
+    /// reordered datagrams are held back a further `reorder_delay`.
+    #[derive(Debug, Clone, Copy)]
+    pub struct LinkConfig {
+        pub delay: f64,
+        pub jitter: f64,
+        pub loss: f64,
+        pub reorder: f64,
+        pub reorder_delay: f64,
+        pub duplicate: f64,
+    }
+
+    impl LinkConfig {
+        pub fn clean(delay: f64) -> LinkConfig {
+            return LinkConfig {
+                delay: delay,
+                jitter: 0.0,
+                loss: 0.0,
+                reorder: 0.0,
+                reorder_delay: 0.0,
+                duplicate: 0.0,
+            };
+        }
+    }
+
+    impl Default for LinkConfig {
+        fn default() -> LinkConfig {
+            return LinkConfig::clean(0.01);
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, Default, PartialEq)]
+    pub struct LinkCounters {
+        pub sent: u64,
+        pub dropped: u64,
+        pub duplicated: u64,
+        pub reordered: u64,
+        pub delivered: u64,
+    }
+
+    pub struct SimulatedLink {
+        pub config: LinkConfig,
+        pub counters: LinkCounters,
+        rng: Rng,
+        // Keyed by delivery time in nanoseconds and a counter so equal times keep their send order
+        in_flight: std::collections::BTreeMap<(u64, u64), Vec<u8>>,
+        next_id: u64,
+    }
+
+    impl SimulatedLink {
+        pub fn new(config: LinkConfig, seed: u64) -> SimulatedLink {
+            return SimulatedLink {
+                config: config,
+                counters: LinkCounters::default(),
+                rng: Rng::new(seed),
+                in_flight: std::collections::BTreeMap::new(),
+                next_id: 0,
+            };
+        }
+
+        pub fn send(&mut self, now: f64, datagram: Vec<u8>) {
+            self.counters.sent += 1;
+            if self.rng.chance(self.config.loss) {
+                self.counters.dropped += 1;
+                return;
+            }
+            if self.rng.chance(self.config.duplicate) {
+                self.counters.duplicated += 1;
+                let duplicate_at = now + self.sample_delay();
+                self.schedule(duplicate_at, datagram.clone());
+            }
+            let deliver_at = now + self.sample_delay();
+            self.schedule(deliver_at, datagram);
+        }
+
+        fn sample_delay(&mut self) -> f64 {
+            let mut delay = self.config.delay + self.config.jitter * self.rng.next_f64();
+            if self.rng.chance(self.config.reorder) {
+                self.counters.reordered += 1;
+                delay += self.config.reorder_delay;
+            }
+            return delay;
+        }
+
+        fn schedule(&mut self, deliver_at: f64, datagram: Vec<u8>) {
+            let deliver_at_ns = (deliver_at * 1e9).round() as u64;
+            self.in_flight.insert((deliver_at_ns, self.next_id), datagram);
+            self.next_id += 1;
+        }
+
+        pub fn next_delivery(&self) -> Option<f64> {
+            return self.in_flight.keys().next().map(|(deliver_at_ns, _)| *deliver_at_ns as f64 / 1e9);
+        }
+
+        /// Next datagram due at or before `now`
+        pub fn deliver(&mut self, now: f64) -> Option<Vec<u8>> {
+            if self.next_delivery().is_some_and(|deliver_at| deliver_at <= now) {
+                self.counters.delivered += 1;
+                return self.in_flight.pop_first().map(|(_, datagram)| datagram);
+            }
+            return None;
+        }
+    }
+}
+
+pub mod util {
+    static MONOTONIC_EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+    /// Wall clock time in seconds since the Unix epoch, only for reporting as NTP may step it
+    pub fn get_timestamp() -> f64 {
+        let current_time_duration = time::OffsetDateTime::now() - time::OffsetDateTime::unix_epoch();
+        return current_time_duration.as_seconds_f64();
+    }
+
+    /// Seconds on a clock that never steps, for measuring intervals. Only meaningful relative to
+    /// other monotonic timestamps of the same process.
+    pub fn get_monotonic_timestamp() -> f64 {
+        return MONOTONIC_EPOCH.get_or_init(std::time::Instant::now).elapsed().as_secs_f64();
+    }
+
+    /// Name of this machine for tagging statistics
+    #[cfg(target_os = "linux")]
+    pub fn get_hostname() -> std::string::String {
+        let mut buf = [0u8; 256];
+        let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+        if result == 0 {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            return std::string::String::from_utf8_lossy(&buf[..len]).into_owned();
+        }
+        return "unknown".to_string();
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn get_hostname() -> std::string::String {
+        return std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_string());
+    }
+
+    /// A seed that differs between runs, from the per-process keys of the standard library hasher and the time
+    pub fn random_seed() -> u64 {
+        use std::hash::{BuildHasher, Hasher};
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(get_timestamp().to_bits());
+        return hasher.finish();
+    }
+}
+pub mod stats {
+    // Statistics waiting for a slow HTTP endpoint before new ones are dropped
+    const HTTP_QUEUE_LENGTH: usize = 1024;
+    const HTTP_TIMEOUT: u64 = 5;
+    const SPOOL_MEMORY_RECORDS: usize = 10000;
+    const SPOOL_DISK_BYTES: u64 = 64 * 1024 * 1024;
+    // Records per TCP acknowledgement, HTTP request and spill file
+    const SPOOL_BATCH_RECORDS: usize = 100;
+    const RETRY_MIN: f64 = 1.0;
+    const RETRY_MAX: f64 = 60.0;
+    // While deliveries fail, records waiting in memory reach the spool directory at least this often
+    const SPOOL_PERSIST_INTERVAL: f64 = 5.0;
+    // Keeps StatsD datagrams within a typical MTU
+    const STATSD_MAX_DATAGRAM: usize = 1400;
+    // Counter baselines of sessions that stopped reporting are forgotten after this many seconds
+    const SERIES_EXPIRY: f64 = 600.0;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum FieldValue {
+        /// Cumulative since the session started
+        Counter(u64),
+        Gauge(f64),
+        Text(std::string::String),
+        /// Cumulative since the session started, like counters
+        Histogram(super::histogram::Histogram),
+    }
+
+    /// One measurement with its tags, the unit the non-JSON outputs work with
+    #[derive(Clone, Debug)]
+    pub struct StatisticsPoint {
+        pub measurement: &'static str,
+        /// Wall clock seconds
+        pub timestamp: f64,
+        /// Wall clock seconds the counters count from, when the record knows
+        pub start: Option<f64>,
+        pub tags: Vec<(&'static str, std::string::String)>,
+        pub fields: Vec<(std::string::String, FieldValue)>,
+    }
+
+    impl StatisticsPoint {
+        pub fn new(measurement: &'static str, timestamp: f64) -> StatisticsPoint {
+            return StatisticsPoint {
+                measurement: measurement,
+                timestamp: timestamp,
+                start: None,
+                tags: Vec::new(),
+                fields: Vec::new(),
+            };
+        }
+
+        pub fn tag(&mut self, key: &'static str, value: &str) {
+            self.tags.push((key, value.to_string()));
+        }
+
+        pub fn counter(&mut self, key: &str, value: u64) {
+            self.fields.push((key.to_string(), FieldValue::Counter(value)));
+        }
+
+        pub fn gauge(&mut self, key: &str, value: f64) {
+            self.fields.push((key.to_string(), FieldValue::Gauge(value)));
+        }
+
+        /// Missing values are left out rather than reported as zero
+        pub fn optional_counter(&mut self, key: &str, value: Option<u64>) {
+            if let Some(value) = value {
+                self.counter(key, value);
+            }
+        }
+
+        pub fn optional_gauge(&mut self, key: &str, value: Option<f64>) {
+            if let Some(value) = value {
+                self.gauge(key, value);
+            }
+        }
+
+        pub fn histogram(&mut self, key: &str, value: &super::histogram::Histogram) {
+            self.fields.push((key.to_string(), FieldValue::Histogram(value.clone())));
+        }
+
+        pub fn text(&mut self, key: &str, value: &str) {
+            self.fields.push((key.to_string(), FieldValue::Text(value.to_string())));
+        }
+    }
+
+    /// Statistics the client and hub report, as JSON for the clocktower and as points for everything else
+    pub trait StatisticsRecord {
+        fn to_json(&self) -> Result<std::string::String, serde_json::Error>;
+        fn points(&self) -> Vec<StatisticsPoint>;
+    }
+
+    fn escape_influx(value: &str, special: &[char]) -> std::string::String {
+        let mut escaped = std::string::String::with_capacity(value.len());
+        for c in value.chars() {
+            if c == '\\' || special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        return escaped;
+    }
+
+    /// Renders a point as a line of InfluxDB line protocol with nanosecond precision
+    pub fn to_influx_line(point: &StatisticsPoint, host: &str) -> std::string::String {
+        let mut line = escape_influx(point.measurement, &[',', ' ']);
+        let mut tags: Vec<(&str, &str)> = point.tags.iter().map(|(key, value)| (*key, value.as_str())).collect();
+        tags.push(("host", host));
+        // Influx wants tags sorted by key for the fastest writes
+        tags.sort();
+        for (key, value) in tags {
+            if value.is_empty() {
+                continue;
+            }
+            line.push(',');
+            line.push_str(&escape_influx(key, &[',', '=', ' ']));
+            line.push('=');
+            line.push_str(&escape_influx(value, &[',', '=', ' ']));
+        }
+        let mut separator = ' ';
+        for (key, value) in point.fields.iter() {
+            let rendered = match value {
+                FieldValue::Counter(counter) => format!("{}i", counter),
+                FieldValue::Gauge(gauge) => {
+                    if !gauge.is_finite() {
+                        continue;
+                    }
+                    format!("{}", gauge)
+                },
+                FieldValue::Text(text) => format!("\"{}\"", escape_influx(text, &['"'])),
+                FieldValue::Histogram(_) => {
+                    continue;
+                },
+            };
+            line.push(separator);
+            line.push_str(&escape_influx(key, &[',', '=', ' ']));
+            line.push('=');
+            line.push_str(&rendered);
+            separator = ',';
+        }
+        line.push_str(&format!(" {}", (point.timestamp * 1e9) as i64));
+        return line;
+    }
+
+    /// Cumulative counters of a session as of its previous report
+    #[derive(Clone, Debug, Default)]
+    pub struct CounterBaseline {
+        counters: std::collections::HashMap<std::string::String, u64>,
+    }
+
+    impl CounterBaseline {
+        pub fn new() -> CounterBaseline {
+            return CounterBaseline {
+                counters: std::collections::HashMap::new(),
+            };
+        }
+
+        /// What each counter of `record` gained since the previous call, the first call counts from zero
+        pub fn deltas(&mut self, record: &dyn StatisticsRecord) -> std::collections::BTreeMap<std::string::String, u64> {
+            let mut deltas = std::collections::BTreeMap::new();
+            for point in record.points() {
+                for (name, value) in point.fields {
+                    if let FieldValue::Counter(counter) = value {
+                        let previous = self.counters.insert(name.clone(), counter).unwrap_or(0);
+                        deltas.insert(name, counter.saturating_sub(previous));
+                    }
+                }
+            }
+            return deltas;
+        }
+    }
+
+    /// Turns cumulative counters into the per-report increments StatsD expects
+    pub struct StatsdEncoder {
+        tagged: bool,
+        // Last cumulative value of each counter and when it was seen on the monotonic clock
+        baselines: std::collections::HashMap<std::string::String, (u64, f64)>,
+        last_pruned: f64,
+    }
+
+    impl StatsdEncoder {
+        /// `tagged` selects DogStatsD tags over encoding the tags into the metric name
+        pub fn new(tagged: bool) -> StatsdEncoder {
+            return StatsdEncoder {
+                tagged: tagged,
+                baselines: std::collections::HashMap::new(),
+                last_pruned: 0.0,
+            };
+        }
+
+        fn sanitize(value: &str) -> std::string::String {
+            return value.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect();
+        }
+
+        /// DogStatsD tag values may hold colons, only the list and message separators are replaced
+        fn escape_tag(value: &str) -> std::string::String {
+            return value.replace([',', '|', '#'], "_");
+        }
+
+        /// Lines of `name:value|type` for one point, counters only count what happened since the
+        /// previous report of the same series. A counter going backwards was reset and is reported whole.
+        pub fn encode(&mut self, point: &StatisticsPoint, host: &str, now: f64) -> Vec<std::string::String> {
+            let prefix = format!("metronome.{}", point.measurement.trim_start_matches("metronome_"));
+            let mut lines: Vec<std::string::String> = Vec::new();
+            for (key, value) in point.fields.iter() {
+                // Directional fields share a name and are told apart by a direction tag
+                let (name, direction) = if let Some(name) = key.strip_prefix("forward_") {
+                    (name, Some("forward"))
+                } else if let Some(name) = key.strip_prefix("reverse_") {
+                    (name, Some("reverse"))
+                } else {
+                    (key.as_str(), None)
+                };
+                let mut tags: Vec<(&str, &str)> = point.tags.iter().map(|(key, value)| (*key, value.as_str())).collect();
+                if let Some(direction) = direction {
+                    tags.push(("direction", direction));
+                }
+                // Colons and pipes in a name would end it early
+                let name = StatsdEncoder::sanitize(name);
+                let metric = if self.tagged {
+                    format!("{}.{}", prefix, name)
+                } else {
+                    let mut metric = prefix.clone();
+                    for (_, tag_value) in tags.iter() {
+                        metric.push('.');
+                        metric.push_str(&StatsdEncoder::sanitize(tag_value));
+                    }
+                    metric.push('.');
+                    metric.push_str(&name);
+                    metric
+                };
+                let series = format!("{}|{:?}", metric, tags);
+                let rendered = match value {
+                    FieldValue::Counter(counter) => {
+                        let previous = self.baselines.insert(series, (*counter, now));
+                        let delta = match previous {
+                            Some((previous, _)) if previous <= *counter => *counter - previous,
+                            _ => *counter,
+                        };
+                        format!("{}:{}|c", metric, delta)
+                    },
+                    FieldValue::Gauge(gauge) => {
+                        if !gauge.is_finite() {
+                            continue;
+                        }
+                        format!("{}:{}|g", metric, gauge)
+                    },
+                    FieldValue::Text(_) | FieldValue::Histogram(_) => {
+                        continue;
+                    }
+                };
+                if self.tagged {
+                    let mut tag_list: Vec<std::string::String> = tags.iter().filter(|(_, value)| !value.is_empty()).map(|(key, value)| format!("{}:{}", key, StatsdEncoder::escape_tag(value))).collect();
+                    tag_list.push(format!("host:{}", StatsdEncoder::escape_tag(host)));
+                    lines.push(format!("{}|#{}", rendered, tag_list.join(",")));
+                } else {
+                    lines.push(rendered);
+                }
+            }
+            if now - self.last_pruned > SERIES_EXPIRY {
+                self.baselines.retain(|_, (_, seen)| now - *seen < SERIES_EXPIRY);
+                self.last_pruned = now;
+            }
+            return lines;
+        }
+    }
+
+    struct OtlpSeries {
+        start: f64,
+        last: f64,
+        seen: f64,
+        counters: std::collections::HashMap<std::string::String, u64>,
+    }
+
+    /// Renders points as OTLP/HTTP JSON metrics with cumulative temporality: counters become
+    /// monotonic sums, gauges gauges and histograms explicit bucket histograms.
+    pub struct OtlpEncoder {
+        resource_attributes: Vec<(&'static str, std::string::String)>,
+        // Start of each series without one of its own, moved forward whenever one of its counters goes backwards
+        series: std::collections::HashMap<std::string::String, OtlpSeries>,
+        last_pruned: f64,
+    }
+
+    impl OtlpEncoder {
+        pub fn new(service_name: &str, host: &str) -> OtlpEncoder {
+            return OtlpEncoder {
+                resource_attributes: vec![
+                    ("service.name", service_name.to_string()),
+                    ("service.version", env!("CARGO_PKG_VERSION").to_string()),
+                    ("host.name", host.to_string()),
+                ],
+                series: std::collections::HashMap::new(),
+                last_pruned: 0.0,
+            };
+        }
+
+        fn attributes(attributes: &[(&str, &str)]) -> serde_json::Value {
+            return serde_json::Value::Array(attributes.iter().map(|(key, value)| serde_json::json!({
+                "key": key,
+                "value": { "stringValue": value },
+            })).collect());
+        }
+
+        fn nanoseconds(timestamp: f64) -> std::string::String {
+            return ((timestamp * 1e9) as u64).to_string();
+        }
+
+        /// Start time of the series `point` belongs to, the one the point carries if any. Otherwise
+        /// the first point seen, its counters are noted for reset detection.
+        fn series_start(&mut self, point: &StatisticsPoint, now: f64) -> f64 {
+            let key = format!("{}|{:?}", point.measurement, point.tags);
+            let series = self.series.entry(key).or_insert_with(|| OtlpSeries {
+                start: point.start.unwrap_or(point.timestamp),
+                last: point.timestamp,
+                seen: now,
+                counters: std::collections::HashMap::new(),
+            });
+            let mut reset = false;
+            for (name, value) in point.fields.iter() {
+                let current = match value {
+                    FieldValue::Counter(counter) => *counter,
+                    FieldValue::Histogram(histogram) => histogram.count(),
+                    _ => {
+                        continue;
+                    }
+                };
+                if let Some(previous) = series.counters.insert(name.clone(), current) {
+                    reset |= current < previous;
+                }
+            }
+            if let Some(start) = point.start {
+                series.start = start;
+            } else if reset {
+                series.start = series.last;
+            }
+            series.last = point.timestamp;
+            series.seen = now;
+            return series.start;
+        }
+
+        /// A complete `ExportMetricsServiceRequest` for the points of one record
+        pub fn encode(&mut self, points: &[StatisticsPoint], now: f64) -> serde_json::Value {
+            let mut metrics: Vec<serde_json::Value> = Vec::new();
+            for point in points {
+                let start = OtlpEncoder::nanoseconds(self.series_start(point, now));
+                let time = OtlpEncoder::nanoseconds(point.timestamp);
+                let tags: Vec<(&str, &str)> = point.tags.iter().map(|(key, value)| (*key, value.as_str())).collect();
+                let attributes = OtlpEncoder::attributes(&tags);
+                let prefix = format!("metronome.{}", point.measurement.trim_start_matches("metronome_"));
+                for (name, value) in point.fields.iter() {
+                    let metric_name = format!("{}.{}", prefix, name);
+                    let metric = match value {
+                        FieldValue::Counter(counter) => serde_json::json!({
+                            "name": metric_name,
+                            "sum": {
+                                "dataPoints": [{
+                                    "attributes": attributes,
+                                    "startTimeUnixNano": start,
+                                    "timeUnixNano": time,
+                                    "asInt": counter.to_string(),
+                                }],
+                                "aggregationTemporality": 2,
+                                "isMonotonic": true,
+                            },
+                        }),
+                        FieldValue::Gauge(gauge) => {
+                            if !gauge.is_finite() {
+                                continue;
+                            }
+                            serde_json::json!({
+                                "name": metric_name,
+                                "gauge": {
+                                    "dataPoints": [{
+                                        "attributes": attributes,
+                                        "timeUnixNano": time,
+                                        "asDouble": gauge,
+                                    }],
+                                },
+                            })
+                        },
+                        FieldValue::Histogram(histogram) => {
+                            let mut data_point = serde_json::json!({
+                                "attributes": attributes,
+                                "startTimeUnixNano": start,
+                                "timeUnixNano": time,
+                                "count": histogram.count().to_string(),
+                                "sum": histogram.sum(),
+                                "bucketCounts": histogram.bucket_counts().iter().map(|count| count.to_string()).collect::<Vec<std::string::String>>(),
+                                "explicitBounds": super::histogram::Histogram::explicit_bounds(),
+                            });
+                            if let Some(max) = histogram.max() {
+                                data_point["max"] = serde_json::json!(max);
+                            }
+                            serde_json::json!({
+                                "name": metric_name,
+                                "unit": "s",
+                                "histogram": {
+                                    "dataPoints": [data_point],
+                                    "aggregationTemporality": 2,
+                                },
+                            })
+                        },
+                        FieldValue::Text(_) => {
+                            continue;
+                        },
+                    };
+                    metrics.push(metric);
+                }
+            }
+            if now - self.last_pruned > SERIES_EXPIRY {
+                self.series.retain(|_, series| now - series.seen < SERIES_EXPIRY);
+                self.last_pruned = now;
+            }
+            let resource_attributes: Vec<(&str, &str)> = self.resource_attributes.iter().map(|(key, value)| (*key, value.as_str())).collect();
+            return serde_json::json!({
+                "resourceMetrics": [{
+                    "resource": { "attributes": OtlpEncoder::attributes(&resource_attributes) },
+                    "scopeMetrics": [{
+                        "scope": { "name": "metronome", "version": env!("CARGO_PKG_VERSION") },
+                        "metrics": metrics,
+                    }],
+                }],
+            });
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum StatsDestination {
+        /// JSON datagrams for the clocktower, a plain `host:port`
+        Clocktower(std::string::String),
+        /// `influx-udp://host:port`
+        InfluxUdp(std::string::String),
+        /// `influx-http://host:port/path?query`, the path is the write endpoint
+        InfluxHttp { address: std::string::String, path: std::string::String },
+        /// `statsd://host:port`, tags become part of the metric name
+        Statsd(std::string::String),
+        /// `dogstatsd://host:port`
+        DogStatsd(std::string::String),
+        /// `otlp-http://host:port/path`, OTLP JSON posted to `/v1/metrics` unless another path is given
+        OtlpHttp { address: std::string::String, path: std::string::String },
+        /// `tcp://host:port?spool=...`, length-delimited JSON the clocktower acknowledges
+        ReliableTcp { address: std::string::String, spool: SpoolConfig },
+        /// `http://host:port/path?spool=...`, JSON arrays posted to `/statistics` unless another path is given
+        ReliableHttp { address: std::string::String, path: std::string::String, spool: SpoolConfig },
+    }
+
+    /// Splits `host:port/path?query` into the address and the path with its query
+    fn split_address(rest: &str, default_path: &str) -> (std::string::String, std::string::String) {
+        match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('/') => {
+                return (rest[..i].to_string(), rest[i..].to_string());
+            },
+            Some(i) => {
+                return (rest[..i].to_string(), format!("{}{}", default_path, &rest[i..]));
+            },
+            None => {
+                return (rest.to_string(), default_path.to_string());
+            }
+        }
+    }
+
+    impl std::str::FromStr for StatsDestination {
+        type Err = std::string::String;
+
+        fn from_str(s: &str) -> Result<StatsDestination, std::string::String> {
+            if let Some(rest) = s.strip_prefix("influx-udp://") {
+                return Ok(StatsDestination::InfluxUdp(rest.trim_end_matches('/').to_string()));
+            }
+            if let Some(rest) = s.strip_prefix("influx-http://") {
+                let (address, path) = split_address(rest, "/write?db=metronome");
+                return Ok(StatsDestination::InfluxHttp { address: address, path: path });
+            }
+            if let Some(rest) = s.strip_prefix("otlp-http://") {
+                let (address, path) = split_address(rest, "/v1/metrics");
+                return Ok(StatsDestination::OtlpHttp { address: address, path: path });
+            }
+            if let Some(rest) = s.strip_prefix("tcp://") {
+                let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+                let (spool, unknown) = SpoolConfig::from_query(query)?;
+                if !unknown.is_empty() {
+                    return Err(format!("unknown parameters '{}' for statistics destination '{}'", unknown, s));
+                }
+                return Ok(StatsDestination::ReliableTcp { address: address.trim_end_matches('/').to_string(), spool: spool });
+            }
+            if let Some(rest) = s.strip_prefix("http://") {
+                let (address, path) = split_address(rest, "/statistics");
+                let (path, query) = path.split_once('?').unwrap_or((&path, ""));
+                let (spool, query) = SpoolConfig::from_query(query)?;
+                let path = if query.is_empty() { path.to_string() } else { format!("{}?{}", path, query) };
+                return Ok(StatsDestination::ReliableHttp { address: address, path: path, spool: spool });
+            }
+            if let Some(rest) = s.strip_prefix("statsd://") {
+                return Ok(StatsDestination::Statsd(rest.trim_end_matches('/').to_string()));
+            }
+            if let Some(rest) = s.strip_prefix("dogstatsd://") {
+                return Ok(StatsDestination::DogStatsd(rest.trim_end_matches('/').to_string()));
+            }
+            if s.contains("://") {
+                return Err(format!("unknown statistics destination '{}', expected host:port, influx-udp://, influx-http://, statsd://, dogstatsd://, otlp-http://, tcp:// or http://", s));
+            }
+            return Ok(StatsDestination::Clocktower(s.to_string()));
+        }
+    }
+
+    fn resolve(address: &str) -> std::io::Result<std::net::SocketAddr> {
+        use std::net::ToSocketAddrs;
+        return address.to_socket_addrs()?.next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("'{}' doesn't resolve to any addresses", address)));
+    }
+
+    fn connect_udp(address: &str) -> std::io::Result<std::net::UdpSocket> {
+        let addr = resolve(address)?;
+        let socket = if addr.is_ipv4() { std::net::UdpSocket::bind("0.0.0.0:0")? } else { std::net::UdpSocket::bind("[::]:0")? };
+        socket.connect(addr)?;
+        return Ok(socket);
+    }
+
+    /// Why a delivery failed
+    #[derive(Clone, Debug, PartialEq)]
+    enum DeliveryError {
+        /// The collector could not be reached or had trouble of its own, the same request may succeed later
+        Failed(std::string::String),
+        /// The collector refused the request itself (a 4xx), sending it again gets the same answer
+        Rejected(std::string::String),
+    }
+
+    impl std::fmt::Display for DeliveryError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                DeliveryError::Failed(e) | DeliveryError::Rejected(e) => {
+                    return write!(f, "{}", e);
+                }
+            }
+        }
+    }
+
+    fn http_post(address: &str, path: &str, content_type: &str, body: &[u8]) -> Result<(), DeliveryError> {
+        use std::io::{Read, Write};
+        let failed = |e: std::io::Error| DeliveryError::Failed(e.to_string());
+        let addr = resolve(address).map_err(failed)?;
+        let mut stream = std::net::TcpStream::connect_timeout(&addr, std::time::Duration::from_secs(HTTP_TIMEOUT)).map_err(failed)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(HTTP_TIMEOUT))).map_err(failed)?;
+        stream.set_write_timeout(Some(std::time::Duration::from_secs(HTTP_TIMEOUT))).map_err(failed)?;
+        let header = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", path, address, content_type, body.len());
+        stream.write_all(header.as_bytes()).map_err(failed)?;
+        stream.write_all(body).map_err(failed)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).map_err(failed)?;
+        let response = std::string::String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or("");
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') => {
+                return Ok(());
+            },
+            // Request Timeout and Too Many Requests are worth another try
+            Some(status) if status.starts_with('4') && status != "408" && status != "429" => {
+                return Err(DeliveryError::Rejected(format!("rejected with '{}'", status_line)));
+            },
+            _ => {
+                return Err(DeliveryError::Failed(format!("unexpected response '{}'", status_line)));
+            }
+        }
+    }
+
+    /// Posts queued bodies in order, `concatenate` batches whatever queued up during the last request into one
+    fn http_writer_thread(address: std::string::String, path: std::string::String, content_type: &'static str, concatenate: bool, rx: std::sync::mpsc::Receiver<Vec<u8>>) {
+        let mut last_error_printed: f64 = f64::NEG_INFINITY;
+        while let Ok(mut body) = rx.recv() {
+            if concatenate {
+                while let Ok(more) = rx.try_recv() {
+                    body.extend_from_slice(&more);
+                }
+            }
+            if let Err(e) = http_post(&address, &path, content_type, &body) {
+                let current_time = super::util::get_monotonic_timestamp();
+                if (current_time - last_error_printed) > 10.0 {
+                    eprintln!("failed to write statistics to {}{}: {}", address, path, e);
+                    last_error_printed = current_time;
+                }
+            }
+        }
+    }
+
+    /// Where records wait while the collector cannot be reached
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct SpoolConfig {
+        /// Records kept in memory before the oldest are spilled to disk or dropped
+        pub memory_records: usize,
+        /// Spill to files in this directory, which also survive restarts. One directory per destination.
+        pub directory: Option<std::string::String>,
+        /// Oldest spilled files are removed beyond this many bytes
+        pub disk_bytes: u64,
+    }
+
+    impl Default for SpoolConfig {
+        fn default() -> SpoolConfig {
+            return SpoolConfig {
+                memory_records: SPOOL_MEMORY_RECORDS,
+                directory: None,
+                disk_bytes: SPOOL_DISK_BYTES,
+            };
+        }
+    }
+
+    impl SpoolConfig {
+        /// Takes the `spool`, `spool_records` and `spool_bytes` parameters out of a query string,
+        /// returning whatever else it held
+        fn from_query(query: &str) -> Result<(SpoolConfig, std::string::String), std::string::String> {
+            let mut spool_config = SpoolConfig::default();
+            let mut rest: Vec<&str> = Vec::new();
+            for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+                let (key, value) = parameter.split_at(parameter.find('=').unwrap_or(parameter.len()));
+                let value = value.trim_start_matches('=');
+                match key {
+                    "spool" => {
+                        spool_config.directory = Some(value.to_string());
+                    },
+                    "spool_records" => {
+                        spool_config.memory_records = value.parse().map_err(|e| format!("invalid spool_records '{}': {}", value, e))?;
+                    },
+                    "spool_bytes" => {
+                        spool_config.disk_bytes = value.parse().map_err(|e| format!("invalid spool_bytes '{}': {}", value, e))?;
+                    },
+                    _ => {
+                        rest.push(parameter);
+                    }
+                }
+            }
+            return Ok((spool_config, rest.join("&")));
+        }
+    }
+
+    struct SpoolFile {
+        id: u64,
+        path: std::path::PathBuf,
+        bytes: u64,
+        records: u64,
+    }
+
+    /// Records peeked from the spool, removed only once delivered
+    pub struct SpoolBatch {
+        pub records: Vec<Vec<u8>>,
+        file: Option<std::path::PathBuf>,
+    }
+
+    /// Bounded FIFO of serialized records, oldest first. Overflow spills the oldest records to
+    /// files of length-prefixed records when a directory is configured, otherwise it drops them.
+    pub struct Spool {
+        config: SpoolConfig,
+        memory: std::collections::VecDeque<Vec<u8>>,
+        files: std::collections::VecDeque<SpoolFile>,
+        disk_bytes: u64,
+        next_file_id: u64,
+        dropped: u64,
+    }
+
+    impl Spool {
+        /// Picks up files a previous run left behind in the spool directory
+        pub fn open(config: &SpoolConfig) -> std::io::Result<Spool> {
+            let mut spool = Spool {
+                config: config.clone(),
+                memory: std::collections::VecDeque::new(),
+                files: std::collections::VecDeque::new(),
+                disk_bytes: 0,
+                next_file_id: 0,
+                dropped: 0,
+            };
+            if let Some(directory) = &config.directory {
+                std::fs::create_dir_all(directory)?;
+                let mut found: Vec<(u64, SpoolFile)> = Vec::new();
+                for entry in std::fs::read_dir(directory)? {
+                    let path = entry?.path();
+                    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("").to_string();
+                    // <id>-<records>.spool
+                    if let Some((id, records)) = name.strip_suffix(".spool").and_then(|stem| stem.split_once('-')) {
+                        if let (Ok(id), Ok(records)) = (id.parse::<u64>(), records.parse::<u64>()) {
+                            let bytes = std::fs::metadata(&path)?.len();
+                            found.push((id, SpoolFile { id: id, path: path, bytes: bytes, records: records }));
+                        }
+                    }
+                }
+                found.sort_by_key(|(id, _)| *id);
+                for (id, file) in found {
+                    spool.next_file_id = id + 1;
+                    spool.disk_bytes += file.bytes;
+                    spool.files.push_back(file);
+                }
+            }
+            return Ok(spool);
+        }
+
+        pub fn is_empty(&self) -> bool {
+            return self.memory.is_empty() && self.files.is_empty();
+        }
+
+        /// Records lost to the spool limits so far
+        pub fn dropped(&self) -> u64 {
+            return self.dropped;
+        }
+
+        pub fn push(&mut self, record: Vec<u8>) {
+            self.memory.push_back(record);
+            if self.memory.len() <= self.config.memory_records {
+                return;
+            }
+            if self.config.directory.is_some() {
+                let spilled: Vec<Vec<u8>> = self.memory.drain(..SPOOL_BATCH_RECORDS.min(self.memory.len())).collect();
+                if let Err(e) = self.spill(&spilled) {
+                    eprintln!("failed to spill statistics to disk, dropping {} records: {}", spilled.len(), e);
+                    self.dropped += spilled.len() as u64;
+                }
+            } else {
+                self.memory.pop_front();
+                self.dropped += 1;
+            }
+        }
+
+        /// Writes records to the spill file `id`, whose name also carries the number of records
+        fn write_file(&mut self, directory: &str, id: u64, records: &[Vec<u8>]) -> std::io::Result<SpoolFile> {
+            let mut contents: Vec<u8> = Vec::new();
+            for record in records {
+                contents.extend_from_slice(&(record.len() as u32).to_be_bytes());
+                contents.extend_from_slice(record);
+            }
+            let path = std::path::Path::new(directory).join(format!("{:016}-{}.spool", id, records.len()));
+            std::fs::write(&path, &contents)?;
+            self.disk_bytes += contents.len() as u64;
+            return Ok(SpoolFile { id: id, path: path, bytes: contents.len() as u64, records: records.len() as u64 });
+        }
+
+        fn spill(&mut self, records: &[Vec<u8>]) -> std::io::Result<()> {
+            let directory = match self.config.directory.clone() {
+                Some(directory) => directory,
+                None => {
+                    return Ok(());
+                }
+            };
+            let file = self.write_file(&directory, self.next_file_id, records)?;
+            self.next_file_id += 1;
+            self.files.push_back(file);
+            while self.disk_bytes > self.config.disk_bytes && self.files.len() > 1 {
+                if let Some(oldest) = self.files.pop_front() {
+                    self.remove_file(&oldest);
+                    self.dropped += oldest.records;
+                }
+            }
+            return Ok(());
+        }
+
+        fn remove_file(&mut self, file: &SpoolFile) {
+            self.disk_bytes -= file.bytes;
+            if let Err(e) = std::fs::remove_file(&file.path) {
+                eprintln!("failed to remove spool file {}: {}", file.path.display(), e);
+            }
+        }
+
+        fn read_file(path: &std::path::Path) -> std::io::Result<Vec<Vec<u8>>> {
+            let contents = std::fs::read(path)?;
+            let mut records: Vec<Vec<u8>> = Vec::new();
+            let mut offset: usize = 0;
+            while offset + 4 <= contents.len() {
+                let length = u32::from_be_bytes([contents[offset], contents[offset + 1], contents[offset + 2], contents[offset + 3]]) as usize;
+                offset += 4;
+                if offset + length > contents.len() {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated record"));
+                }
+                records.push(contents[offset..offset + length].to_vec());
+                offset += length;
+            }
+            return Ok(records);
+        }
+
+        /// The oldest records, up to `max_records` unless they come from a spill file
+        pub fn peek(&mut self, max_records: usize) -> Option<SpoolBatch> {
+            while let Some(file) = self.files.front() {
+                let path = file.path.clone();
+                match Spool::read_file(&path) {
+                    Ok(records) => {
+                        return Some(SpoolBatch { records: records, file: Some(path) });
+                    },
+                    Err(e) => {
+                        eprintln!("dropping unreadable spool file {}: {}", path.display(), e);
+                        if let Some(file) = self.files.pop_front() {
+                            self.remove_file(&file);
+                            self.dropped += file.records;
+                        }
+                    }
+                }
+            }
+            if self.memory.is_empty() {
+                return None;
+            }
+            return Some(SpoolBatch { records: self.memory.iter().take(max_records).cloned().collect(), file: None });
+        }
+
+        /// Removes the first `delivered` records of a batch from `peek` once the collector has them,
+        /// the rest stay at the front of the spool
+        pub fn commit(&mut self, batch: SpoolBatch, delivered: usize) {
+            let delivered = delivered.min(batch.records.len());
+            if delivered == 0 {
+                return;
+            }
+            match batch.file {
+                Some(path) => {
+                    if self.files.front().is_none_or(|file| file.path != path) {
+                        return;
+                    }
+                    if let Some(file) = self.files.pop_front() {
+                        let remaining = &batch.records[delivered..];
+                        if !remaining.is_empty() {
+                            // Same id so the rest keeps its place, written before the old file goes
+                            let directory = self.config.directory.clone().unwrap_or_default();
+                            match self.write_file(&directory, file.id, remaining) {
+                                Ok(rest) => self.files.push_front(rest),
+                                Err(e) => {
+                                    eprintln!("failed to rewrite spool file {}, dropping {} records: {}", file.path.display(), remaining.len(), e);
+                                    self.dropped += remaining.len() as u64;
+                                }
+                            }
+                        }
+                        self.remove_file(&file);
+                    }
+                },
+                None => {
+                    let delivered = delivered.min(self.memory.len());
+                    self.memory.drain(..delivered);
+                }
+            }
+        }
+
+        /// Moves everything still in memory to disk, when there is a directory to move it to
+        pub fn persist(&mut self) {
+            while !self.memory.is_empty() && self.config.directory.is_some() {
+                let spilled: Vec<Vec<u8>> = self.memory.drain(..SPOOL_BATCH_RECORDS.min(self.memory.len())).collect();
+                if let Err(e) = self.spill(&spilled) {
+                    eprintln!("failed to spill statistics to disk, dropping {} records: {}", spilled.len(), e);
+                    self.dropped += spilled.len() as u64;
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum ReliableTarget {
+        Tcp(std::string::String),
+        Http { address: std::string::String, path: std::string::String },
+    }
+
+    /// Writes each record as a big-endian u32 length and the JSON, then a zero length frame asking
+    /// the clocktower to acknowledge with the number of records it took since the last one. It takes
+    /// records in order, so on failure the error comes with how many of them it already has.
+    fn tcp_deliver(address: &str, connection: &mut Option<std::net::TcpStream>, records: &[Vec<u8>]) -> Result<(), (usize, std::string::String)> {
+        use std::io::{Read, Write};
+        if connection.is_none() {
+            let addr = resolve(address).map_err(|e| (0, e.to_string()))?;
+            let stream = std::net::TcpStream::connect_timeout(&addr, std::time::Duration::from_secs(HTTP_TIMEOUT)).map_err(|e| (0, e.to_string()))?;
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(HTTP_TIMEOUT))).map_err(|e| (0, e.to_string()))?;
+            stream.set_write_timeout(Some(std::time::Duration::from_secs(HTTP_TIMEOUT))).map_err(|e| (0, e.to_string()))?;
+            stream.set_nodelay(true).map_err(|e| (0, e.to_string()))?;
+            *connection = Some(stream);
+        }
+        let stream = match connection {
+            Some(stream) => stream,
+            None => {
+                return Err((0, "not connected".to_string()));
+            }
+        };
+        let mut frames: Vec<u8> = Vec::new();
+        for record in records {
+            frames.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            frames.extend_from_slice(record);
+        }
+        frames.extend_from_slice(&0u32.to_be_bytes());
+        stream.write_all(&frames).map_err(|e| (0, e.to_string()))?;
+        let mut ack = [0u8; 4];
+        stream.read_exact(&mut ack).map_err(|e| (0, e.to_string()))?;
+        let acknowledged = u32::from_be_bytes(ack) as usize;
+        if acknowledged != records.len() {
+            return Err((acknowledged.min(records.len()), format!("clocktower acknowledged {} of {} records", acknowledged, records.len())));
+        }
+        return Ok(());
+    }
+
+    /// Delivers a batch, on failure returning how many of its records the collector took along with the error
+    fn reliable_deliver(target: &ReliableTarget, connection: &mut Option<std::net::TcpStream>, records: &[Vec<u8>]) -> Result<(), (usize, DeliveryError)> {
+        match target {
+            ReliableTarget::Tcp(address) => {
+                return tcp_deliver(address, connection, records).map_err(|(delivered, e)| (delivered, DeliveryError::Failed(e)));
+            },
+            ReliableTarget::Http { address, path } => {
+                let mut body: Vec<u8> = vec![b'['];
+                for (index, record) in records.iter().enumerate() {
+                    if index > 0 {
+                        body.push(b',');
+                    }
+                    body.extend_from_slice(record);
+                }
+                body.push(b']');
+                return http_post(address, path, "application/json", &body).map_err(|e| (0, e));
+            }
+        }
+    }
+
+    /// Owns the spool, delivering it oldest first in batches and backing off exponentially while the collector is away
+    fn reliable_writer_thread(target: ReliableTarget, mut spool: Spool, rx: std::sync::mpsc::Receiver<Vec<u8>>) {
+        let mut connection: Option<std::net::TcpStream> = None;
+        let mut retry_at: f64 = 0.0;
+        let mut backoff: f64 = RETRY_MIN;
+        // The monotonic clock starts near zero, the first failure should not wait ten seconds to be reported
+        let mut last_error_printed: f64 = f64::NEG_INFINITY;
+        let mut dropped_reported: u64 = 0;
+        let mut rejected: u64 = 0;
+        let mut last_persisted: f64 = f64::NEG_INFINITY;
+        loop {
+            let current_time = super::util::get_monotonic_timestamp();
+            let wait = if spool.is_empty() { RETRY_MIN } else { (retry_at - current_time).max(0.0) };
+            match rx.recv_timeout(std::time::Duration::from_secs_f64(wait)) {
+                Ok(record) => {
+                    spool.push(record);
+                },
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {},
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                    spool.persist();
+                    return;
+                }
+            }
+            let current_time = super::util::get_monotonic_timestamp();
+            if current_time < retry_at {
+                // Records arriving while the collector is away should survive the process going down
+                if (current_time - last_persisted) > SPOOL_PERSIST_INTERVAL {
+                    spool.persist();
+                    last_persisted = current_time;
+                }
+                continue;
+            }
+            loop {
+                while let Ok(record) = rx.try_recv() {
+                    spool.push(record);
+                }
+                let batch = match spool.peek(SPOOL_BATCH_RECORDS) {
+                    Some(batch) => batch,
+                    None => {
+                        break;
+                    }
+                };
+                let batch_records = batch.records.len();
+                match reliable_deliver(&target, &mut connection, &batch.records) {
+                    Ok(()) => {
+                        spool.commit(batch, batch_records);
+                        backoff = RETRY_MIN;
+                    },
+                    Err((_, DeliveryError::Rejected(e))) => {
+                        // Retrying would be refused the same way and hold up everything behind the batch
+                        spool.commit(batch, batch_records);
+                        rejected += batch_records as u64;
+                        let current_time = super::util::get_monotonic_timestamp();
+                        if (current_time - last_error_printed) > 10.0 {
+                            eprintln!("{:?} rejected {} statistics records, {} dropped so far: {}", target, batch_records, rejected, e);
+                            last_error_printed = current_time;
+                        }
+                    },
+                    Err((delivered, DeliveryError::Failed(e))) => {
+                        // Only what the collector did not take is sent again
+                        spool.commit(batch, delivered);
+                        spool.persist();
+                        let current_time = super::util::get_monotonic_timestamp();
+                        last_persisted = current_time;
+                        if (current_time - last_error_printed) > 10.0 {
+                            eprintln!("failed to deliver statistics to {:?}, retrying in {} s: {}", target, backoff, e);
+                            last_error_printed = current_time;
+                        }
+                        connection = None;
+                        retry_at = current_time + backoff;
+                        backoff = (backoff * 2.0).min(RETRY_MAX);
+                        break;
+                    }
+                }
+            }
+            if spool.dropped() > dropped_reported {
+                let current_time = super::util::get_monotonic_timestamp();
+                if (current_time - last_error_printed) > 10.0 {
+                    eprintln!("statistics spool for {:?} full, {} records dropped so far", target, spool.dropped());
+                    last_error_printed = current_time;
+                    dropped_reported = spool.dropped();
+                }
+            }
+        }
+    }
+
+    enum SinkTransport {
+        Udp(std::net::UdpSocket),
+        Http(std::sync::mpsc::SyncSender<Vec<u8>>),
+        Reliable(std::sync::mpsc::SyncSender<Vec<u8>>),
+    }
+
+    /// A connected statistics destination, sending never blocks on the network
+    pub struct StatsSink {
+        destination: StatsDestination,
+        transport: SinkTransport,
+        host: std::string::String,
+        statsd: Option<StatsdEncoder>,
+        otlp: Option<OtlpEncoder>,
+        last_error_printed: f64,
+    }
+
+    impl StatsSink {
+        /// `service_name` identifies the reporting program where the output format has room for it
+        pub fn new(destination: &StatsDestination, service_name: &str) -> std::io::Result<StatsSink> {
+            let host = super::util::get_hostname();
+            let transport = match destination {
+                StatsDestination::Clocktower(address) | StatsDestination::InfluxUdp(address) | StatsDestination::Statsd(address) | StatsDestination::DogStatsd(address) => SinkTransport::Udp(connect_udp(address)?),
+                StatsDestination::InfluxHttp { address, path } | StatsDestination::OtlpHttp { address, path } => {
+                    resolve(address)?;
+                    let (content_type, concatenate) = match destination {
+                        StatsDestination::OtlpHttp { .. } => ("application/json", false),
+                        _ => ("text/plain; charset=utf-8", true),
+                    };
+                    let (tx, rx) = std::sync::mpsc::sync_channel(HTTP_QUEUE_LENGTH);
+                    let address = address.clone();
+                    let path = path.clone();
+                    std::thread::spawn(move || {
+                        http_writer_thread(address, path, content_type, concatenate, rx);
+                    });
+                    SinkTransport::Http(tx)
+                },
+                StatsDestination::ReliableTcp { spool, .. } | StatsDestination::ReliableHttp { spool, .. } => {
+                    let target = match destination {
+                        StatsDestination::ReliableHttp { address, path, .. } => ReliableTarget::Http { address: address.clone(), path: path.clone() },
+                        StatsDestination::ReliableTcp { address, .. } => ReliableTarget::Tcp(address.clone()),
+                        _ => unreachable!(),
+                    };
+                    // Records only pass through here on their way to the spool, which enforces the limits
+                    let (tx, rx) = std::sync::mpsc::sync_channel(spool.memory_records.max(HTTP_QUEUE_LENGTH));
+                    let spool = Spool::open(spool)?;
+                    std::thread::spawn(move || {
+                        reliable_writer_thread(target, spool, rx);
+                    });
+                    SinkTransport::Reliable(tx)
+                },
+            };
+            return Ok(StatsSink {
+                destination: destination.clone(),
+                transport: transport,
+                statsd: match destination {
+                    StatsDestination::Statsd(_) => Some(StatsdEncoder::new(false)),
+                    StatsDestination::DogStatsd(_) => Some(StatsdEncoder::new(true)),
+                    _ => None,
+                },
+                otlp: match destination {
+                    StatsDestination::OtlpHttp { .. } => Some(OtlpEncoder::new(service_name, &host)),
+                    _ => None,
+                },
+                host: host,
+                last_error_printed: f64::NEG_INFINITY,
+            });
+        }
+
+        /// One or more payloads, each sent as a datagram or request of its own
+        fn encode(&mut self, record: &dyn StatisticsRecord) -> Vec<Vec<u8>> {
+            if let Some(otlp) = &mut self.otlp {
+                let request = otlp.encode(&record.points(), super::util::get_monotonic_timestamp());
+                return vec![request.to_string().into_bytes()];
+            }
+            if let Some(statsd) = &mut self.statsd {
+                let now = super::util::get_monotonic_timestamp();
+                let mut payloads: Vec<Vec<u8>> = Vec::new();
+                let mut datagram = std::string::String::new();
+                for point in record.points() {
+                    for line in statsd.encode(&point, &self.host, now) {
+                        if !datagram.is_empty() && datagram.len() + line.len() + 1 > STATSD_MAX_DATAGRAM {
+                            payloads.push(std::mem::take(&mut datagram).into_bytes());
+                        }
+                        if !datagram.is_empty() {
+                            datagram.push('\n');
+                        }
+                        datagram.push_str(&line);
+                    }
+                }
+                if !datagram.is_empty() {
+                    payloads.push(datagram.into_bytes());
+                }
+                return payloads;
+            }
+            match self.destination {
+                StatsDestination::InfluxUdp(_) | StatsDestination::InfluxHttp { .. } => {
+                    let mut lines = std::string::String::new();
+                    for point in record.points() {
+                        lines.push_str(&to_influx_line(&point, &self.host));
+                        lines.push('\n');
+                    }
+                    return vec![lines.into_bytes()];
+                },
+                _ => {
+                    match record.to_json() {
+                        Ok(json) => {
+                            return vec![json.into_bytes()];
+                        },
+                        Err(e) => {
+                            eprintln!("failed to serialize statistics: {}", e);
+                            return Vec::new();
+                        }
+                    }
+                }
+            }
+        }
+
+        pub fn send(&mut self, record: &dyn StatisticsRecord) {
+            for payload in self.encode(record) {
+                let result = match &self.transport {
+                    SinkTransport::Udp(socket) => socket.send(&payload).map(|_| ()).map_err(|e| e.to_string()),
+                    SinkTransport::Http(tx) | SinkTransport::Reliable(tx) => tx.try_send(payload).map_err(|e| e.to_string()),
+                };
+                if let Err(e) = result {
+                    let current_time = super::util::get_monotonic_timestamp();
+                    if (current_time - self.last_error_printed) > 10.0 {
+                        eprintln!("failed to send statistics to {:?}: {}", self.destination, e);
+                        self.last_error_printed = current_time;
+                    }
+                }
+            }
+        }
+    }
+}
+#[cfg(target_os = "linux")]
+pub mod sockaddr {
+    pub fn sockaddr_to_socket_addr(storage: &libc::sockaddr_storage) -> Option<std::net::SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+                let ip = std::net::Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                return Some(std::net::SocketAddr::new(std::net::IpAddr::V4(ip), u16::from_be(sin.sin_port)));
+            },
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+                let ip = std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                return Some(std::net::SocketAddr::V6(std::net::SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)));
+            },
+            _ => {
+                return None;
+            }
+        }
+    }
+
+    pub fn socket_addr_to_sockaddr(addr: &std::net::SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            std::net::SocketAddr::V4(v4) => {
+                let sin = unsafe { &mut *(storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = v4.port().to_be();
+                sin.sin_addr = libc::in_addr { s_addr: u32::from(*v4.ip()).to_be() };
+                return std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            },
+            std::net::SocketAddr::V6(v6) => {
+                let sin6 = unsafe { &mut *(storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = v6.port().to_be();
+                sin6.sin6_flowinfo = v6.flowinfo();
+                sin6.sin6_addr = libc::in6_addr { s6_addr: v6.ip().octets() };
+                sin6.sin6_scope_id = v6.scope_id();
+                return std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            },
+        }
+    }
+}
+
+/// Kernel (SO_TIMESTAMPING) receive and transmit timestamps with a userspace fallback
+pub mod timestamping {
+    #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, schemars::JsonSchema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum TimestampSource {
+        Userspace,
+        Software,
+        Hardware,
+    }
+
+    impl std::fmt::Display for TimestampSource {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                TimestampSource::Userspace => write!(f, "userspace"),
+                TimestampSource::Software => write!(f, "software"),
+                TimestampSource::Hardware => write!(f, "hardware"),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum TimestampingMode {
+        Off,
+        Software,
+        Hardware,
+        /// Software receive timestamps only, what `enable` falls back to without SO_TIMESTAMPING
+        ReceiveOnly,
+    }
+
+    impl TimestampingMode {
+        /// Whether sends get timestamps from the socket error queue
+        pub fn transmit(&self) -> bool {
+            return *self == TimestampingMode::Software || *self == TimestampingMode::Hardware;
+        }
+    }
+
+    impl std::str::FromStr for TimestampingMode {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<TimestampingMode, String> {
+            match s {
+                "off" => return Ok(TimestampingMode::Off),
+                "software" => return Ok(TimestampingMode::Software),
+                "hardware" => return Ok(TimestampingMode::Hardware),
+                _ => return Err(format!("unknown timestamping mode '{}', expected off, software or hardware", s)),
+            }
+        }
+    }
+
+    /// Kernel timestamps further than this from the userspace wall clock reading of the same
+    /// event are assumed to straddle a clock step and are not mapped onto the monotonic clock
+    const MAX_KERNEL_OFFSET: f64 = 1.0;
+
+    /// Every timestamp available for one send or receive event. Intervals are measured on the
+    /// monotonic clock, software kernel timestamps are mapped onto it through their offset from
+    /// the wall clock reading taken at the same time. Raw hardware timestamps are only comparable
+    /// with each other.
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub struct Timestamps {
+        pub monotonic: f64,
+        pub wall: f64,
+        pub software: Option<f64>,
+        pub hardware: Option<f64>,
+    }
+
+    impl Timestamps {
+        pub fn now() -> Timestamps {
+            return Timestamps::at(super::util::get_monotonic_timestamp(), super::util::get_timestamp());
+        }
+
+        pub fn at(monotonic: f64, wall: f64) -> Timestamps {
+            return Timestamps {
+                monotonic: monotonic,
+                wall: wall,
+                software: None,
+                hardware: None,
+            };
+        }
+
+        fn kernel_offset(&self) -> Option<f64> {
+            let offset = self.software? - self.wall;
+            if offset.abs() < MAX_KERNEL_OFFSET {
+                return Some(offset);
+            }
+            return None;
+        }
+
+        /// Monotonic time of the event, corrected by the kernel timestamp when there is one
+        pub fn event_monotonic(&self) -> f64 {
+            return self.monotonic + self.kernel_offset().unwrap_or(0.0);
+        }
+
+        /// Wall clock time of the event for reporting, preferring the kernel timestamp
+        pub fn event_wall(&self) -> f64 {
+            return self.software.unwrap_or(self.wall);
+        }
+
+        pub fn wall_source(&self) -> TimestampSource {
+            if self.kernel_offset().is_some() {
+                return TimestampSource::Software;
+            }
+            return TimestampSource::Userspace;
+        }
+
+        /// Most precise source available for this event
+        pub fn best_source(&self) -> TimestampSource {
+            if self.hardware.is_some() {
+                return TimestampSource::Hardware;
+            }
+            return self.wall_source();
+        }
+
+        /// Time from this event until `later` using the most precise source both events have
+        pub fn elapsed_until(&self, later: &Timestamps) -> (f64, TimestampSource) {
+            if let (Some(start), Some(end)) = (self.hardware, later.hardware) {
+                return (end - start, TimestampSource::Hardware);
+            }
+            let elapsed = later.event_monotonic() - self.event_monotonic();
+            if self.kernel_offset().is_some() && later.kernel_offset().is_some() {
+                return (elapsed, TimestampSource::Software);
+            }
+            return (later.monotonic - self.monotonic, TimestampSource::Userspace);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    const SOF_TIMESTAMPING_TX_HARDWARE: u32 = 1 << 0;
+    #[cfg(target_os = "linux")]
+    const SOF_TIMESTAMPING_TX_SOFTWARE: u32 = 1 << 1;
+    #[cfg(target_os = "linux")]
+    const SOF_TIMESTAMPING_RX_HARDWARE: u32 = 1 << 2;
+    #[cfg(target_os = "linux")]
+    const SOF_TIMESTAMPING_RX_SOFTWARE: u32 = 1 << 3;
+    #[cfg(target_os = "linux")]
+    const SOF_TIMESTAMPING_SOFTWARE: u32 = 1 << 4;
+    #[cfg(target_os = "linux")]
+    const SOF_TIMESTAMPING_RAW_HARDWARE: u32 = 1 << 6;
+    #[cfg(target_os = "linux")]
+    const SOF_TIMESTAMPING_OPT_ID: u32 = 1 << 7;
+    #[cfg(target_os = "linux")]
+    const SOF_TIMESTAMPING_OPT_TSONLY: u32 = 1 << 11;
+
+    #[cfg(target_os = "linux")]
+    fn setsockopt_int(socket: &std::net::UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        let result = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), level, name, &value as *const libc::c_int as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    /// Enables kernel timestamps on `socket` and returns the mode that took effect. Transmit
+    /// timestamps need SO_TIMESTAMPING, without it the mode drops to SO_TIMESTAMPNS receive timestamps.
+    /// Hardware timestamps also need the interface to be configured for them (e.g. with hwstamp_ctl).
+    #[cfg(target_os = "linux")]
+    pub fn enable(socket: &std::net::UdpSocket, mode: TimestampingMode) -> std::io::Result<TimestampingMode> {
+        if mode == TimestampingMode::Off {
+            return Ok(TimestampingMode::Off);
+        }
+        if mode == TimestampingMode::ReceiveOnly {
+            setsockopt_int(socket, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1)?;
+            return Ok(TimestampingMode::ReceiveOnly);
+        }
+        let mut flags = SOF_TIMESTAMPING_RX_SOFTWARE | SOF_TIMESTAMPING_TX_SOFTWARE | SOF_TIMESTAMPING_SOFTWARE | SOF_TIMESTAMPING_OPT_ID | SOF_TIMESTAMPING_OPT_TSONLY;
+        if mode == TimestampingMode::Hardware {
+            flags |= SOF_TIMESTAMPING_RX_HARDWARE | SOF_TIMESTAMPING_TX_HARDWARE | SOF_TIMESTAMPING_RAW_HARDWARE;
+        }
+        if let Err(e) = setsockopt_int(socket, libc::SOL_SOCKET, libc::SO_TIMESTAMPING, flags as libc::c_int) {
+            setsockopt_int(socket, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1).map_err(|_| e)?;
+            return Ok(TimestampingMode::ReceiveOnly);
+        }
+        return Ok(mode);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn enable(_socket: &std::net::UdpSocket, mode: TimestampingMode) -> std::io::Result<TimestampingMode> {
+        if mode == TimestampingMode::Off {
+            return Ok(TimestampingMode::Off);
+        }
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, "kernel timestamps are only supported on Linux"));
+    }
+
+    #[cfg(target_os = "linux")]
+    fn timespec_to_f64(ts: &libc::timespec) -> Option<f64> {
+        if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+            return None;
+        }
+        return Some(ts.tv_sec as f64 + (ts.tv_nsec as f64 / 1_000_000_000.0));
+    }
+
+    /// Fills the kernel timestamps found in the control messages of a received `msghdr`
+    #[cfg(target_os = "linux")]
+    pub fn parse_control_messages(header: &libc::msghdr, timestamps: &mut Timestamps) -> Option<u32> {
+        let mut key: Option<u32> = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(header) };
+        while !cmsg.is_null() {
+            let cmsg_ref = unsafe { &*cmsg };
+            let data = unsafe { libc::CMSG_DATA(cmsg) };
+            if cmsg_ref.cmsg_level == libc::SOL_SOCKET && cmsg_ref.cmsg_type == libc::SCM_TIMESTAMPING {
+                // Software, deprecated and raw hardware timestamps in that order
+                let ts = unsafe { std::ptr::read_unaligned(data as *const [libc::timespec; 3]) };
+                timestamps.software = timespec_to_f64(&ts[0]);
+                timestamps.hardware = timespec_to_f64(&ts[2]);
+            } else if cmsg_ref.cmsg_level == libc::SOL_SOCKET && cmsg_ref.cmsg_type == libc::SCM_TIMESTAMPNS {
+                let ts = unsafe { std::ptr::read_unaligned(data as *const libc::timespec) };
+                timestamps.software = timespec_to_f64(&ts);
+            } else if (cmsg_ref.cmsg_level == libc::SOL_IP && cmsg_ref.cmsg_type == libc::IP_RECVERR) || (cmsg_ref.cmsg_level == libc::SOL_IPV6 && cmsg_ref.cmsg_type == libc::IPV6_RECVERR) {
+                let extended_err = unsafe { std::ptr::read_unaligned(data as *const libc::sock_extended_err) };
+                if extended_err.ee_errno == libc::ENOMSG as u32 && extended_err.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING {
+                    key = Some(extended_err.ee_data);
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(header, cmsg) };
+        }
+        return key;
+    }
+
+    /// Control message space for one SCM_TIMESTAMPING and one extended error
+    #[cfg(target_os = "linux")]
+    pub const CONTROL_BUFFER_SIZE: usize = 256;
+
+    /// Aligned buffer for the control messages of one datagram
+    #[cfg(target_os = "linux")]
+    #[derive(Clone, Copy)]
+    pub struct ControlBuffer {
+        buffer: [u64; CONTROL_BUFFER_SIZE / 8],
+    }
+
+    #[cfg(target_os = "linux")]
+    impl ControlBuffer {
+        pub fn new() -> ControlBuffer {
+            return ControlBuffer {
+                buffer: [0; CONTROL_BUFFER_SIZE / 8],
+            };
+        }
+
+        pub fn attach(&mut self, header: &mut libc::msghdr) {
+            header.msg_control = self.buffer.as_mut_ptr() as *mut libc::c_void;
+            header.msg_controllen = CONTROL_BUFFER_SIZE as _;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Default for ControlBuffer {
+        fn default() -> ControlBuffer {
+            return ControlBuffer::new();
+        }
+    }
+
+    /// Receives one datagram together with its receive timestamps, falling back to userspace time
+    pub struct TimestampedReceiver {
+        #[cfg(target_os = "linux")]
+        control: ControlBuffer,
+    }
+
+    impl TimestampedReceiver {
+        pub fn new() -> TimestampedReceiver {
+            return TimestampedReceiver {
+                #[cfg(target_os = "linux")]
+                control: ControlBuffer::new(),
+            };
+        }
+
+        #[cfg(target_os = "linux")]
+        pub fn recv_from(&mut self, socket: &std::net::UdpSocket, buffer: &mut [u8]) -> std::io::Result<(usize, std::net::SocketAddr, Timestamps)> {
+            use std::os::unix::io::AsRawFd;
+
+            let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+            let mut iovec = libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            };
+            let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+            header.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_iov = &mut iovec;
+            header.msg_iovlen = 1;
+            self.control.attach(&mut header);
+
+            let result = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, 0) };
+            if result < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mut timestamps = Timestamps::now();
+            parse_control_messages(&header, &mut timestamps);
+            let source = match super::sockaddr::sockaddr_to_socket_addr(&addr) {
+                Some(source) => source,
+                None => socket.peer_addr()?,
+            };
+            return Ok((result as usize, source, timestamps));
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        pub fn recv_from(&mut self, socket: &std::net::UdpSocket, buffer: &mut [u8]) -> std::io::Result<(usize, std::net::SocketAddr, Timestamps)> {
+            let (size, source) = socket.recv_from(buffer)?;
+            return Ok((size, source, Timestamps::now()));
+        }
+    }
+
+    impl Default for TimestampedReceiver {
+        fn default() -> TimestampedReceiver {
+            return TimestampedReceiver::new();
+        }
+    }
+
+    /// Matches transmit timestamps from the socket error queue with the sends they belong to.
+    /// Items are released once their timestamp arrives or after `max_wait` seconds without one.
+    pub struct PendingTxTimestamps<T> {
+        pending: std::collections::VecDeque<(u32, Timestamps, T)>,
+        next_key: u32,
+        max_wait: f64,
+        #[cfg(target_os = "linux")]
+        control: ControlBuffer,
+    }
+
+    impl<T> PendingTxTimestamps<T> {
+        pub fn new(max_wait: f64) -> PendingTxTimestamps<T> {
+            return PendingTxTimestamps {
+                pending: std::collections::VecDeque::new(),
+                next_key: 0,
+                max_wait: max_wait,
+                #[cfg(target_os = "linux")]
+                control: ControlBuffer::new(),
+            };
+        }
+
+        /// Records a datagram the kernel accepted, keys follow the SOF_TIMESTAMPING_OPT_ID counter
+        pub fn sent(&mut self, timestamps: Timestamps, item: T) {
+            self.pending.push_back((self.next_key, timestamps, item));
+            self.next_key = self.next_key.wrapping_add(1);
+        }
+
+        pub fn is_empty(&self) -> bool {
+            return self.pending.is_empty();
+        }
+
+        /// Drains the error queue without blocking and hands over every item that is complete or
+        /// expired, `current_time` is a monotonic timestamp
+        pub fn poll(&mut self, socket: &std::net::UdpSocket, current_time: f64, released: &mut Vec<(T, Timestamps)>) {
+            #[cfg(target_os = "linux")]
+            while let Some((key, software, hardware)) = self.read_error_queue(socket) {
+                while let Some((pending_key, _, _)) = self.pending.front() {
+                    // Keys older than the one just read will not get a timestamp anymore
+                    if (key.wrapping_sub(*pending_key) as i32) < 0 {
+                        break;
+                    }
+                    if let Some((pending_key, mut timestamps, item)) = self.pending.pop_front() {
+                        if pending_key == key {
+                            timestamps.software = software;
+                            timestamps.hardware = hardware;
+                        }
+                        released.push((item, timestamps));
+                    }
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = socket;
+
+            while let Some((_, timestamps, _)) = self.pending.front() {
+                if (current_time - timestamps.monotonic) < self.max_wait {
+                    break;
+                }
+                if let Some((_, timestamps, item)) = self.pending.pop_front() {
+                    released.push((item, timestamps));
+                }
+            }
+        }
+
+        /// The next transmit timestamp in the error queue, None once it is drained. Entries
+        /// without one, such as ICMP errors, are skipped.
+        #[cfg(target_os = "linux")]
+        fn read_error_queue(&mut self, socket: &std::net::UdpSocket) -> Option<(u32, Option<f64>, Option<f64>)> {
+            use std::os::unix::io::AsRawFd;
+
+            loop {
+                let mut data = [0u8; 64];
+                let mut iovec = libc::iovec {
+                    iov_base: data.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: data.len(),
+                };
+                let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+                header.msg_iov = &mut iovec;
+                header.msg_iovlen = 1;
+                self.control.attach(&mut header);
+
+                let result = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+                if result < 0 {
+                    if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return None;
+                }
+                let mut timestamps = Timestamps::at(0.0, 0.0);
+                if let Some(key) = parse_control_messages(&header, &mut timestamps) {
+                    return Some((key, timestamps.software, timestamps.hardware));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+extern crate metronome_lib;
+
+use metronome_lib::client::{ClientSession, RTTMeasurement, SentPings};
+use metronome_lib::datatypes::SessionContainer;
+use metronome_lib::schema::ServerSessionStatistics;
+use metronome_lib::timestamping::{TimestampSource, Timestamps};
+
+const WALL_START: f64 = 1_600_000_000.0;
+const CLOCK_STEP: f64 = -3600.0;
+
+fn assert_close(actual: f64, expected: f64) {
+    assert!((actual - expected).abs() < 1e-6, "expected {}, got {}", expected, actual);
+}
+
+/// Timestamps of a message sent every 10 ms while the wall clock steps back an hour after `step_at` messages
+fn stepped_timestamps(index: u64, step_at: u64) -> Timestamps {
+    let monotonic = index as f64 * 0.01;
+    let mut wall = WALL_START + monotonic;
+    if index >= step_at {
+        wall += CLOCK_STEP;
+    }
+    return Timestamps::at(monotonic, wall);
+}
+
+#[test]
+fn session_survives_wall_clock_step() {
+    let mut session = SessionContainer::new(0, 100, &stepped_timestamps(0, 50));
+    for seq in 1..100 {
+        // One message goes missing just after the step
+        if seq == 60 {
+            continue;
+        }
+        session.seq_analyze(seq, 100, &stepped_timestamps(seq, 50));
+    }
+
+    // The missing message doubles one gap, the rest stay at 10 ms across the step
+    let intermessage_gap = session.intermessage_gap_mavg.unwrap();
+    assert!(intermessage_gap > 0.01 && intermessage_gap < 0.011, "unexpected intermessage gap {}", intermessage_gap);
+    assert_close(session.last_rx, 0.99);
+    assert_close(session.last_rx_wall, WALL_START + 0.99 + CLOCK_STEP);
+
+    // The hole is 0.39 s old on the monotonic clock, a one second hole timeout must keep it
+    session.prune_holes(session.last_rx - 1.0);
+    assert_eq!(session.holes_created, 1);
+    assert_eq!(session.holes_timed_out, 0);
+    assert_eq!(session.holes.len(), 1);
+
+    session.prune_holes(session.last_rx);
+    assert_eq!(session.holes_timed_out, 1);
+}
+
+#[test]
+fn rtt_across_wall_clock_step_uses_monotonic_time() {
+    let sent = Timestamps::at(10.0, WALL_START);
+    let received = Timestamps::at(10.025, WALL_START + 0.025 + CLOCK_STEP);
+
+    let (rtt, source) = sent.elapsed_until(&received);
+    assert_close(rtt, 0.025);
+    assert_eq!(source, TimestampSource::Userspace);
+}
+
+#[test]
+fn software_timestamps_are_mapped_onto_monotonic_clock() {
+    let mut sent = Timestamps::at(10.0, WALL_START);
+    // The kernel stamps the transmit after the userspace reading and the receive before it
+    sent.software = Some(WALL_START + 0.0001);
+    let mut received = Timestamps::at(10.025, WALL_START + 0.025 + CLOCK_STEP);
+    received.software = Some(WALL_START + 0.0248 + CLOCK_STEP);
+
+    let (rtt, source) = sent.elapsed_until(&received);
+    assert_close(rtt, 0.0247);
+    assert_eq!(source, TimestampSource::Software);
+    assert_close(received.event_wall(), WALL_START + 0.0248 + CLOCK_STEP);
+}
+
+#[test]
+fn kernel_timestamp_straddling_step_falls_back_to_userspace() {
+    let mut sent = Timestamps::at(10.0, WALL_START);
+    sent.software = Some(WALL_START + 0.0001);
+    // The step happened between the kernel timestamp and the userspace reading
+    let mut received = Timestamps::at(10.025, WALL_START + 0.025 + CLOCK_STEP);
+    received.software = Some(WALL_START + 0.0248);
+
+    assert_eq!(received.wall_source(), TimestampSource::Userspace);
+    let (rtt, source) = sent.elapsed_until(&received);
+    assert_close(rtt, 0.025);
+    assert_eq!(source, TimestampSource::Userspace);
+}
+
+#[test]
+fn interval_starts_over_with_each_report() {
+    let mut session = SessionContainer::new(0, 100, &stepped_timestamps(0, 1000));
+    for seq in 1..100 {
+        session.seq_analyze(seq, 100, &stepped_timestamps(seq, 1000));
+    }
+    let first = ServerSessionStatistics::next_report("default", "s1", &mut session, false, 1.0);
+    assert_eq!(first.interval_received_messages, 100);
+    assert_close(first.interval_duration.unwrap(), 1.0);
+    assert_close(first.interval_received_pps.unwrap(), 100.0);
+    assert_eq!(first.interval_loss_percent, Some(0.0));
+
+    // Half as many messages with one hole timing out during the next second
+    for seq in 101..150 {
+        session.seq_analyze(seq, 100, &stepped_timestamps(seq, 1000));
+    }
+    session.prune_holes(2.0);
+    let second = ServerSessionStatistics::next_report("default", "s1", &mut session, false, 2.0);
+    assert_eq!(second.received_messages, 149);
+    assert_eq!(second.interval_received_messages, 49);
+    assert_eq!(second.interval_lost_messages, 1);
+    assert_close(second.interval_loss_percent.unwrap(), 2.0);
+    assert_close(second.interval_received_pps.unwrap(), 49.0);
+
+    let idle = ServerSessionStatistics::next_report("default", "s1", &mut session, false, 3.0);
+    assert_eq!(idle.interval_received_messages, 0);
+    assert_eq!(idle.interval_loss_percent, None);
+}
+
+#[test]
+fn client_interval_starts_at_the_first_unanswered_ping() {
+    let sent_pings = std::sync::Arc::new(SentPings::new(256));
+    let mut session = ClientSession::new(sent_pings.clone(), WALL_START);
+    for seq in 0..100 {
+        let rtt_measurement = RTTMeasurement {
+            seq: seq,
+            timestamps: stepped_timestamps(seq, 1000),
+            scheduled: seq as f64 * 0.01,
+            catch_up: false,
+            skipped: 0,
+            message_raw_size: 100,
+        };
+        sent_pings.sending(&rtt_measurement);
+        sent_pings.sent(&rtt_measurement);
+    }
+    // Nothing came back, all of them are counted when the report is due
+    let report = session.statistics(WALL_START + 1.0, "s1", 1.0);
+    assert_eq!(report.sent_messages, 100);
+    assert_eq!(report.inflight_messages, 100);
+    assert_eq!(report.interval_sent_messages, 100);
+    assert_close(report.interval_duration.unwrap(), 1.0);
+}
+
+#[test]
+fn hub_throughput_counts_both_directions() {
+    let mut session = SessionContainer::new(0, 100, &stepped_timestamps(0, 1000));
+    session.ip_overhead = metronome_lib::datatypes::ip_overhead(&"[2001:db8::1]:5555".parse().unwrap());
+    session.sent(120);
+    for seq in 1..100 {
+        session.seq_analyze(seq, 100, &stepped_timestamps(seq, 1000));
+        session.sent(120);
+    }
+    let report = ServerSessionStatistics::next_report("default", "s1", &mut session, false, 1.0);
+    assert_eq!(report.sent_messages, 100);
+    assert_eq!(report.sent_bytes, 12000);
+    assert_eq!(report.interval_received_bytes, 10000);
+    assert_eq!(report.interval_received_ip_bytes, 10000 + 100 * 48);
+    assert_eq!(report.interval_sent_wire_bytes, 12000 + 100 * 66);
+    assert_close(report.interval_sent_bps.unwrap(), (12000.0 + 6600.0) * 8.0);
+    assert_close(report.interval_received_goodput_bps.unwrap(), 80000.0);
+
+    let idle = ServerSessionStatistics::next_report("default", "s1", &mut session, false, 2.0);
+    assert_eq!(idle.sent_bytes, 12000);
+    assert_eq!(idle.interval_sent_bytes, 0);
+    assert_close(idle.interval_sent_bps.unwrap(), 0.0);
+}
@@ -0,0 +1,45 @@
+use metronome_lib::client::{ClientSession, ClientSessionStatistics, RTTMeasurement, SentPings};
+use metronome_lib::clock::{Clock, SimulatedClock};
+use metronome_lib::datatypes::{MessageWithSize, MetronomeMessage, TimestampedMessage};
+
+/// Statistics of a client session whose `count` pings, sent every 10 ms, are each answered 5 ms later
+pub fn client_statistics(count: u64) -> ClientSessionStatistics {
+    let clock = SimulatedClock::new(1_600_000_000.0);
+    let sent_pings = std::sync::Arc::new(SentPings::new(64));
+    let mut session = ClientSession::new(sent_pings.clone(), clock.wall());
+    for seq in 0..count {
+        clock.advance_to(seq as f64 * 0.01);
+        let ping = MetronomeMessage {
+            mode: "ping".to_string(),
+            payload: Some("X".repeat(16)),
+            mul: 1.0,
+            seq: seq,
+            key: "key".to_string(),
+            sid: "s1".to_string(),
+            hub_stats_requested: false,
+            hub_stats: None,
+            payload_checksum: None,
+        };
+        let message_raw_size = ping.as_vec().unwrap().len();
+        let rtt_measurement = RTTMeasurement {
+            seq: seq,
+            timestamps: clock.now(),
+            scheduled: clock.monotonic(),
+            catch_up: false,
+            skipped: 0,
+            message_raw_size: message_raw_size,
+        };
+        sent_pings.sending(&rtt_measurement);
+        sent_pings.sent(&rtt_measurement);
+
+        clock.advance(0.005);
+        session.received(&TimestampedMessage {
+            timestamps: clock.now(),
+            message_with_size: MessageWithSize {
+                message_raw_size: message_raw_size,
+                message: ping.get_pong(),
+            },
+        });
+    }
+    return session.statistics(clock.wall(), "s1", clock.monotonic());
+}
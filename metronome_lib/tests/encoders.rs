@@ -0,0 +1,202 @@
+extern crate metronome_lib;
+#[macro_use] extern crate serde_json;
+
+use metronome_lib::histogram::Histogram;
+use metronome_lib::stats::{OtlpEncoder, StatisticsPoint, StatsdEncoder, to_influx_line};
+
+// 2023-11-14T22:13:20Z, exactly representable in nanoseconds as f64
+const TIMESTAMP: f64 = 1700000000.0;
+
+fn session_point(sid: &str, sent: u64, lost: u64) -> StatisticsPoint {
+    let mut point = StatisticsPoint::new("metronome_client_session", TIMESTAMP);
+    point.tag("sid", sid);
+    point.counter("sent_messages", sent);
+    point.counter("forward_lost_messages", lost);
+    point.gauge("rtt_mavg", 0.25);
+    point.text("rtt_timestamp_source", "userspace");
+    return point;
+}
+
+#[test]
+fn influx_line_format() {
+    let mut point = session_point("s1", 10, 3);
+    point.tag("tenant", "default");
+    point.gauge("intermessage_gap_mavg", f64::NAN);
+    let mut histogram = Histogram::new();
+    histogram.record(0.001);
+    point.histogram("rtt", &histogram);
+
+    // Tags sorted by key with host among them, text fields quoted, non-finite gauges and histograms left out
+    assert_eq!(to_influx_line(&point, "probe-1"),
+        "metronome_client_session,host=probe-1,sid=s1,tenant=default sent_messages=10i,forward_lost_messages=3i,rtt_mavg=0.25,rtt_timestamp_source=\"userspace\" 1700000000000000000");
+}
+
+#[test]
+fn influx_escaping() {
+    let mut point = StatisticsPoint::new("metronome client,session", TIMESTAMP);
+    point.tag("sid", "s 1,a=b");
+    point.tag("empty", "");
+    point.counter("sent messages", 5);
+    point.gauge("odd,name=x", 1.5);
+    point.text("note", "say \"hi\" \\o/");
+
+    assert_eq!(to_influx_line(&point, "probe 1"),
+        "metronome\\ client\\,session,host=probe\\ 1,sid=s\\ 1\\,a\\=b sent\\ messages=5i,odd\\,name\\=x=1.5,note=\"say \\\"hi\\\" \\\\o/\" 1700000000000000000");
+}
+
+#[test]
+fn statsd_reports_counter_increments() {
+    let mut encoder = StatsdEncoder::new(false);
+    assert_eq!(encoder.encode(&session_point("s1", 10, 3), "probe-1", 0.0), vec![
+        "metronome.client_session.s1.sent_messages:10|c",
+        "metronome.client_session.s1.forward.lost_messages:3|c",
+        "metronome.client_session.s1.rtt_mavg:0.25|g",
+    ]);
+    assert_eq!(encoder.encode(&session_point("s1", 15, 3), "probe-1", 1.0), vec![
+        "metronome.client_session.s1.sent_messages:5|c",
+        "metronome.client_session.s1.forward.lost_messages:0|c",
+        "metronome.client_session.s1.rtt_mavg:0.25|g",
+    ]);
+    // A counter going backwards was reset and counts from zero
+    assert_eq!(encoder.encode(&session_point("s1", 4, 3), "probe-1", 2.0)[0], "metronome.client_session.s1.sent_messages:4|c");
+    // Other sessions have their own baselines
+    assert_eq!(encoder.encode(&session_point("s2", 7, 0), "probe-1", 3.0)[0], "metronome.client_session.s2.sent_messages:7|c");
+}
+
+#[test]
+fn statsd_escaping() {
+    let mut point = StatisticsPoint::new("metronome_client_session", TIMESTAMP);
+    point.tag("sid", "s1.x|y:z");
+    point.counter("odd:name|c", 1);
+
+    let mut encoder = StatsdEncoder::new(false);
+    assert_eq!(encoder.encode(&point, "probe-1", 0.0), vec!["metronome.client_session.s1_x_y_z.odd_name_c:1|c"]);
+}
+
+#[test]
+fn dogstatsd_format() {
+    let mut encoder = StatsdEncoder::new(true);
+    assert_eq!(encoder.encode(&session_point("s1", 10, 3), "probe-1", 0.0), vec![
+        "metronome.client_session.sent_messages:10|c|#sid:s1,host:probe-1",
+        "metronome.client_session.lost_messages:3|c|#sid:s1,direction:forward,host:probe-1",
+        "metronome.client_session.rtt_mavg:0.25|g|#sid:s1,host:probe-1",
+    ]);
+}
+
+#[test]
+fn dogstatsd_escaping() {
+    let mut point = StatisticsPoint::new("metronome_hub_session", TIMESTAMP);
+    point.tag("tenant", "");
+    point.tag("sid", "a,b|c#d:e");
+    point.counter("odd:name|c", 1);
+
+    let mut encoder = StatsdEncoder::new(true);
+    // Empty tags are left out, colons are fine within tag values
+    assert_eq!(encoder.encode(&point, "probe,1", 0.0), vec!["metronome.hub_session.odd_name_c:1|c|#sid:a_b_c_d:e,host:probe_1"]);
+}
+
+#[test]
+fn otlp_json_format() {
+    let mut point = StatisticsPoint::new("metronome_client_session", TIMESTAMP);
+    point.tag("sid", "s \"1\"");
+    point.counter("sent_messages", 10);
+    point.gauge("rtt_mavg", 0.25);
+    point.gauge("intermessage_gap_mavg", f64::INFINITY);
+    point.text("rtt_timestamp_source", "userspace");
+
+    let mut encoder = OtlpEncoder::new("metronome-client", "probe-1");
+    let attributes = json!([{ "key": "sid", "value": { "stringValue": "s \"1\"" } }]);
+    assert_eq!(encoder.encode(&[point.clone()], 0.0), json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": "metronome-client" } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                    { "key": "host.name", "value": { "stringValue": "probe-1" } },
+                ],
+            },
+            "scopeMetrics": [{
+                "scope": { "name": "metronome", "version": env!("CARGO_PKG_VERSION") },
+                "metrics": [
+                    {
+                        "name": "metronome.client_session.sent_messages",
+                        "sum": {
+                            "dataPoints": [{
+                                "attributes": attributes,
+                                "startTimeUnixNano": "1700000000000000000",
+                                "timeUnixNano": "1700000000000000000",
+                                "asInt": "10",
+                            }],
+                            "aggregationTemporality": 2,
+                            "isMonotonic": true,
+                        },
+                    },
+                    {
+                        "name": "metronome.client_session.rtt_mavg",
+                        "gauge": {
+                            "dataPoints": [{
+                                "attributes": attributes,
+                                "timeUnixNano": "1700000000000000000",
+                                "asDouble": 0.25,
+                            }],
+                        },
+                    },
+                ],
+            }],
+        }],
+    }));
+}
+
+#[test]
+fn otlp_series_start_moves_on_counter_reset() {
+    let mut encoder = OtlpEncoder::new("metronome-client", "probe-1");
+    let start = |request: &serde_json::Value| request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0]["sum"]["dataPoints"][0]["startTimeUnixNano"].clone();
+    let mut point = session_point("s1", 10, 0);
+    assert_eq!(start(&encoder.encode(&[point.clone()], 0.0)), "1700000000000000000");
+    point.timestamp = TIMESTAMP + 1.0;
+    point.fields[0].1 = metronome_lib::stats::FieldValue::Counter(20);
+    assert_eq!(start(&encoder.encode(&[point.clone()], 1.0)), "1700000000000000000");
+    // The reset happened after the previous report
+    point.timestamp = TIMESTAMP + 2.0;
+    point.fields[0].1 = metronome_lib::stats::FieldValue::Counter(5);
+    assert_eq!(start(&encoder.encode(&[point], 2.0)), "1700000001000000000");
+}
+
+#[test]
+fn otlp_series_start_follows_the_record() {
+    let mut encoder = OtlpEncoder::new("metronome-client", "probe-1");
+    let start = |request: &serde_json::Value| request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0]["sum"]["dataPoints"][0]["startTimeUnixNano"].clone();
+    let mut point = session_point("s1", 10, 0);
+    point.start = Some(TIMESTAMP - 10.0);
+    assert_eq!(start(&encoder.encode(&[point.clone()], 0.0)), "1699999990000000000");
+    // A new epoch after a reset is taken as it is
+    point.timestamp = TIMESTAMP + 2.0;
+    point.start = Some(TIMESTAMP + 1.5);
+    point.fields[0].1 = metronome_lib::stats::FieldValue::Counter(5);
+    assert_eq!(start(&encoder.encode(&[point], 1.0)), "1700000001500000000");
+}
+
+#[test]
+fn otlp_histogram_format() {
+    let mut histogram = Histogram::new();
+    histogram.record(0.001);
+    histogram.record(0.003);
+    let mut point = StatisticsPoint::new("metronome_client_session", TIMESTAMP);
+    point.histogram("rtt", &histogram);
+
+    let mut encoder = OtlpEncoder::new("metronome-client", "probe-1");
+    let request = encoder.encode(&[point], 0.0);
+    let metric = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0];
+    assert_eq!(metric["name"], "metronome.client_session.rtt");
+    assert_eq!(metric["unit"], "s");
+    assert_eq!(metric["histogram"]["aggregationTemporality"], 2);
+    let data_point = &metric["histogram"]["dataPoints"][0];
+    assert_eq!(data_point["attributes"], json!([]));
+    assert_eq!(data_point["startTimeUnixNano"], "1700000000000000000");
+    assert_eq!(data_point["count"], "2");
+    assert_eq!(data_point["sum"], json!(histogram.sum()));
+    assert_eq!(data_point["max"], json!(0.003));
+    let bucket_counts: Vec<String> = histogram.bucket_counts().iter().map(|count| count.to_string()).collect();
+    assert_eq!(data_point["bucketCounts"], json!(bucket_counts));
+    assert_eq!(data_point["explicitBounds"], json!(Histogram::explicit_bounds()));
+}
@@ -0,0 +1,97 @@
+extern crate metronome_lib;
+extern crate serde_json;
+
+mod common;
+
+use metronome_lib::stats::{StatsDestination, StatsSink};
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Accepts one request like an OTLP collector would and hands back its path, headers and body
+fn mock_collector() -> (std::net::SocketAddr, std::thread::JoinHandle<(String, Vec<String>, serde_json::Value)>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut headers: Vec<String> = Vec::new();
+        let mut content_length: usize = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                content_length = value.trim().parse().unwrap();
+            }
+            headers.push(line);
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        let path = request_line.split(' ').nth(1).unwrap().to_string();
+        return (path, headers, serde_json::from_slice(&body).unwrap());
+    });
+    return (addr, handle);
+}
+
+fn find_metric<'a>(request: &'a serde_json::Value, name: &str) -> &'a serde_json::Value {
+    let metrics = request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"].as_array().unwrap();
+    return metrics.iter().find(|metric| metric["name"] == name).unwrap_or_else(|| panic!("metric {} missing", name));
+}
+
+#[test]
+fn client_statistics_export_to_collector() {
+    let client = common::client_statistics(100);
+    let (addr, collector) = mock_collector();
+    let destination: StatsDestination = format!("otlp-http://{}", addr).parse().unwrap();
+    let mut sink = StatsSink::new(&destination, "metronome-client").unwrap();
+    sink.send(&client);
+    let (path, headers, request) = collector.join().unwrap();
+
+    assert_eq!(path, "/v1/metrics");
+    assert!(headers.iter().any(|header| header.eq_ignore_ascii_case("content-type: application/json")));
+
+    let resource_attributes = request["resourceMetrics"][0]["resource"]["attributes"].as_array().unwrap();
+    assert!(resource_attributes.iter().any(|attribute| attribute["key"] == "host.name"));
+    assert!(resource_attributes.iter().any(|attribute| attribute["key"] == "service.name" && attribute["value"]["stringValue"] == "metronome-client"));
+
+    let sent = find_metric(&request, "metronome.client_session.sent_messages");
+    assert_eq!(sent["sum"]["isMonotonic"], true);
+    assert_eq!(sent["sum"]["aggregationTemporality"], 2);
+    let sent_point = &sent["sum"]["dataPoints"][0];
+    assert_eq!(sent_point["asInt"], client.sent_messages.to_string());
+    assert_eq!(sent_point["attributes"][0]["key"], "sid");
+    assert_eq!(sent_point["attributes"][0]["value"]["stringValue"], client.sid.as_str());
+
+    let inflight = find_metric(&request, "metronome.client_session.inflight_messages");
+    assert!(inflight["gauge"]["dataPoints"][0]["asDouble"].is_number());
+
+    let rtt = find_metric(&request, "metronome.client_session.rtt");
+    let rtt_point = &rtt["histogram"]["dataPoints"][0];
+    assert_eq!(rtt_point["count"], client.timely_received_messages.to_string());
+    let bucket_counts = rtt_point["bucketCounts"].as_array().unwrap();
+    let explicit_bounds = rtt_point["explicitBounds"].as_array().unwrap();
+    assert_eq!(bucket_counts.len(), explicit_bounds.len() + 1);
+    let bucketed: u64 = bucket_counts.iter().map(|count| count.as_str().unwrap().parse::<u64>().unwrap()).sum();
+    assert_eq!(bucketed, client.timely_received_messages);
+}
+
+#[test]
+fn custom_path_and_empty_histogram_left_out() {
+    let (addr, collector) = mock_collector();
+    let destination: StatsDestination = format!("otlp-http://{}/custom/metrics", addr).parse().unwrap();
+    let mut sink = StatsSink::new(&destination, "metronome-client").unwrap();
+    let mut client = common::client_statistics(100);
+    client.rtt_histogram.reset();
+    sink.send(&client);
+    let (path, _, request) = collector.join().unwrap();
+
+    assert_eq!(path, "/custom/metrics");
+    let metrics = request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"].as_array().unwrap();
+    assert!(metrics.iter().all(|metric| metric.get("histogram").is_none()));
+}
@@ -0,0 +1,53 @@
+extern crate metronome_lib;
+
+use metronome_lib::datatypes::{payload_checksum, MetronomeMessage, PayloadPattern};
+
+#[test]
+fn patterns_parse_and_fill_the_payload() {
+    assert_eq!("fill".parse::<PayloadPattern>().unwrap(), PayloadPattern::Fill('X'));
+    assert_eq!("fill:a".parse::<PayloadPattern>().unwrap(), PayloadPattern::Fill('a'));
+    assert_eq!("custom:abc".parse::<PayloadPattern>().unwrap(), PayloadPattern::Custom("abc".to_string()));
+    assert!("fill:ab".parse::<PayloadPattern>().is_err());
+    assert!("custom:".parse::<PayloadPattern>().is_err());
+    assert!("ones".parse::<PayloadPattern>().is_err());
+
+    assert_eq!(PayloadPattern::default().generate(4, 0, 0), "XXXX");
+    assert_eq!(PayloadPattern::Zeros.generate(3, 0, 0), "\0\0\0");
+    assert_eq!(PayloadPattern::Custom("abc".to_string()).generate(7, 0, 0), "abcabca");
+    // A multi-byte fill character never overshoots the requested size
+    assert_eq!(PayloadPattern::Fill('é').generate(5, 0, 0), "éé");
+
+    for pattern in [PayloadPattern::Random, PayloadPattern::Prbs].iter() {
+        assert!(pattern.per_message());
+        let payload = pattern.generate(1000, 1, 7);
+        assert_eq!(payload.len(), 1000);
+        assert!(payload.bytes().all(|byte| byte < 0x80));
+        assert_ne!(payload, pattern.generate(1000, 2, 7));
+        let mut seen = [false; 128];
+        payload.bytes().for_each(|byte| seen[byte as usize] = true);
+        assert!(seen.iter().filter(|seen| **seen).count() > 100);
+    }
+    // Only random payloads depend on the session seed, PRBS is reproducible from the sequence number alone
+    assert_ne!(PayloadPattern::Random.generate(1000, 1, 7), PayloadPattern::Random.generate(1000, 1, 8));
+    assert_eq!(PayloadPattern::Prbs.generate(1000, 1, 7), PayloadPattern::Prbs.generate(1000, 1, 8));
+}
+
+#[test]
+fn checksum_is_crc32() {
+    assert_eq!(payload_checksum("123456789"), 0xCBF4_3926);
+    assert_eq!(payload_checksum(""), 0);
+}
+
+#[test]
+fn resized_pongs_keep_the_pattern() {
+    let mut message = MetronomeMessage::ping("simulation", "simulation", 1);
+    message.set_payload(PayloadPattern::Custom("abc".to_string()).generate(6, 1, 0), true);
+    message.mul = 1.5;
+    let pong = message.get_pong();
+    assert_eq!(pong.payload.as_deref(), Some("abcabcabc"));
+    assert_eq!(pong.payload_intact(), Some(true));
+
+    let mut message = MetronomeMessage::ping("simulation", "simulation", 1);
+    message.set_payload(PayloadPattern::default().generate(6, 1, 0), false);
+    assert_eq!(message.get_pong().payload_intact(), None);
+}
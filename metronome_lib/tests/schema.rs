@@ -0,0 +1,107 @@
+extern crate metronome_lib;
+extern crate serde_json;
+
+mod common;
+
+use metronome_lib::datatypes::SessionContainer;
+use metronome_lib::schema::{json_schema, AclRuleStatistics, HubAclStatistics, ServerSessionStatistics, Statistics, SCHEMA_VERSION};
+use metronome_lib::stats::StatisticsRecord;
+use metronome_lib::timestamping::Timestamps;
+
+#[test]
+fn checked_in_schema_is_current() {
+    let checked_in: serde_json::Value = serde_json::from_str(include_str!("../../schema/statistics.schema.json")).unwrap();
+    assert!(checked_in == json_schema(), "schema/statistics.schema.json is stale, regenerate it with `cargo run -p metronome_lib --example statistics_schema`");
+}
+
+#[test]
+fn client_statistics_round_trip() {
+    let client = common::client_statistics(100);
+    let json = client.to_json().unwrap();
+    match Statistics::from_json(&json).unwrap() {
+        Statistics::ClientSession(statistics) => {
+            assert_eq!(statistics.schema_version, SCHEMA_VERSION);
+            assert_eq!(statistics.sent_messages, client.sent_messages);
+            assert_eq!(statistics.rtt_best, client.rtt_best);
+            assert_eq!(statistics.to_json().unwrap(), json);
+        },
+        other => panic!("expected client statistics, got {:?}", other),
+    }
+}
+
+#[test]
+fn hub_statistics_round_trip() {
+    let mut session_container = SessionContainer::new(0, 100, &Timestamps::at(1.0, 1600000000.0));
+    let statistics = ServerSessionStatistics::next_report("default", "s1", &mut session_container, true, 2.0);
+    let json = statistics.to_json().unwrap();
+    assert_eq!(Statistics::from_json(&json).unwrap(), Statistics::HubSession(Box::new(statistics)));
+    assert_eq!(session_container.stats_seq, 1);
+
+    let acl_rule = AclRuleStatistics {
+        action: "deny".to_string(),
+        prefix: "192.0.2.0/24".to_string(),
+        hits: 3,
+    };
+    let acl_statistics = HubAclStatistics::new(1600000000.0, vec![acl_rule], "allow", 7);
+    let json = acl_statistics.to_json().unwrap();
+    assert_eq!(Statistics::from_json(&json).unwrap(), Statistics::HubAcl(acl_statistics));
+}
+
+// Reports as the client and hub of the first release sent them, before records were versioned
+const BASELINE_CLIENT_RECORD: &str = r#"{"clocktower_type":"client_session_statistics","sid":"s1","timestamp":1792375074.1504605,"seq_unexpected_increment":0,"seq_unexpected_decrement":0,"sent_messages":41,"received_messages":41,"timely_received_messages":38,"lost_messages":0,"inflight_messages":3,"received_bytes":984,"rtt_worst":0.0031418800354003906,"rtt_best":0.00011777877807617188,"rtt_mavg":0.00046056888504178727,"intermessage_gap_mavg":0.049906021752816575,"receive_time_windows":[4,5,4,4,4,4,4,4,4,4]}"#;
+const BASELINE_HUB_RECORD: &str = r#"{"clocktower_type":"hub_session_statistics","sid":"s1","timestamp":1792375073.8457787,"received_messages":35,"holes_created":0,"holes_closed":0,"holes_timed_out":0,"holes_current":0,"received_bytes":840,"intermessage_gap_mavg":0.0499032184841595,"receive_time_windows":[2,3,4,4,4,4,4,4,3,2]}"#;
+
+#[test]
+fn baseline_records_still_parse() {
+    match Statistics::from_json(BASELINE_CLIENT_RECORD).unwrap() {
+        Statistics::ClientSession(statistics) => {
+            assert_eq!(statistics.schema_version, 0);
+            assert_eq!(statistics.sent_messages, 41);
+            assert_eq!(statistics.rtt_best, Some(0.00011777877807617188));
+            assert_eq!(statistics.stats_seq, 0);
+            assert_eq!(statistics.send_skipped, 0);
+            assert_eq!(statistics.interval_duration, None);
+        },
+        other => panic!("expected client statistics, got {:?}", other),
+    }
+    match Statistics::from_json(BASELINE_HUB_RECORD).unwrap() {
+        Statistics::HubSession(statistics) => {
+            assert_eq!(statistics.schema_version, 0);
+            assert_eq!(statistics.tenant, "default");
+            assert_eq!(statistics.received_messages, 35);
+            assert_eq!(statistics.receive_time_windows, vec![2, 3, 4, 4, 4, 4, 4, 4, 3, 2]);
+            assert_eq!(statistics.rate_limited, 0);
+            assert_eq!(statistics.impairment_dropped, 0);
+        },
+        other => panic!("expected hub statistics, got {:?}", other),
+    }
+}
+
+/// Fields the first release always sent, anything newer must be optional for its records to keep parsing
+#[test]
+fn only_baseline_fields_are_required() {
+    let schema = json_schema();
+    for (definition, record) in [("ClientSessionStatistics", BASELINE_CLIENT_RECORD), ("ServerSessionStatistics", BASELINE_HUB_RECORD)].iter() {
+        let baseline_fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(record).unwrap();
+        for required in schema["$defs"][*definition]["required"].as_array().unwrap() {
+            let required = required.as_str().unwrap();
+            assert!(baseline_fields.contains_key(required), "{} field {} is required but older records lack it", definition, required);
+        }
+    }
+}
+
+#[test]
+fn unversioned_records_still_parse() {
+    let json = r#"{"clocktower_type":"hub_acl_statistics","timestamp":1600000000.0,"rules":[],"default_action":"allow","default_hits":1}"#;
+    match Statistics::from_json(json).unwrap() {
+        Statistics::HubAcl(statistics) => assert_eq!(statistics.schema_version, 0),
+        other => panic!("expected hub ACL statistics, got {:?}", other),
+    }
+}
+
+#[test]
+fn unknown_records_are_rejected() {
+    assert!(Statistics::from_json(r#"{"clocktower_type":"something_else"}"#).is_err());
+    assert!(Statistics::from_json(r#"{"clocktower_type":"hub_acl_statistics","timestamp":"now"}"#).is_err());
+    assert!(Statistics::from_json("not json").is_err());
+}
@@ -0,0 +1,206 @@
+extern crate metronome_lib;
+
+mod common;
+
+use metronome_lib::stats::{Spool, SpoolConfig, StatisticsRecord, StatsDestination, StatsSink};
+use std::io::{Read, Write};
+
+/// An empty directory of its own for each test
+fn spool_directory(name: &str) -> String {
+    let directory = std::env::temp_dir().join(format!("metronome-spool-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&directory);
+    return directory.to_str().unwrap().to_string();
+}
+
+fn spool_config(directory: &str, memory_records: usize) -> SpoolConfig {
+    return SpoolConfig {
+        memory_records: memory_records,
+        directory: Some(directory.to_string()),
+        ..SpoolConfig::default()
+    };
+}
+
+fn record(n: usize) -> Vec<u8> {
+    return format!("{{\"n\":{}}}", n).into_bytes();
+}
+
+/// Everything left in a spool directory, oldest first
+fn spooled_records(directory: &str) -> Vec<Vec<u8>> {
+    let mut spool = Spool::open(&spool_config(directory, 1000)).unwrap();
+    let mut records: Vec<Vec<u8>> = Vec::new();
+    while let Some(batch) = spool.peek(1000) {
+        let count = batch.records.len();
+        records.extend(batch.records.iter().cloned());
+        spool.commit(batch, count);
+    }
+    return records;
+}
+
+fn read_frame(stream: &mut std::net::TcpStream) -> Option<Vec<u8>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).ok()?;
+    let mut frame = vec![0u8; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut frame).ok()?;
+    return Some(frame);
+}
+
+#[test]
+fn partially_delivered_batches_keep_the_rest() {
+    let directory = spool_directory("partial");
+    let mut spool = Spool::open(&spool_config(&directory, 1000)).unwrap();
+    for n in 0..5 {
+        spool.push(record(n));
+    }
+    let batch = spool.peek(100).unwrap();
+    assert_eq!(batch.records.len(), 5);
+    spool.commit(batch, 2);
+    assert_eq!(spool.peek(100).unwrap().records, vec![record(2), record(3), record(4)]);
+
+    // Spilled to disk, a partial commit rewrites the file with what is left
+    spool.persist();
+    let batch = spool.peek(100).unwrap();
+    spool.commit(batch, 1);
+    let batch = spool.peek(100).unwrap();
+    assert_eq!(batch.records, vec![record(3), record(4)]);
+    // Nothing taken leaves the batch in place
+    spool.commit(batch, 0);
+    drop(spool);
+
+    // And a restart picks up exactly those
+    assert_eq!(spooled_records(&directory), vec![record(3), record(4)]);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn partial_ack_resends_only_unacknowledged_records() {
+    let directory = spool_directory("resend");
+    // Records a previous run left behind
+    let mut spool = Spool::open(&spool_config(&directory, 1000)).unwrap();
+    for n in 0..5 {
+        spool.push(record(n));
+    }
+    spool.persist();
+    drop(spool);
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let clocktower = std::thread::spawn(move || {
+        let mut connections: Vec<Vec<Vec<u8>>> = Vec::new();
+        for (index, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+            let mut received: Vec<Vec<u8>> = Vec::new();
+            while let Some(frame) = read_frame(&mut stream) {
+                if !frame.is_empty() {
+                    received.push(frame);
+                    continue;
+                }
+                // The first connection takes two records and goes away
+                let acknowledged = if index == 0 { 2 } else { received.len() };
+                stream.write_all(&(acknowledged as u32).to_be_bytes()).unwrap();
+                if index == 0 {
+                    break;
+                }
+                if received.len() >= 3 {
+                    connections.push(received);
+                    return connections;
+                }
+            }
+            connections.push(received);
+        }
+        return connections;
+    });
+
+    let destination: StatsDestination = format!("tcp://{}?spool={}", addr, directory).parse().unwrap();
+    let sink = StatsSink::new(&destination, "metronome-client").unwrap();
+    let connections = clocktower.join().unwrap();
+    drop(sink);
+
+    assert_eq!(connections[0], (0..5).map(record).collect::<Vec<Vec<u8>>>());
+    assert_eq!(connections[1], (2..5).map(record).collect::<Vec<Vec<u8>>>());
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn failed_deliveries_reach_the_spool_directory_before_shutdown() {
+    let directory = spool_directory("persist");
+    // Nothing listens on a port freed again straight away
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let destination: StatsDestination = format!("tcp://{}?spool={}", addr, directory).parse().unwrap();
+    let mut sink = StatsSink::new(&destination, "metronome-client").unwrap();
+    let client = common::client_statistics(10);
+    sink.send(&client);
+
+    // The sink stays up, as if the process were about to be killed
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let mut batch = Spool::open(&spool_config(&directory, 1000)).unwrap().peek(1000);
+    while batch.is_none() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        batch = Spool::open(&spool_config(&directory, 1000)).unwrap().peek(1000);
+    }
+    assert_eq!(batch.unwrap().records, vec![client.to_json().unwrap().into_bytes()]);
+    drop(sink);
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+/// The body of one HTTP request, after answering it with `status`
+fn answer_request(stream: &mut std::net::TcpStream, status: &str) -> Vec<u8> {
+    let mut request: Vec<u8> = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let body_start = loop {
+        let received = stream.read(&mut buffer).unwrap();
+        assert!(received > 0, "connection closed before the request was complete");
+        request.extend_from_slice(&buffer[..received]);
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+    let header = String::from_utf8_lossy(&request[..body_start]).to_string();
+    let length: usize = header.lines().find_map(|line| line.strip_prefix("Content-Length: ")).unwrap().parse().unwrap();
+    while request.len() < body_start + length {
+        let received = stream.read(&mut buffer).unwrap();
+        assert!(received > 0, "connection closed before the body was complete");
+        request.extend_from_slice(&buffer[..received]);
+    }
+    stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).unwrap();
+    return request[body_start..body_start + length].to_vec();
+}
+
+#[test]
+fn rejected_batches_are_dropped_instead_of_retried() {
+    let directory = spool_directory("rejected");
+    let mut spool = Spool::open(&spool_config(&directory, 1000)).unwrap();
+    for n in 0..5 {
+        spool.push(record(n));
+    }
+    spool.persist();
+    drop(spool);
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let collector = std::thread::spawn(move || {
+        let mut bodies: Vec<Vec<u8>> = Vec::new();
+        for (index, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+            bodies.push(answer_request(&mut stream, if index == 0 { "400 Bad Request" } else { "204 No Content" }));
+            if bodies.len() == 2 {
+                return bodies;
+            }
+        }
+        return bodies;
+    });
+
+    let destination: StatsDestination = format!("http://{}/statistics?spool={}", addr, directory).parse().unwrap();
+    let mut sink = StatsSink::new(&destination, "metronome-client").unwrap();
+    let client = common::client_statistics(10);
+    sink.send(&client);
+    let bodies = collector.join().unwrap();
+    drop(sink);
+
+    let spooled: Vec<String> = (0..5).map(|n| String::from_utf8(record(n)).unwrap()).collect();
+    assert_eq!(bodies[0], format!("[{}]", spooled.join(",")).into_bytes());
+    // The next request carries what came after the rejected batch, not the batch again
+    assert_eq!(bodies[1], format!("[{}]", client.to_json().unwrap()).into_bytes());
+    let _ = std::fs::remove_dir_all(&directory);
+}
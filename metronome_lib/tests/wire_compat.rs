@@ -0,0 +1,114 @@
+extern crate metronome_lib;
+extern crate rmp_serde;
+#[macro_use] extern crate serde_derive;
+
+use metronome_lib::datatypes::{HubSessionCounters, MetronomeMessage, MetronomeMessageRef};
+
+/// The message as peers predating hub statistics and payload checksums encode and decode it
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct BaselineMessage {
+    mode: String,
+    payload: Option<String>,
+    mul: f32,
+    seq: u64,
+    key: String,
+    sid: String,
+}
+
+fn baseline_ping() -> BaselineMessage {
+    return BaselineMessage {
+        mode: "ping".to_string(),
+        payload: Some("XXXXXXXX".to_string()),
+        mul: 1.5,
+        seq: 42,
+        key: "key".to_string(),
+        sid: "sid".to_string(),
+    };
+}
+
+fn ping() -> MetronomeMessage {
+    return MetronomeMessage {
+        mode: "ping".to_string(),
+        payload: Some("XXXXXXXX".to_string()),
+        mul: 1.5,
+        seq: 42,
+        key: "key".to_string(),
+        sid: "sid".to_string(),
+        hub_stats_requested: false,
+        hub_stats: None,
+        payload_checksum: None,
+    };
+}
+
+/// msgpack fixarray header of the given length
+fn array_header(len: u8) -> u8 {
+    return 0x90 | len;
+}
+
+#[test]
+fn old_ping_decodes_with_defaults() {
+    let datagram = rmp_serde::to_vec(&baseline_ping()).unwrap();
+    assert_eq!(datagram[0], array_header(6));
+    let message = MetronomeMessageRef::parse_from_slice(&datagram).unwrap();
+    assert_eq!(message, ping().borrowed());
+    assert!(!message.hub_stats_requested);
+    assert_eq!(message.hub_stats, None);
+    assert_eq!(message.payload_intact(), None);
+}
+
+#[test]
+fn plain_ping_encodes_like_old_peers() {
+    let datagram = ping().as_vec().unwrap();
+    assert_eq!(datagram, rmp_serde::to_vec(&baseline_ping()).unwrap());
+    assert_eq!(rmp_serde::from_slice::<BaselineMessage>(&datagram).unwrap(), baseline_ping());
+}
+
+#[test]
+fn pong_to_old_ping_decodes_as_old() {
+    let datagram = rmp_serde::to_vec(&baseline_ping()).unwrap();
+    let message = MetronomeMessageRef::parse_from_slice(&datagram).unwrap();
+    let mut scratch = String::new();
+    let mut reply = Vec::new();
+    message.get_pong(&mut scratch).write_to(&mut reply).unwrap();
+    assert_eq!(reply[0], array_header(6));
+
+    let pong = rmp_serde::from_slice::<BaselineMessage>(&reply).unwrap();
+    assert_eq!(pong.mode, "pong");
+    assert_eq!(pong.seq, 42);
+    assert_eq!(pong.payload.as_deref(), Some("XXXXXXXXXXXX"));
+
+    // The owned path answers the same way
+    let owned = MetronomeMessage::parse_from_slice(&datagram).unwrap();
+    assert_eq!(owned.get_pong().as_vec().unwrap(), reply);
+}
+
+#[test]
+fn optional_fields_are_only_sent_when_set() {
+    let mut message = ping();
+    message.hub_stats_requested = true;
+    let datagram = message.as_vec().unwrap();
+    assert_eq!(datagram[0], array_header(7));
+    assert_eq!(MetronomeMessageRef::parse_from_slice(&datagram).unwrap(), message.borrowed());
+
+    message.hub_stats = Some(HubSessionCounters {
+        received_messages: 1,
+        holes_created: 0,
+        holes_closed: 0,
+        holes_timed_out: 0,
+        holes_current: 0,
+        policed_messages: 0,
+        corrupted_messages: 0,
+    });
+    let datagram = message.as_vec().unwrap();
+    assert_eq!(datagram[0], array_header(8));
+    assert_eq!(MetronomeMessageRef::parse_from_slice(&datagram).unwrap(), message.borrowed());
+
+    // A checksum alone still carries the fields before it at their defaults
+    let mut message = ping();
+    message.set_payload("XXXXXXXX".to_string(), true);
+    let datagram = message.as_vec().unwrap();
+    assert_eq!(datagram[0], array_header(9));
+    let decoded = MetronomeMessageRef::parse_from_slice(&datagram).unwrap();
+    assert_eq!(decoded, message.borrowed());
+    assert_eq!(decoded.payload_intact(), Some(true));
+}